@@ -11,6 +11,8 @@ use core::ops::{Deref, DerefMut, CoerceUnsized};
 use core::marker::Unsize;
 use core::intrinsics::drop_in_place;
 use util::KernResult;
+use slab::Cache;
+use AllocFlags;
 logger_init!(Debug);
 
 /// A pointer type for heap allocations.
@@ -19,15 +21,47 @@ pub struct Box<T: ?Sized>(*mut T);
 impl<T> Box<T> {
    
     /// Allocates memory on the heap and then moves `x` into it.
+    #[inline(always)]
     pub fn new(x: T) -> KernResult<Box<T>> {
         ::allocate(x).map(Box)
     }
 
     /// Allocates memory and calls the initialization function on it. This helps avoid copying
     /// large data structures on the stack. This is especially important when allocating stacks!
+    #[inline(always)]
     pub fn emplace<F>(init: F) -> KernResult<Box<T>> where F: Fn(&mut T) {
         ::allocate_emplace(init).map(Box)
     }
+
+    /// Allocates memory as directed by `flags` and then moves `x` into it. With `ALLOC_ATOMIC` this
+    /// can be used from interrupt handlers, and so can dropping the box.
+    #[inline(always)]
+    pub fn new_flags(x: T, flags: AllocFlags) -> KernResult<Box<T>> {
+        ::allocate_flags(x, flags).map(Box)
+    }
+
+    /// Allocates memory as directed by `flags` and calls the initialization function on it.
+    #[inline(always)]
+    pub fn emplace_flags<F>(init: F, flags: AllocFlags) -> KernResult<Box<T>> where F: Fn(&mut T) {
+        ::allocate_emplace_flags(init, flags).map(Box)
+    }
+
+    /// Allocates memory from `cache` and then moves `x` into it. The memory goes back to the cache
+    /// when the box is dropped.
+    #[inline(always)]
+    pub fn new_in(x: T, cache: &'static Cache<T>) -> KernResult<Box<T>> {
+        cache.allocate(x).map(Box)
+    }
+
+    /// Allocates memory from `cache` and initializes it with the cache's constructor.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the cache has no constructor.
+    #[inline(always)]
+    pub fn construct_in(cache: &'static Cache<T>) -> KernResult<Box<T>> {
+        cache.construct().map(Box)
+    }
    
     /// Extracts the contents of the box.
     pub fn into_inner(self) -> T {
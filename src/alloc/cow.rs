@@ -1,12 +1,111 @@
-enum Cow<T> {
-    Data(T),
-    Ref(*const T)
+//!
+//! Copy-on-write pointers.
+//!
+//! A `Cow<T>` is a shared pointer to a `T` that can be mutated. Clones of a `Cow` share the value
+//! until one of them asks for mutable access, at which point it gets a copy of its own. Since
+//! copying may allocate and allocation can fail in the kernel, values are copied with `TryClone`
+//! instead of `Clone` and mutable access can fail.
+//!
+use core::prelude::*;
+use core::atomic::{AtomicUsize, Ordering};
+use core::ops::Deref;
+use core::{fmt, mem, ptr};
+use boxed::Box;
+use util::KernResult;
+
+/// A type that can be copied, possibly failing because copying needs memory.
+pub trait TryClone {
+    /// Tries to copy the value.
+    fn try_clone(&self) -> KernResult<Self> where Self: Sized;
+}
+
+// The shared part of a `Cow`.
+struct CowInner<T> {
+    count: AtomicUsize,
+    value: T,
+}
+
+/// A copy-on-write pointer.
+pub struct Cow<T: TryClone> {
+    inner: *mut CowInner<T>,
+}
+
+impl<T: TryClone> Cow<T> {
+
+    /// Tries to move `value` onto the heap.
+    pub fn new(value: T) -> KernResult<Cow<T>> {
+        let inner = try!(Box::new(CowInner { count: AtomicUsize::new(1), value: value }));
+        Ok(Cow { inner: unsafe { inner.into_raw() } })
+    }
+
+    fn inner(&self) -> &CowInner<T> {
+        unsafe { &*self.inner }
+    }
+
+    /// Returns whether other `Cow`s point to the same value.
+    pub fn is_shared(&self) -> bool {
+        self.inner().count.load(Ordering::SeqCst) != 1
+    }
+
+    /// Returns a mutable reference to the value, copying it first if it is shared.
+    ///
+    /// # Failures
+    ///
+    /// Fails if the value is shared and copying it fails. The `Cow` still points to the shared
+    /// value.
+    pub fn make_mut(&mut self) -> KernResult<&mut T> {
+        if self.is_shared() {
+            let copy = try!(self.inner().value.try_clone());
+            *self = try!(Cow::new(copy));
+        }
+        Ok(unsafe { &mut (*self.inner).value })
+    }
+
+    /// Returns the value, copying it if it is shared.
+    ///
+    /// # Failures
+    ///
+    /// Fails if the value is shared and copying it fails.
+    pub fn into_inner(self) -> KernResult<T> {
+        if self.is_shared() {
+            return self.inner().value.try_clone();
+        }
+        // We know this is safe because we are the only pointer to the value. Its memory is freed
+        // without dropping it since we moved it out.
+        unsafe {
+            let value = ptr::read(&(*self.inner).value);
+            ::deallocate(self.inner);
+            mem::forget(self);
+            Ok(value)
+        }
+    }
+
+}
+
+impl<T: TryClone> Clone for Cow<T> {
+    fn clone(&self) -> Cow<T> {
+        self.inner().count.fetch_add(1, Ordering::SeqCst);
+        Cow { inner: self.inner }
+    }
+}
+
+impl<T: TryClone> Drop for Cow<T> {
+    fn drop(&mut self) {
+        if self.inner().count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            unsafe { drop(Box::from_raw(self.inner)) };
+        }
+    }
 }
 
-struct Cow<T> {
-    data: T
+impl<T: TryClone> Deref for Cow<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.inner().value
+    }
 }
 
-impl<T: Clone> for Cow {
-    
+impl<T: TryClone + fmt::Debug> fmt::Debug for Cow<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cow({:?})", self.deref())
+    }
 }
@@ -0,0 +1,297 @@
+//!
+//! A debugging allocator that catches heap corruption close to its cause.
+//!
+//! Every block gets a header and is surrounded by red zones filled with a canary pattern. Fresh
+//! blocks are filled with a pattern of their own so reads of uninitialized memory stand out. Freed
+//! blocks are poisoned and held in a quarantine instead of going straight back to the heap, so a
+//! write through a dangling pointer shows up as broken poison once the block leaves the
+//! quarantine.
+//!
+//! Red zones and poison are verified when a block is freed, when it leaves the quarantine and every
+//! `CHECK_INTERVAL` operations for every block. A broken block panics with the site that allocated
+//! it (see `alloc::track`).
+//!
+//! This backend is used when the kernel is built with `ALLOC_DEBUG := yes`. It wraps the backend
+//! chosen at boot, which does the actual work.
+//!
+use core::prelude::*;
+use core::{cmp, mem, ptr};
+use core::intrinsics::volatile_copy_memory;
+use Allocator;
+use dispatch::{Dispatch, DISPATCH_INIT, HeapBackend};
+use util::KernResult;
+logger_init!(Error);
+
+/// The size of the red zones in front of and behind every block.
+pub const RED_ZONE: usize = 16;
+
+/// The number of freed blocks held back from the heap.
+pub const QUARANTINE_SIZE: usize = 64;
+
+/// Freed blocks larger than this go straight back to the heap after they are checked.
+pub const QUARANTINE_MAX_BLOCK: usize = 16 * 1024;
+
+/// The number of operations between checks of every block.
+pub const CHECK_INTERVAL: usize = 256;
+
+/// The pattern red zones are filled with.
+pub const RED_ZONE_BYTE: u8 = 0xFD;
+
+/// The pattern new blocks are filled with.
+pub const FRESH_BYTE: u8 = 0xCD;
+
+/// The pattern freed blocks are filled with.
+pub const POISON_BYTE: u8 = 0xDD;
+
+// The magic numbers of the header of a live block and a block in the quarantine.
+const LIVE_MAGIC: usize = 0xA110CA7E;
+const FREED_MAGIC: usize = 0xDEADB10C;
+
+// The header in front of the front red zone of every block. Live blocks are linked through it.
+#[repr(C)]
+struct Header {
+    magic: usize,
+    base: usize,
+    total: usize,
+    size: usize,
+    site: usize,
+    prev: *mut Header,
+    next: *mut Header,
+}
+
+pub struct DebugAllocator {
+    inner: Dispatch,
+    live: *mut Header,
+    quarantine: [*mut Header; QUARANTINE_SIZE],
+    quarantined: usize,
+    oldest: usize,
+    quarantined_bytes: usize,
+    ops: usize,
+    site: usize,
+}
+
+pub const DEBUG_ALLOCATOR_INIT: DebugAllocator = DebugAllocator {
+    inner: DISPATCH_INIT,
+    live: 0 as *mut Header,
+    quarantine: [0 as *mut Header; QUARANTINE_SIZE],
+    quarantined: 0,
+    oldest: 0,
+    quarantined_bytes: 0,
+    ops: 0,
+    site: 0,
+};
+
+// Returns the address of the user part of the block with header `hdr`.
+fn user_addr(hdr: *const Header) -> usize {
+    hdr as usize + mem::size_of::<Header>() + RED_ZONE
+}
+
+// Returns the header of the block whose user part starts at `addr`.
+fn header(addr: usize) -> *mut Header {
+    (addr - RED_ZONE - mem::size_of::<Header>()) as *mut Header
+}
+
+// Returns the offset of the first byte in `len` bytes at `addr` that is not `byte`.
+fn find_mismatch(addr: usize, len: usize, byte: u8) -> Option<usize> {
+    (0 .. len).position(|i| unsafe { *((addr + i) as *const u8) } != byte)
+}
+
+impl DebugAllocator {
+
+    pub fn init(&mut self, backend: HeapBackend, heap_start: usize, heap_end: usize) {
+        self.inner.init(backend, heap_start, heap_end);
+    }
+
+    pub fn backend(&self) -> HeapBackend {
+        self.inner.backend()
+    }
+
+    /// Returns whether the block whose user part starts at `addr` is in the quarantine.
+    pub fn is_quarantined(&self, addr: usize) -> bool {
+        let hdr = header(addr);
+        (0 .. self.quarantined).any(|i| {
+            self.quarantine[(self.oldest + i) % QUARANTINE_SIZE] == hdr
+        })
+    }
+
+    // Panics if either red zone of the block with header `hdr` is broken.
+    unsafe fn check_red_zones(&self, hdr: *const Header) {
+        let user = user_addr(hdr);
+        let front = find_mismatch(user - RED_ZONE, RED_ZONE, RED_ZONE_BYTE);
+        let back = find_mismatch(user + (*hdr).size, RED_ZONE, RED_ZONE_BYTE);
+        if front.is_some() || back.is_some() {
+            panic!("{} red zone of {} byte block at 0x{:x} allocated from 0x{:x} overwritten",
+                   if front.is_some() { "front" } else { "back" }, (*hdr).size, user,
+                   (*hdr).site);
+        }
+    }
+
+    // Panics if the block with header `hdr` was written to after it was freed.
+    unsafe fn check_poison(&self, hdr: *const Header) {
+        let user = user_addr(hdr);
+        if (*hdr).magic != FREED_MAGIC {
+            panic!("header of freed block at 0x{:x} overwritten", user);
+        }
+        if let Some(i) = find_mismatch(user, (*hdr).size, POISON_BYTE) {
+            panic!("{} byte block at 0x{:x} allocated from 0x{:x} written at offset {} after free",
+                   (*hdr).size, user, (*hdr).site, i);
+        }
+        self.check_red_zones(hdr);
+    }
+
+    // Checks the red zones of every live block and the poison of every block in the quarantine.
+    fn check_all(&self) {
+        // We know this is safe because only blocks we handed out are on the lists.
+        unsafe {
+            let mut hdr = self.live;
+            while !hdr.is_null() {
+                if (*hdr).magic != LIVE_MAGIC {
+                    panic!("header of block at 0x{:x} overwritten", user_addr(hdr));
+                }
+                self.check_red_zones(hdr);
+                hdr = (*hdr).next;
+            }
+            for i in 0 .. self.quarantined {
+                self.check_poison(self.quarantine[(self.oldest + i) % QUARANTINE_SIZE]);
+            }
+        }
+    }
+
+    // Counts an operation and checks every block if it is time to.
+    fn tick(&mut self) {
+        self.ops += 1;
+        if self.ops % CHECK_INTERVAL == 0 {
+            self.check_all();
+        }
+    }
+
+    // Gives the oldest block in the quarantine back to the heap.
+    unsafe fn release_oldest(&mut self) {
+        let hdr = self.quarantine[self.oldest];
+        self.check_poison(hdr);
+        self.oldest = (self.oldest + 1) % QUARANTINE_SIZE;
+        self.quarantined -= 1;
+        self.quarantined_bytes -= (*hdr).total;
+        let (base, total) = ((*hdr).base, (*hdr).total);
+        self.inner.deallocate_raw(base, total);
+    }
+
+    unsafe fn unlink(&mut self, hdr: *mut Header) {
+        if (*hdr).prev.is_null() {
+            self.live = (*hdr).next;
+        } else {
+            (*(*hdr).prev).next = (*hdr).next;
+        }
+        if !(*hdr).next.is_null() {
+            (*(*hdr).next).prev = (*hdr).prev;
+        }
+    }
+
+}
+
+impl Allocator for DebugAllocator {
+
+    fn allocate_raw(&mut self, size: usize, align: usize) -> KernResult<usize> {
+        self.tick();
+        let align = cmp::max(align, mem::min_align_of::<Header>());
+        let offset = align_up!(mem::size_of::<Header>() + RED_ZONE, align);
+        let total = offset + size + RED_ZONE;
+        let base = try!(self.inner.allocate_raw(total, align));
+        let user = base + offset;
+        let hdr = header(user);
+
+        // We know this is safe because we just allocated the whole block.
+        unsafe {
+            ptr::write_bytes(base as *mut u8, RED_ZONE_BYTE, total);
+            ptr::write_bytes(user as *mut u8, FRESH_BYTE, size);
+            ptr::write(hdr, Header {
+                magic: LIVE_MAGIC,
+                base: base,
+                total: total,
+                size: size,
+                site: self.site,
+                prev: ptr::null_mut(),
+                next: self.live,
+            });
+            if !self.live.is_null() {
+                (*self.live).prev = hdr;
+            }
+        }
+        self.live = hdr;
+        trace!("allocated {} bytes at 0x{:x} for 0x{:x}", size, user, self.site);
+        Ok(user)
+    }
+
+    fn reallocate_raw(&mut self, old_addr: usize, old_size: usize, new_size: usize, align: usize)
+                      -> KernResult<usize> {
+        let new_addr = try!(self.allocate_raw(new_size, align));
+        let len = cmp::min(old_size, new_size);
+        unsafe { volatile_copy_memory(new_addr as *mut u8, old_addr as *const u8, len) };
+        self.deallocate_raw(old_addr, old_size);
+        Ok(new_addr)
+    }
+
+    fn deallocate_raw(&mut self, addr: usize, size: usize) {
+        self.tick();
+        let hdr = header(addr);
+
+        // We know this is safe because the header is checked before anything else is trusted.
+        unsafe {
+            match (*hdr).magic {
+                LIVE_MAGIC => { }
+                FREED_MAGIC => {
+                    panic!("double free of {} byte block at 0x{:x} allocated from 0x{:x}",
+                           (*hdr).size, addr, (*hdr).site)
+                }
+                _ => panic!("free of 0x{:x} which is not a heap block", addr),
+            }
+            if (*hdr).size != size {
+                panic!("free of {} byte block at 0x{:x} allocated from 0x{:x} as {} bytes",
+                       (*hdr).size, addr, (*hdr).site, size);
+            }
+            self.check_red_zones(hdr);
+            self.unlink(hdr);
+            (*hdr).magic = FREED_MAGIC;
+            ptr::write_bytes(addr as *mut u8, POISON_BYTE, size);
+
+            if (*hdr).total > QUARANTINE_MAX_BLOCK {
+                let (base, total) = ((*hdr).base, (*hdr).total);
+                self.inner.deallocate_raw(base, total);
+                return;
+            }
+            if self.quarantined == QUARANTINE_SIZE {
+                self.release_oldest();
+            }
+            let slot = (self.oldest + self.quarantined) % QUARANTINE_SIZE;
+            self.quarantine[slot] = hdr;
+            self.quarantined += 1;
+            self.quarantined_bytes += (*hdr).total;
+        }
+    }
+
+    fn get_free_space(&self) -> usize {
+        // Blocks in the quarantine are as good as free.
+        self.inner.get_free_space() + self.quarantined_bytes
+    }
+
+    fn largest_free_block(&self) -> usize {
+        self.inner.largest_free_block()
+    }
+
+    fn add_region(&mut self, start: usize, end: usize) {
+        self.inner.add_region(start, end)
+    }
+
+    fn remove_free_region(&mut self) -> Option<(usize, usize)> {
+        self.inner.remove_free_region()
+    }
+
+    fn set_site(&mut self, site: usize) {
+        self.site = site;
+    }
+
+    fn check(&self) {
+        self.check_all();
+    }
+
+}
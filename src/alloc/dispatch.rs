@@ -0,0 +1,146 @@
+//!
+//! Selection of the heap backend at boot.
+//!
+//! Every backend is statically allocated and `Dispatch` forwards to the one chosen by `init`. This
+//! keeps the choice out of the rest of the allocation library, which only sees an `Allocator`.
+//!
+use core::prelude::*;
+use core::fmt;
+use Allocator;
+use lmm::{LMMAllocator, LMM_ALLOCATOR_INIT};
+use naive::{NaiveAllocator, NAIVE_ALLOCATOR_INIT};
+use tlsf::{TLSFAllocator, TLSF_ALLOCATOR_INIT};
+use util::KernResult;
+
+/// The heap backends the kernel can use.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HeapBackend {
+    /// The List-based Memory Manager. This is the default.
+    LMM,
+    /// A Two-Level Segregated Fit allocator, which allocates and frees in constant time.
+    TLSF,
+    /// An allocator that never frees anything. Only useful to rule out the other backends.
+    Naive,
+}
+
+impl HeapBackend {
+
+    /// Returns the backend called `name`, as given by `heap=<name>` on the kernel command line.
+    pub fn from_name(name: &str) -> Option<HeapBackend> {
+        match name {
+            "lmm" => Some(HeapBackend::LMM),
+            "tlsf" => Some(HeapBackend::TLSF),
+            "naive" => Some(HeapBackend::Naive),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the backend.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HeapBackend::LMM => "lmm",
+            HeapBackend::TLSF => "tlsf",
+            HeapBackend::Naive => "naive",
+        }
+    }
+
+}
+
+impl fmt::Debug for HeapBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+pub struct Dispatch {
+    backend: HeapBackend,
+    lmm: LMMAllocator,
+    tlsf: TLSFAllocator,
+    naive: NaiveAllocator,
+}
+
+pub const DISPATCH_INIT: Dispatch = Dispatch {
+    backend: HeapBackend::LMM,
+    lmm: LMM_ALLOCATOR_INIT,
+    tlsf: TLSF_ALLOCATOR_INIT,
+    naive: NAIVE_ALLOCATOR_INIT,
+};
+
+// Runs `$op` on the chosen backend, bound to `$a`.
+macro_rules! dispatch {
+    ($s:expr, $a:ident => $op:expr) => {
+        match $s.backend {
+            HeapBackend::LMM => { let $a = &mut $s.lmm; $op }
+            HeapBackend::TLSF => { let $a = &mut $s.tlsf; $op }
+            HeapBackend::Naive => { let $a = &mut $s.naive; $op }
+        }
+    }
+}
+
+// Like `dispatch!` for methods that only need a shared reference.
+macro_rules! dispatch_ref {
+    ($s:expr, $a:ident => $op:expr) => {
+        match $s.backend {
+            HeapBackend::LMM => { let $a = &$s.lmm; $op }
+            HeapBackend::TLSF => { let $a = &$s.tlsf; $op }
+            HeapBackend::Naive => { let $a = &$s.naive; $op }
+        }
+    }
+}
+
+impl Dispatch {
+
+    /// Chooses `backend` and gives it the heap between `heap_start` and `heap_end`. This must only
+    /// be called once.
+    pub fn init(&mut self, backend: HeapBackend, heap_start: usize, heap_end: usize) {
+        self.backend = backend;
+        dispatch!(self, a => a.init(heap_start, heap_end))
+    }
+
+    /// Returns the chosen backend.
+    pub fn backend(&self) -> HeapBackend {
+        self.backend
+    }
+
+}
+
+impl Allocator for Dispatch {
+
+    fn allocate_raw(&mut self, size: usize, align: usize) -> KernResult<usize> {
+        dispatch!(self, a => a.allocate_raw(size, align))
+    }
+
+    fn reallocate_raw(&mut self, old_addr: usize, old_size: usize, new_size: usize, align: usize)
+                      -> KernResult<usize> {
+        dispatch!(self, a => a.reallocate_raw(old_addr, old_size, new_size, align))
+    }
+
+    fn deallocate_raw(&mut self, addr: usize, size: usize) {
+        dispatch!(self, a => a.deallocate_raw(addr, size))
+    }
+
+    fn get_free_space(&self) -> usize {
+        dispatch_ref!(self, a => a.get_free_space())
+    }
+
+    fn largest_free_block(&self) -> usize {
+        dispatch_ref!(self, a => a.largest_free_block())
+    }
+
+    fn add_region(&mut self, start: usize, end: usize) {
+        dispatch!(self, a => a.add_region(start, end))
+    }
+
+    fn remove_free_region(&mut self) -> Option<(usize, usize)> {
+        dispatch!(self, a => a.remove_free_region())
+    }
+
+    fn set_site(&mut self, site: usize) {
+        dispatch!(self, a => a.set_site(site))
+    }
+
+    fn check(&self) {
+        dispatch_ref!(self, a => a.check())
+    }
+
+}
@@ -5,7 +5,11 @@
 //!
 //! More information can be found here: http://www.cs.utah.edu/flux/oskit/html/oskit-wwwch25.html
 //!
+//! The heap starts out as a single region. Regions added later keep their header at their start and
+//! rank below the initial one, so they are only used once it is full and can empty out again.
+//!
 use core::prelude::*;
+use core::{cmp, mem, ptr};
 use core::intrinsics::volatile_copy_memory;
 use util::align_bits;
 use Allocator;
@@ -15,10 +19,17 @@ logger_init!(Error);
 
 const ALLOC_FLAGS: u32 = 0;
 
+// The priority of the initial region. LMM tries regions with a higher priority first.
+const INITIAL_PRI: u32 = 1;
+
+// The priority of regions added with `add_region`.
+const ADDED_PRI: u32 = 0;
+
 #[allow(dead_code,improper_ctypes)]
 extern {
     fn lmm_init(lmm: &mut LMM);
-    fn lmm_add_region(lmm: &mut LMM, region: &mut LMMRegion, addr: usize, size: usize);
+    fn lmm_add_region(lmm: &mut LMM, region: &mut LMMRegion, addr: usize, size: usize, flags: u32,
+                      pri: u32);
     fn lmm_add_free(lmm: &mut LMM, addr: usize, size: usize);
     fn lmm_remove_free(lmm: &mut LMM, addr: usize, size: usize);
     fn lmm_alloc(lmm: &mut LMM, size: usize, flags: u32) -> usize;
@@ -81,12 +92,19 @@ impl LMMAllocator {
         assert!(heap_start < heap_end);
         unsafe {
             lmm_init(&mut self.lmm);
-            lmm_add_region(&mut self.lmm, &mut self.region, heap_start, heap_end - heap_start);
+            lmm_add_region(&mut self.lmm, &mut self.region, heap_start, heap_end - heap_start,
+                           ALLOC_FLAGS, INITIAL_PRI);
             lmm_add_free(&mut self.lmm, heap_start, heap_end - heap_start);
         }
         self.free = heap_end - heap_start;
     }
 
+    // Returns whether `region` was added by `add_region` and nothing in it is allocated.
+    fn is_removable(&self, region: &LMMRegion) -> bool {
+        region as *const LMMRegion != &self.region as *const LMMRegion &&
+            region.free == region.max - region.min
+    }
+
 }
 
 /// An address for 0-sized allocations.
@@ -104,7 +122,6 @@ impl Allocator for LMMAllocator {
             Ok(&EMPTY as *const () as usize)
         } else {
             // Otherwise we go to LMM for the allocation.
-            self.free -= size;
             let align_bits = align_bits(align) as u32;
             let align_ofs = 0;
             match unsafe { lmm_alloc_aligned(&mut self.lmm, size, ALLOC_FLAGS, align_bits, align_ofs) } {
@@ -114,6 +131,7 @@ impl Allocator for LMMAllocator {
                 }
                 x => {
                     trace!("allocated {} bytes at 0x{:x}", size, x);
+                    self.free -= size;
                     Ok(x)
                 }
             }
@@ -149,4 +167,57 @@ impl Allocator for LMMAllocator {
         self.free
     }
 
+    fn largest_free_block(&self) -> usize {
+        let mut largest = 0;
+        let mut region = self.lmm.regions;
+        // We know this is safe because LMM keeps the free blocks of every region in its list.
+        unsafe {
+            while !region.is_null() {
+                let mut node = (*region).nodes;
+                while !node.is_null() {
+                    largest = cmp::max(largest, (*node).size);
+                    node = (*node).next;
+                }
+                region = (*region).next;
+            }
+        }
+        largest
+    }
+
+    fn add_region(&mut self, start: usize, end: usize) {
+        let header = mem::size_of::<LMMRegion>();
+        trace!("adding region 0x{:x}-0x{:x} to the heap", start, end);
+        assert!(start + header < end);
+
+        // We know this is safe because the caller hands the whole range over to us.
+        unsafe {
+            let region = &mut *(start as *mut LMMRegion);
+            lmm_add_region(&mut self.lmm, region, start + header, end - start - header,
+                           ALLOC_FLAGS, ADDED_PRI);
+            lmm_add_free(&mut self.lmm, start + header, end - start - header);
+            self.free += region.max - region.min;
+        }
+    }
+
+    fn remove_free_region(&mut self) -> Option<(usize, usize)> {
+        // The free blocks of a region live inside it, so unlinking the region forgets them too.
+        let mut link: *mut *mut LMMRegion = &mut self.lmm.regions;
+        // We know this is safe because LMM only links regions that are still part of the heap.
+        unsafe {
+            while !(*link).is_null() {
+                let region = *link;
+                if self.is_removable(&*region) {
+                    *link = (*region).next;
+                    (*region).next = ptr::null_mut();
+                    self.free -= (*region).max - (*region).min;
+                    trace!("removed region 0x{:x}-0x{:x} from the heap", region as usize,
+                           (*region).max);
+                    return Some((region as usize, (*region).max));
+                }
+                link = &mut (**link).next;
+            }
+        }
+        None
+    }
+
 }
@@ -1,6 +1,6 @@
 #![crate_name="alloc"]
 #![crate_type="rlib"]
-#![feature(no_std,const_fn,lang_items,core,filling_drop,core_prelude,core_intrinsics,unsize,coerce_unsized)]
+#![feature(no_std,const_fn,lang_items,core,filling_drop,core_prelude,core_intrinsics,unsize,coerce_unsized,link_llvm_intrinsics)]
 #![no_std]
 //!
 //! The kernel allocation library.
@@ -17,6 +17,10 @@
 //! aborting the kernel on an allocation failure is unaccceptable, all allocation procedures now
 //! return an Option. This includes `Box::new`.
 //!
+//! Allocations normally take a mutex and may block. The `_flags` variants of the allocation
+//! functions take `AllocFlags` that can ask for zeroed memory or for memory from the emergency pool
+//! (see `pool`) instead, which is safe to use from interrupt handlers.
+//!
 
 #[macro_use] extern crate core;
 #[macro_use] extern crate util;
@@ -28,21 +32,73 @@ pub mod boxed;
 /// A reference counted pointer.
 pub mod rc;
 
+/// A copy-on-write pointer.
+pub mod cow;
+
+/// Caches of fixed-size objects.
+pub mod slab;
+
+/// Tracking of live allocations by call site.
+pub mod track;
+
+/// The emergency pool for allocations that can't block.
+pub mod pool;
+
 mod lmm;
 
+mod naive;
+
+mod tlsf;
+
+mod dispatch;
+
+/// A heap backend that catches corruption.
+#[cfg(ALLOC_DEBUG="yes")]
+pub mod debug;
+
 use core::prelude::*;
-use core::{ptr, mem};
+use core::{cmp, fmt, ptr, mem};
 use core::mem::min_align_of;
 use mutex::Mutex;
-use lmm::{LMMAllocator, LMM_ALLOCATOR_INIT};
-use util::KernResult;
+#[cfg(not(ALLOC_DEBUG="yes"))]
+use dispatch::{Dispatch, DISPATCH_INIT};
+#[cfg(ALLOC_DEBUG="yes")]
+use debug::{DebugAllocator, DEBUG_ALLOCATOR_INIT};
+use util::{KernResult, PAGE_SIZE};
+use util::KernError::OutOfMemory;
+pub use dispatch::HeapBackend;
 logger_init!(Trace);
 
+// This is our entry point to the memory manager, which maps fresh pages for the heap to grow by.
+// This prevents the need for liballoc to rely on libmem which relies on liballoc itself.
+#[allow(improper_ctypes)] // This doesn't go to C!
+extern {
+    fn heap_map(size: usize) -> Option<usize>;
+    fn heap_unmap(addr: usize, size: usize);
+}
+
+/// Flags that direct how memory is allocated.
+bitflags! {
+    flags AllocFlags: u32 {
+        /// A normal allocation that may block. This is the same as no flags.
+        const ALLOC_BLOCK   = 0x00000000,
+        /// The caller must not block, for example because it is an interrupt handler. The memory
+        /// comes from the emergency pool, which is never contended for long.
+        const ALLOC_ATOMIC  = 0x00000001,
+        /// The memory is zeroed.
+        const ALLOC_ZEROED  = 0x00000002,
+        /// The emergency pool may be used if the heap is out of memory.
+        const ALLOC_RESERVE = 0x00000004,
+    }
+}
+
+/// The smallest amount of memory the heap grows by at once.
+pub const HEAP_GROW_SIZE: usize = 64 * PAGE_SIZE;
+
 /// An interface for dealing with Allocator back-ends. Implementors only need implement
-/// `allocate_raw` and `deallocate_raw`.
+/// `allocate_raw`, `deallocate_raw` and the region management methods.
 ///
-/// This should likely be extended with initialization procedures as well as methods for
-/// adding/removing regions from the heap.
+/// This should likely be extended with initialization procedures as well.
 trait Allocator {
 
     /// Tries to allocate `size` bytes aligned to `align` on the heap. Returns the address of the
@@ -59,6 +115,26 @@ trait Allocator {
     /// Returns an approximation of the amount of free space left on the heap.
     fn get_free_space(&self) -> usize;
 
+    /// Returns the size of the largest free block, which is the largest allocation that can
+    /// succeed without growing the heap.
+    fn largest_free_block(&self) -> usize;
+
+    /// Adds the memory between `start` and `end` to the heap. Part of it may be used for
+    /// bookkeeping.
+    fn add_region(&mut self, start: usize, end: usize);
+
+    /// Takes a region added with `add_region` out of the heap if none of it is allocated anymore.
+    /// Returns the bounds it was added with.
+    fn remove_free_region(&mut self) -> Option<(usize, usize)>;
+
+    /// Tells the allocator where the next allocation is made from. Backends that don't keep track
+    /// of this can ignore it.
+    fn set_site(&mut self, _site: usize) { }
+
+    /// Verifies the allocator's bookkeeping and panics if it is broken. Backends that can't check
+    /// anything do nothing.
+    fn check(&self) { }
+
     /// Tries to allocate an object aligned to `align`. Returns a unique pointer to the object if
     /// successful and `None` otherwise.
     fn allocate_aligned<T>(&mut self, elem: T, align: usize) -> KernResult<*mut T> {
@@ -90,15 +166,33 @@ trait Allocator {
 
 }
 
-static ALLOCATOR: Mutex<LMMAllocator> = Mutex::new(LMM_ALLOCATOR_INIT);
+// The heap's backend. This forwards to the backend chosen at boot. Kernels built with
+// `ALLOC_DEBUG := yes` check the heap for corruption on top of that.
+#[cfg(not(ALLOC_DEBUG="yes"))]
+type Backend = Dispatch;
+#[cfg(not(ALLOC_DEBUG="yes"))]
+const BACKEND_INIT: Backend = DISPATCH_INIT;
+#[cfg(ALLOC_DEBUG="yes")]
+type Backend = DebugAllocator;
+#[cfg(ALLOC_DEBUG="yes")]
+const BACKEND_INIT: Backend = DEBUG_ALLOCATOR_INIT;
+
+static ALLOCATOR: Mutex<Backend> = Mutex::new(BACKEND_INIT);
 
 /// Initializes the allocation library and allocates all memory between `__heap_start` and
-/// `__heap_end` to the allocator.
-pub fn init() {
-    debug!("initializing alloc");
+/// `__heap_end` to `backend`.
+///
+/// Once the memory manager is initialized, the heap grows by at least `HEAP_GROW_SIZE` bytes
+/// whenever an allocation does not fit and gives regions back once they are unused again.
+pub fn init(backend: HeapBackend) {
+    debug!("initializing alloc with the {:?} heap", backend);
     let heap_start = linker_sym!(__heap_start);
     let heap_end = linker_sym!(__heap_end);
-    ALLOCATOR.lock().init(heap_start, heap_end);
+    ALLOCATOR.lock().init(backend, heap_start, heap_end);
+    slab::init(heap_start, heap_end);
+    let pool = heap_allocate(pool::POOL_SIZE, PAGE_SIZE, 0)
+        .ok().expect("unable to allocate the emergency pool");
+    pool::init(pool);
 }
 
 /// Tries to allocate space on the heap and returns a unique pointer to it.
@@ -106,19 +200,56 @@ pub fn init() {
 /// # Failures
 ///
 /// Fails if the heap cannot find a slot big enough to accomodate the requested object.
+#[inline(never)]
 pub extern fn allocate_raw(size: usize, align: usize) -> KernResult<usize> {
-    ALLOCATOR.lock().allocate_raw(size, align)
+    let site = unsafe { track::return_address(0) } as usize;
+    allocate_with(size, align, ALLOC_BLOCK, site)
+}
+
+/// Tries to allocate space as directed by `flags` and returns a unique pointer to it.
+///
+/// # Failures
+///
+/// Fails if the heap cannot find a slot big enough to accomodate the requested object, or with
+/// `ALLOC_ATOMIC` if the emergency pool has no block big enough left.
+#[inline(never)]
+pub extern fn allocate_raw_flags(size: usize, align: usize, flags: AllocFlags)
+                                 -> KernResult<usize> {
+    let site = unsafe { track::return_address(0) } as usize;
+    allocate_with(size, align, flags, site)
 }
 
 /// Tries to reallocate a space on the heap to accomodate a new size. Returns Ok(addr) if succesful
 /// or Err(old_addr) if unsuccesful.
+#[inline(never)]
 pub extern fn reallocate_raw(old_addr: usize, old_size: usize, new_size: usize, align: usize) -> KernResult<usize> {
-    ALLOCATOR.lock().reallocate_raw(old_addr, old_size, new_size, align)
+    let site = unsafe { track::return_address(0) } as usize;
+    reallocate_with(old_addr, old_size, new_size, align, ALLOC_BLOCK, site)
 }   
 
-/// Deallocates a space on the heap. 
+/// Tries to reallocate a space as directed by `flags` to accomodate a new size. Memory from the
+/// emergency pool moves to the heap unless `ALLOC_ATOMIC` is given. `ALLOC_ZEROED` only applies to
+/// the memory past the old size.
+///
+/// # Failures
+///
+/// Fails if the memory cannot be reallocated, and always with `ALLOC_ATOMIC` if the memory is not
+/// from the emergency pool since heap memory can't be freed without blocking.
+#[inline(never)]
+pub extern fn reallocate_raw_flags(old_addr: usize, old_size: usize, new_size: usize, align: usize,
+                                   flags: AllocFlags) -> KernResult<usize> {
+    let site = unsafe { track::return_address(0) } as usize;
+    reallocate_with(old_addr, old_size, new_size, align, flags, site)
+}
+
+/// Deallocates a space on the heap. Memory from the emergency pool can be freed from any context.
 pub extern fn deallocate_raw(addr: usize, size: usize) {
-    ALLOCATOR.lock().deallocate_raw(addr, size)
+    if pool::owns(addr) {
+        pool::free(addr);
+    } else {
+        track::remove(addr);
+        heap_free(addr, size);
+    }
 }
 
 /// Tries to allocate an object to the heap and returns a unique pointer to it.
@@ -126,8 +257,26 @@ pub extern fn deallocate_raw(addr: usize, size: usize) {
 /// # Failures
 ///
 /// Fails if the heap cannot find a slot big enough to accomodate the requested object.
+#[inline(never)]
 pub extern fn allocate<T>(elem: T) -> KernResult<*mut T> {
-    ALLOCATOR.lock().allocate(elem)
+    let site = unsafe { track::return_address(0) } as usize;
+    let addr = try!(allocate_with(mem::size_of::<T>(), min_align_of::<T>(), ALLOC_BLOCK, site));
+    unsafe { ptr::write(addr as *mut T, elem) };
+    Ok(addr as *mut T)
+}
+
+/// Tries to allocate an object as directed by `flags` and returns a unique pointer to it.
+/// `ALLOC_ZEROED` makes no difference here since the object is moved in.
+///
+/// # Failures
+///
+/// Fails like `allocate_raw_flags`.
+#[inline(never)]
+pub extern fn allocate_flags<T>(elem: T, flags: AllocFlags) -> KernResult<*mut T> {
+    let site = unsafe { track::return_address(0) } as usize;
+    let addr = try!(allocate_with(mem::size_of::<T>(), min_align_of::<T>(), flags, site));
+    unsafe { ptr::write(addr as *mut T, elem) };
+    Ok(addr as *mut T)
 }
 
 /// Tries to allocate an object on the heap from the given constructor and returns a unique pointer
@@ -136,17 +285,238 @@ pub extern fn allocate<T>(elem: T) -> KernResult<*mut T> {
 /// # Failures
 ///
 /// Fails if the heap cannot find a slot big enough to accomodate the requested object.
+#[inline(never)]
 pub extern fn allocate_emplace<F, T>(init: F) -> KernResult<*mut T> where F: Fn(&mut T) {
-    ALLOCATOR.lock().allocate_emplace(init)
+    let site = unsafe { track::return_address(0) } as usize;
+    let addr = try!(allocate_with(mem::size_of::<T>(), min_align_of::<T>(), ALLOC_BLOCK, site));
+    init(unsafe { &mut *(addr as *mut T) });
+    Ok(addr as *mut T)
+}
+
+/// Tries to allocate an object as directed by `flags` from the given constructor and returns a
+/// unique pointer to it. With `ALLOC_ZEROED` the constructor sees zeroed memory.
+///
+/// # Failures
+///
+/// Fails like `allocate_raw_flags`.
+#[inline(never)]
+pub extern fn allocate_emplace_flags<F, T>(init: F, flags: AllocFlags) -> KernResult<*mut T>
+    where F: Fn(&mut T) {
+    let site = unsafe { track::return_address(0) } as usize;
+    let addr = try!(allocate_with(mem::size_of::<T>(), min_align_of::<T>(), flags, site));
+    init(unsafe { &mut *(addr as *mut T) });
+    Ok(addr as *mut T)
 }
 
 /// Frees an object on the heap. If this object implements Drop, its destructor WILL NOT BE CALLED.
 /// This is up to the caller of deallocate to perform. TODO This may want to be changed.
+///
+/// Objects allocated from a `slab::Cache` are returned to their cache.
 pub extern fn deallocate<T: ?Sized>(elem: *mut T) {
-    ALLOCATOR.lock().deallocate(elem)
+    let addr = elem as *const () as usize;
+    if pool::owns(addr) {
+        pool::free(addr);
+        return;
+    }
+    track::remove(addr);
+    if slab::owns(addr) {
+        slab::free(addr)
+    } else {
+        heap_free(addr, mem::size_of_val(unsafe { &*elem }));
+    }
+}
+
+/// Checks the heap for corruption. This only finds anything in kernels built with
+/// `ALLOC_DEBUG := yes`.
+///
+/// # Panics
+///
+/// This function panics if the heap is corrupted.
+pub fn check_heap() {
+    ALLOCATOR.lock().check()
+}
+
+/// Returns whether the freed block at `addr` is still held in the quarantine of the debug
+/// allocator.
+#[cfg(ALLOC_DEBUG="yes")]
+pub fn is_quarantined(addr: usize) -> bool {
+    ALLOCATOR.lock().is_quarantined(addr)
 }
 
 /// Returns an upper bound on the amount of free space.
 pub extern fn get_free_space() -> usize {
     ALLOCATOR.lock().get_free_space()
 }
+
+/// A snapshot of the state of the heap backend.
+#[derive(Clone, Copy)]
+pub struct HeapStats {
+    /// The backend in use.
+    pub backend: HeapBackend,
+    /// An upper bound on the amount of free space, as returned by `get_free_space`.
+    pub free: usize,
+    /// The size of the largest free block.
+    pub largest_free_block: usize,
+}
+
+impl HeapStats {
+
+    /// Returns the percentage of free space that is not part of the largest free block. This is 0
+    /// when all free space is in one piece.
+    pub fn fragmentation(&self) -> usize {
+        if self.free == 0 || self.largest_free_block >= self.free {
+            0
+        } else {
+            100 - self.largest_free_block * 100 / self.free
+        }
+    }
+
+}
+
+impl fmt::Debug for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "backend {:?} free {} bytes largest free block {} bytes fragmentation {}%",
+               self.backend, self.free, self.largest_free_block, self.fragmentation())
+    }
+}
+
+/// Returns a snapshot of the state of the heap backend.
+pub fn heap_stats() -> HeapStats {
+    let allocator = ALLOCATOR.lock();
+    HeapStats {
+        backend: allocator.backend(),
+        free: allocator.get_free_space(),
+        largest_free_block: allocator.largest_free_block(),
+    }
+}
+
+/// Writes the state of the heap backend to `w`.
+pub fn dump_heap_stats<W: fmt::Write>(w: &mut W) -> fmt::Result {
+    let stats = heap_stats();
+    write!(w, "backend: {:?}\nfree: {}\nlargest free block: {}\nfragmentation: {}%\n",
+           stats.backend, stats.free, stats.largest_free_block, stats.fragmentation())
+}
+
+// Allocates memory as directed by `flags` on behalf of the code at `site`. Atomic allocations are
+// not recorded since recording takes a mutex.
+#[inline(always)]
+fn allocate_with(size: usize, align: usize, flags: AllocFlags, site: usize) -> KernResult<usize> {
+    let addr = if flags.contains(ALLOC_ATOMIC) {
+        try!(pool::allocate(size, align))
+    } else {
+        let addr = match heap_allocate(size, align, site) {
+            Err(OutOfMemory) if flags.contains(ALLOC_RESERVE) => try!(pool::allocate(size, align)),
+            res => try!(res),
+        };
+        if !pool::owns(addr) {
+            track::add(addr, size, align, site);
+        }
+        addr
+    };
+    if flags.contains(ALLOC_ZEROED) {
+        unsafe { ptr::write_bytes(addr as *mut u8, 0, size) };
+    }
+    Ok(addr)
+}
+
+// Reallocates memory as directed by `flags` on behalf of the code at `site`. Only heap memory that
+// stays on the heap is reallocated in place by the backend.
+#[inline(always)]
+fn reallocate_with(old_addr: usize, old_size: usize, new_size: usize, align: usize,
+                   flags: AllocFlags, site: usize) -> KernResult<usize> {
+    if flags.contains(ALLOC_ATOMIC) && !pool::owns(old_addr) {
+        // Freeing heap memory takes the heap mutex, which atomic callers must not touch.
+        return Err(OutOfMemory);
+    }
+    let new_addr = if pool::owns(old_addr) || flags.contains(ALLOC_ATOMIC) {
+        if flags.contains(ALLOC_ATOMIC) &&
+           new_size <= pool::block_size(old_addr) && old_addr % align == 0 {
+            old_addr
+        } else {
+            let new_addr = try!(allocate_with(new_size, align, flags - ALLOC_ZEROED, site));
+            let len = cmp::min(old_size, new_size);
+            unsafe { ptr::copy_nonoverlapping(old_addr as *const u8, new_addr as *mut u8, len) };
+            deallocate_raw(old_addr, old_size);
+            new_addr
+        }
+    } else {
+        let new_addr = {
+            let mut allocator = ALLOCATOR.lock();
+            allocator.set_site(site);
+            try!(retry(&mut allocator, new_size, align,
+                       |a| a.reallocate_raw(old_addr, old_size, new_size, align)))
+        };
+        track::remove(old_addr);
+        track::add(new_addr, new_size, align, site);
+        new_addr
+    };
+    if flags.contains(ALLOC_ZEROED) && new_size > old_size {
+        unsafe { ptr::write_bytes((new_addr + old_size) as *mut u8, 0, new_size - old_size) };
+    }
+    Ok(new_addr)
+}
+
+// Allocates memory for internal use without recording it. `site` is passed on to the backend.
+fn heap_allocate(size: usize, align: usize, site: usize) -> KernResult<usize> {
+    let mut allocator = ALLOCATOR.lock();
+    allocator.set_site(site);
+    retry(&mut allocator, size, align, |a| a.allocate_raw(size, align))
+}
+
+// Frees memory allocated with `heap_allocate` and gives back regions that are no longer used.
+fn heap_free(addr: usize, size: usize) {
+    let mut allocator = ALLOCATOR.lock();
+    allocator.deallocate_raw(addr, size);
+    shrink(&mut allocator);
+}
+
+// Runs `op` and, if the heap is out of memory, grows the heap so that it has room for `size` bytes
+// aligned to `align` and runs `op` again.
+fn retry<F>(allocator: &mut Backend, size: usize, align: usize, mut op: F)
+    -> KernResult<usize> where F: FnMut(&mut Backend) -> KernResult<usize> {
+    match op(allocator) {
+        Err(OutOfMemory) => {
+            try!(grow(allocator, size, align));
+            op(allocator)
+        }
+        res => res,
+    }
+}
+
+// Maps at least enough memory for `size` bytes aligned to `align` and adds it to the heap. The
+// start of the new region keeps track of the slabs in it.
+fn grow(allocator: &mut Backend, size: usize, align: usize) -> KernResult<()> {
+    let want = align_up!(size + align + PAGE_SIZE, PAGE_SIZE);
+    let grow_size = cmp::max(want, HEAP_GROW_SIZE);
+    // We know this is safe because the memory manager implements heap_map.
+    let start = try!(unsafe { heap_map(grow_size) }.ok_or(OutOfMemory));
+    let end = start + grow_size;
+    debug!("growing the heap by {} bytes at 0x{:x}", grow_size, start);
+    // We know this is safe because we just mapped the region and nothing else knows about it.
+    unsafe { slab::add_window(start, start, end) };
+    allocator.add_region(start + slab::window_size(start, end), end);
+    Ok(())
+}
+
+// Gives regions the heap has grown by back to the memory manager once they are unused. At least
+// `HEAP_GROW_SIZE` bytes are left free, so allocating and freeing at the edge of the heap doesn't
+// map and unmap a region every time.
+fn shrink(allocator: &mut Backend) {
+    // Every grown region is at least `HEAP_GROW_SIZE` bytes.
+    while allocator.get_free_space() >= 2 * HEAP_GROW_SIZE {
+        let (region_start, region_end) = match allocator.remove_free_region() {
+            Some(region) => region,
+            None => return,
+        };
+        if allocator.get_free_space() < HEAP_GROW_SIZE {
+            // Keep it as a spare.
+            allocator.add_region(region_start, region_end);
+            return;
+        }
+        let (start, end) = slab::remove_window(region_start);
+        debug!("shrinking the heap by {} bytes at 0x{:x}", end - start, start);
+        // We know this is safe because the memory manager implements heap_unmap and the region is
+        // no longer part of the heap.
+        unsafe { heap_unmap(start, end - start) };
+    }
+}
@@ -1,13 +1,15 @@
-//! 
+//!
 //! This module contains a naive allocator that is very good at allocations and not so good at
 //! deallocations. This is primarily used for testing when we want an allocator that is probably
 //! not the source of the problem.
 //!
+//! Since nothing is ever freed, the leak checks of the self tests fail with this allocator.
+//!
 use core::prelude::*;
-use core::ptr::Unique;
-use core::mem;
+use core::{cmp, ptr};
 use Allocator;
-use util::align_up;
+use util::KernResult;
+use util::KernError::OutOfMemory;
 logger_init!(Trace);
 
 /// A naive allocator that always allocates at the next lowest address on the heap. When the heap
@@ -25,7 +27,7 @@ pub const NAIVE_ALLOCATOR_INIT: NaiveAllocator = NaiveAllocator {
 };
 
 impl NaiveAllocator {
-    
+
     pub fn init(&mut self, heap_start: usize, heap_end: usize) {
         assert_eq!(self.heap_start, 0);
         assert_eq!(self.heap_end, 0);
@@ -40,20 +42,49 @@ impl NaiveAllocator {
 
 impl Allocator for NaiveAllocator {
 
-    fn allocate_raw(&mut self, size: usize, align: usize) -> Option<usize> {
+    fn allocate_raw(&mut self, size: usize, align: usize) -> KernResult<usize> {
         trace!("trying to allocate {} bytes aligned to 0x{:x}", size, align);
-        if align_up(self.heap_cur, align) + size > self.heap_end {
+        let addr = align_up!(self.heap_cur, align);
+        if addr + size > self.heap_end {
             trace!("not enough space on heap");
-            None
+            Err(OutOfMemory)
         } else {
-            trace!("allocated {} bytes at {:x}", size, align_up(self.heap_cur, align));
-            self.heap_cur = align_up(self.heap_cur, align) + size;
-            Some(self.heap_cur - size)
+            trace!("allocated {} bytes at {:x}", size, addr);
+            self.heap_cur = addr + size;
+            Ok(addr)
         }
     }
 
-    fn deallocate_raw(&mut self, addr: usize, size: usize) {
+    fn reallocate_raw(&mut self, old_addr: usize, old_size: usize, new_size: usize, align: usize)
+                      -> KernResult<usize> {
+        let new_addr = try!(self.allocate_raw(new_size, align));
+        let len = cmp::min(old_size, new_size);
+        unsafe { ptr::copy_nonoverlapping(old_addr as *const u8, new_addr as *mut u8, len) };
+        Ok(new_addr)
+    }
+
+    fn deallocate_raw(&mut self, _addr: usize, _size: usize) {
         // Welp.
     }
 
+    fn get_free_space(&self) -> usize {
+        self.heap_end - self.heap_cur
+    }
+
+    fn largest_free_block(&self) -> usize {
+        self.heap_end - self.heap_cur
+    }
+
+    fn add_region(&mut self, start: usize, end: usize) {
+        // Whatever is left of the current region is abandoned.
+        trace!("moving to region 0x{:x}-0x{:x}", start, end);
+        self.heap_start = start;
+        self.heap_cur = start;
+        self.heap_end = end;
+    }
+
+    fn remove_free_region(&mut self) -> Option<(usize, usize)> {
+        None
+    }
+
 }
@@ -0,0 +1,199 @@
+//!
+//! The emergency pool.
+//!
+//! The heap is protected by a mutex that yields to the scheduler when it is contended, so it can't
+//! be used from interrupt handlers. The emergency pool is a small, pre-filled set of blocks taken
+//! from the heap at boot that is protected by disabling interrupts instead. Allocations made with
+//! `ALLOC_ATOMIC` come from here, and allocations made with `ALLOC_RESERVE` fall back to it when
+//! the heap is out of memory.
+//!
+//! Blocks come in a few power-of-two size classes. Each class is a contiguous run of blocks with a
+//! free list threaded through the free ones. The largest class comes first and the pool starts on
+//! a page boundary, so every block is aligned to its size.
+//!
+use core::prelude::*;
+use core::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::cmp;
+use util::{asm, KernResult, PAGE_SIZE};
+use util::KernError::OutOfMemory;
+logger_init!(Error);
+
+/// The number of size classes.
+pub const CLASSES: usize = 7;
+
+/// The size of the blocks of the largest class. Every other class is half the size of the one
+/// before it.
+const LARGEST_CLASS: usize = 2048;
+
+/// The size of the blocks of each class, largest first.
+pub const CLASS_SIZES: [usize; CLASSES] = [LARGEST_CLASS, LARGEST_CLASS >> 1, LARGEST_CLASS >> 2,
+                                           LARGEST_CLASS >> 3, LARGEST_CLASS >> 4,
+                                           LARGEST_CLASS >> 5, LARGEST_CLASS >> 6];
+
+/// The number of blocks of each class.
+pub const BLOCKS_PER_CLASS: usize = 16;
+
+/// The size of the pool in bytes. The class sizes halve, so they add up to twice the largest less
+/// the smallest.
+pub const POOL_SIZE: usize =
+    BLOCKS_PER_CLASS * (2 * LARGEST_CLASS - (LARGEST_CLASS >> (CLASSES - 1)));
+
+// A lock that keeps interrupt handlers out by disabling interrupts. The kernel only runs on one
+// processor so that is all it takes.
+struct IrqLock<T> {
+    data: UnsafeCell<T>,
+}
+
+struct IrqLockGuard<'a, T: 'a> {
+    reenable: bool,
+    data: &'a UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for IrqLock<T> { }
+
+impl<T> IrqLock<T> {
+
+    const fn new(data: T) -> IrqLock<T> {
+        IrqLock { data: UnsafeCell::new(data) }
+    }
+
+    fn lock(&self) -> IrqLockGuard<T> {
+        let reenable = asm::interrupts_enabled();
+        if reenable {
+            asm::disable_interrupts();
+        }
+        IrqLockGuard { reenable: reenable, data: &self.data }
+    }
+
+}
+
+impl<'a, T> Deref for IrqLockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for IrqLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+impl<'a, T> Drop for IrqLockGuard<'a, T> {
+    fn drop(&mut self) {
+        if self.reenable {
+            asm::enable_interrupts();
+        }
+    }
+}
+
+// The start of the pool, or 0 before `init`. It never changes after that, so it is kept out of
+// `POOL` to let `owns` check addresses without disabling interrupts.
+static START: AtomicUsize = ATOMIC_USIZE_INIT;
+
+struct Pool {
+    // The first free block of each class. Free blocks point to the next one with their first word.
+    free: [usize; CLASSES],
+    available: [usize; CLASSES],
+    failures: usize,
+}
+
+static POOL: IrqLock<Pool> = IrqLock::new(Pool {
+    free: [0; CLASSES],
+    available: [0; CLASSES],
+    failures: 0,
+});
+
+// Returns the offset of the first block of `class` from the start of the pool.
+fn class_offset(class: usize) -> usize {
+    CLASS_SIZES[..class].iter().fold(0, |sum, size| sum + size * BLOCKS_PER_CLASS)
+}
+
+/// A snapshot of the state of the pool.
+#[derive(Clone, Copy)]
+pub struct PoolStats {
+    /// The number of free blocks of each class.
+    pub available: [usize; CLASSES],
+    /// The number of allocations the pool could not serve.
+    pub failures: usize,
+}
+
+/// Fills the pool with `POOL_SIZE` bytes at `start`, which must be page aligned.
+pub fn init(start: usize) {
+    assert!(start % PAGE_SIZE == 0);
+    assert!(START.load(Ordering::Relaxed) == 0, "the pool is already initialized");
+    let mut pool = POOL.lock();
+    for class in 0 .. CLASSES {
+        let first = start + class_offset(class);
+        for i in (0 .. BLOCKS_PER_CLASS).rev() {
+            let block = first + i * CLASS_SIZES[class];
+            // We know this is safe because the pool owns the memory.
+            unsafe { *(block as *mut usize) = pool.free[class] };
+            pool.free[class] = block;
+        }
+        pool.available[class] = BLOCKS_PER_CLASS;
+    }
+    START.store(start, Ordering::Release);
+}
+
+/// Returns whether `addr` is a block of the pool.
+pub fn owns(addr: usize) -> bool {
+    let start = START.load(Ordering::Acquire);
+    start != 0 && start <= addr && addr < start + POOL_SIZE
+}
+
+/// Tries to allocate a block that can hold `size` bytes aligned to `align`. This never blocks.
+///
+/// # Failures
+///
+/// Fails if the request is larger than the largest class or all fitting blocks are taken.
+pub fn allocate(size: usize, align: usize) -> KernResult<usize> {
+    let needed = cmp::max(size, align);
+    let mut pool = POOL.lock();
+    // Take the smallest block that fits, or a larger one if those are gone.
+    for class in (0 .. CLASSES).rev() {
+        if CLASS_SIZES[class] < needed || pool.free[class] == 0 {
+            continue;
+        }
+        let block = pool.free[class];
+        // We know this is safe because free blocks hold the address of the next one.
+        pool.free[class] = unsafe { *(block as *const usize) };
+        pool.available[class] -= 1;
+        trace!("allocated {} bytes from the pool at 0x{:x}", size, block);
+        return Ok(block);
+    }
+    pool.failures += 1;
+    Err(OutOfMemory)
+}
+
+/// Returns a block to the pool.
+///
+/// # Panics
+///
+/// This function panics if `addr` is not the start of a block of the pool.
+pub fn free(addr: usize) {
+    let offset = addr - START.load(Ordering::Acquire);
+    let mut pool = POOL.lock();
+    let class = (0 .. CLASSES).rev().find(|&c| offset >= class_offset(c)).unwrap();
+    assert!((offset - class_offset(class)) % CLASS_SIZES[class] == 0, "not a pool block");
+    // We know this is safe because the block is ours again.
+    unsafe { *(addr as *mut usize) = pool.free[class] };
+    pool.free[class] = addr;
+    pool.available[class] += 1;
+}
+
+/// Returns the size of the block at `addr`.
+pub fn block_size(addr: usize) -> usize {
+    let offset = addr - START.load(Ordering::Acquire);
+    let class = (0 .. CLASSES).rev().find(|&c| offset >= class_offset(c)).unwrap();
+    CLASS_SIZES[class]
+}
+
+/// Returns a snapshot of the state of the pool.
+pub fn stats() -> PoolStats {
+    let pool = POOL.lock();
+    PoolStats { available: pool.available, failures: pool.failures }
+}
@@ -4,6 +4,7 @@ use core::ops::{Deref, CoerceUnsized};
 use core::marker::Unsize;
 use core::any::Any;
 use core::fmt;
+use core::intrinsics::drop_in_place;
 use boxed::Box;
 logger_init!(Debug);
 
@@ -11,6 +12,14 @@ logger_init!(Debug);
 pub trait HasRc {
     /// Returns the reference count.
     fn get_count(&self) -> &AtomicUsize;
+
+    /// Returns the weak reference count if the type can be pointed to by a `Weak`. All strong
+    /// references together hold one weak reference, so the memory of the value stays around until
+    /// the last `Weak` is gone even though the value itself is dropped with the last `Rc`. The
+    /// value's destructor must leave the weak count alone.
+    fn get_weak_count(&self) -> Option<&AtomicUsize> {
+        None
+    }
 }
 
 /// A reference counted pointer.
@@ -25,6 +34,9 @@ impl<T: ?Sized + HasRc> Rc<T> {
     /// Constructs a new RC type.
     pub fn new(val: Box<T>) -> Rc<T> {
         val.get_count().store(1, Ordering::Relaxed);
+        if let Some(weak) = val.get_weak_count() {
+            weak.store(1, Ordering::Relaxed);
+        }
         Rc { value: unsafe { val.into_raw() } }
     }
 
@@ -39,6 +51,15 @@ impl<T: ?Sized + HasRc> Rc<T> {
         assert!(val.get_count().fetch_add(1, Ordering::Relaxed) > 0);
         Rc { value: val as *const T as *mut T } 
     }
+
+    /// Creates a weak reference to the value.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the value has no weak count.
+    pub fn downgrade(&self) -> Weak<T> {
+        Weak::from_ref(self.deref())
+    }
 }
 
 impl<T: ?Sized + HasRc> Clone for Rc<T> {
@@ -53,8 +74,18 @@ impl<T: ?Sized + HasRc> Drop for Rc<T> {
         trace!("dropping rc 0x{:x}", self.value as *const () as usize);
         let count = unsafe { &*self.value }.get_count().fetch_sub(1, Ordering::Relaxed);
         if count == 1 {
-            // We were the last reference. Drop the contents.
-            unsafe { drop(Box::from_raw(self.value)) };
+            // We were the last reference. Drop the contents. If there are weak references the
+            // memory stays until the last of them is gone.
+            let value = unsafe { &*self.value };
+            match value.get_weak_count() {
+                Some(weak) => unsafe {
+                    drop_in_place(self.value);
+                    if weak.fetch_sub(1, Ordering::Relaxed) == 1 {
+                        ::deallocate(self.value);
+                    }
+                },
+                None => unsafe { drop(Box::from_raw(self.value)) },
+            }
         }
     }
 }
@@ -73,6 +104,65 @@ impl <T: ?Sized + HasRc + fmt::Debug> fmt::Debug for Rc<T> {
     }
 }
 
+/// A weak reference to a reference counted value. It does not keep the value alive but can be
+/// turned into an `Rc` for as long as the value is.
+pub struct Weak<T: ?Sized + HasRc> {
+    value: *mut T
+}
+
+impl<T: ?Sized+Unsize<U>+HasRc, U: ?Sized+HasRc> CoerceUnsized<Weak<U>> for Weak<T> {}
+
+impl<T: ?Sized + HasRc> Weak<T> {
+    /// Constructs a weak reference from a reference.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the value has no weak count or if it is not owned by an `Rc`.
+    pub fn from_ref(val: &T) -> Weak<T> {
+        let weak = val.get_weak_count().expect("value has no weak count");
+        assert!(val.get_count().load(Ordering::Relaxed) > 0);
+        weak.fetch_add(1, Ordering::Relaxed);
+        Weak { value: val as *const T as *mut T }
+    }
+
+    /// Returns a strong reference to the value, or `None` if it was already dropped.
+    pub fn upgrade(&self) -> Option<Rc<T>> {
+        // We know the counts are still there because we hold a weak reference.
+        let count = unsafe { &*self.value }.get_count();
+        let mut old = count.load(Ordering::Relaxed);
+        while old != 0 {
+            let seen = count.compare_and_swap(old, old + 1, Ordering::Relaxed);
+            if seen == old {
+                return Some(Rc { value: self.value });
+            }
+            old = seen;
+        }
+        None
+    }
+
+    // Returns the weak count of the value.
+    fn weak_count(&self) -> &AtomicUsize {
+        unsafe { &*self.value }.get_weak_count().unwrap()
+    }
+}
+
+impl<T: ?Sized + HasRc> Clone for Weak<T> {
+    fn clone(&self) -> Weak<T> {
+        self.weak_count().fetch_add(1, Ordering::Relaxed);
+        Weak { value: self.value }
+    }
+}
+
+impl<T: ?Sized + HasRc> Drop for Weak<T> {
+    fn drop(&mut self) {
+        if self.weak_count().fetch_sub(1, Ordering::Relaxed) == 1 {
+            // The value was dropped along with the last strong reference and we were the last
+            // reference of any kind. Free its memory.
+            ::deallocate(self.value);
+        }
+    }
+}
+
 /// A reference counted Any type. This is necessary in order to store an Any type in an Rc pointer.
 /// It is necessary to have the `as_any` method because we can't implement methods like
 /// `downcast_ref` that are defined in the `impl Any` from an `RcAny` trait object.
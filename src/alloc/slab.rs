@@ -0,0 +1,529 @@
+//!
+//! Object caches for fixed-size kernel objects.
+//!
+//! A `Cache<T>` hands out memory for `T`s from slabs. A slab is a page taken from the heap that is
+//! carved into equally sized slots, so allocating and freeing an object is a matter of popping and
+//! pushing a free list instead of searching the heap. Objects of the same type also end up next to
+//! each other, which keeps them from fragmenting the heap.
+//!
+//! Caches are meant to be statics with a name. They register themselves on first use so their
+//! statistics can be listed with `for_each_cache`. A cache can have a constructor that initializes
+//! objects allocated with `Box::construct_in`.
+//!
+//! Objects are freed through the normal deallocation path. A bitmap of the heap pages that are
+//! slabs tells `deallocate` which objects to return to their cache. Regions the heap grows by get a
+//! window of their own with `add_window`, which keeps its part of the bitmap inside the region.
+//! Every slab starts with a header that points back at its cache. A slab is returned to the heap
+//! once its last object is freed, unless it is the only slab of its cache with free slots.
+//!
+//! Kernels built with `ALLOC_DEBUG := yes` don't use slabs. Every object gets a heap block of its
+//! own so the debug allocator can put red zones around it and quarantine it once it is freed. Such
+//! objects are freed like any other heap block, so their caches only count allocations.
+//!
+use core::prelude::*;
+use core::{cmp, fmt, mem, ptr};
+use core::mem::min_align_of;
+use core::atomic::{AtomicBool, AtomicUsize, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT, Ordering};
+use mutex::Mutex;
+use track;
+use util::{KernResult, PAGE_SIZE, page_align};
+logger_init!(Trace);
+
+/// The largest object a cache can hold. Slabs are a single page and should hold at least eight
+/// objects.
+pub const MAX_OBJECT_SIZE: usize = PAGE_SIZE / 8;
+
+// The largest heap the slab bitmap can describe.
+const MAX_HEAP_PAGES: usize = 4096;
+
+// The number of pages tracked by each word of the bitmap.
+const BITS_PER_WORD: usize = 32;
+
+// The heap pages that are slabs. The initial heap has a bitmap of its own and every region the heap
+// grows by has a window that keeps its bitmap at the start of the region.
+struct SlabPages {
+    first: usize,
+    pages: usize,
+    bitmap: [u32; MAX_HEAP_PAGES / BITS_PER_WORD],
+    windows: *mut Window,
+}
+
+// A region the heap has grown by, followed by the words of its bitmap.
+struct Window {
+    next: *mut Window,
+    first: usize,
+    pages: usize,
+}
+
+static SLAB_PAGES: Mutex<SlabPages> = Mutex::new(SlabPages {
+    first: 0,
+    pages: 0,
+    bitmap: [0; MAX_HEAP_PAGES / BITS_PER_WORD],
+    windows: 0 as *mut Window,
+});
+
+impl Window {
+
+    // Returns the first word of the window's bitmap.
+    fn bitmap(&mut self) -> *mut u32 {
+        unsafe { (self as *mut Window).offset(1) as *mut u32 }
+    }
+
+}
+
+impl SlabPages {
+
+    // Returns the bitmap word and bit of the page containing `addr` if it is part of the heap.
+    fn locate(&mut self, addr: usize) -> Option<(*mut u32, u32)> {
+        let page = addr / PAGE_SIZE;
+        if page >= self.first && page < self.first + self.pages {
+            let i = page - self.first;
+            let word = &mut self.bitmap[i / BITS_PER_WORD] as *mut u32;
+            return Some((word, 1 << (i % BITS_PER_WORD)));
+        }
+        let mut window = self.windows;
+        // We know this is safe because windows stay mapped until they are removed.
+        unsafe {
+            while !window.is_null() {
+                let w = &mut *window;
+                if page >= w.first && page < w.first + w.pages {
+                    let i = page - w.first;
+                    let word = w.bitmap().offset((i / BITS_PER_WORD) as isize);
+                    return Some((word, 1 << (i % BITS_PER_WORD)));
+                }
+                window = w.next;
+            }
+        }
+        None
+    }
+
+    fn contains(&mut self, addr: usize) -> bool {
+        match self.locate(addr) {
+            Some((word, bit)) => unsafe { *word & bit != 0 },
+            None => false,
+        }
+    }
+
+    fn set(&mut self, addr: usize, slab: bool) {
+        let (word, bit) = self.locate(addr).expect("slab outside of the heap");
+        unsafe {
+            if slab {
+                *word |= bit;
+            } else {
+                *word &= !bit;
+            }
+        }
+    }
+
+}
+
+// The header at the start of every slab. Free slots are linked through their first word.
+struct Slab {
+    cache: *const RawCache,
+    prev: *mut Slab,
+    next: *mut Slab,
+    free: usize,
+    in_use: usize,
+}
+
+/// A snapshot of the statistics of a cache.
+#[derive(Clone, Copy)]
+pub struct CacheStats {
+    /// The name of the cache.
+    pub name: &'static str,
+    /// The size of each slot in bytes. This is zero until the cache is first used.
+    pub slot_size: usize,
+    /// The number of objects that fit in a slab.
+    pub slots_per_slab: usize,
+    /// The number of slabs the cache currently owns.
+    pub slabs: usize,
+    /// The number of objects currently allocated.
+    pub active: usize,
+    /// The number of allocations made from the cache.
+    pub allocs: usize,
+    /// The number of objects returned to the cache.
+    pub frees: usize,
+    /// The number of allocations that failed because no slab could be allocated.
+    pub failures: usize,
+}
+
+impl fmt::Debug for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:<16} {:>5} {:>6}/{:<6} {:>4} slabs {:>8} allocs {:>8} frees {:>4} failed",
+               self.name, self.slot_size, self.active, self.slabs * self.slots_per_slab,
+               self.slabs, self.allocs, self.frees, self.failures)
+    }
+}
+
+// The part of a cache's state that changes.
+struct CacheState {
+    slot_size: usize,
+    first_slot: usize,
+    slots_per_slab: usize,
+    // Slabs with free slots. Full slabs are not tracked.
+    partial: *mut Slab,
+    slabs: usize,
+    active: usize,
+    allocs: usize,
+    frees: usize,
+    failures: usize,
+}
+
+impl CacheState {
+
+    // Computes the slab layout the first time the cache is used.
+    fn setup(&mut self, size: usize, align: usize) {
+        assert!(align <= PAGE_SIZE / 2);
+        let slot_size = align_up!(cmp::max(size, mem::size_of::<usize>()), align);
+        assert!(slot_size <= MAX_OBJECT_SIZE);
+        self.slot_size = slot_size;
+        self.first_slot = align_up!(mem::size_of::<Slab>(), align);
+        self.slots_per_slab = (PAGE_SIZE - self.first_slot) / slot_size;
+    }
+
+    // Puts a slab at the head of the partial list.
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    // Takes a slab off the partial list.
+    unsafe fn unlink(&mut self, slab: *mut Slab) {
+        if (*slab).prev.is_null() {
+            self.partial = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = ptr::null_mut();
+    }
+
+    // Allocates a new slab from the heap on behalf of `site` and puts all of its slots on its free
+    // list.
+    #[cfg(not(ALLOC_DEBUG="yes"))]
+    unsafe fn grow(&mut self, cache: *const RawCache, site: usize) -> KernResult<()> {
+        let addr = try!(::heap_allocate(PAGE_SIZE, PAGE_SIZE, site));
+        SLAB_PAGES.lock().set(addr, true);
+        let slab = addr as *mut Slab;
+        ptr::write(slab, Slab {
+            cache: cache,
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            free: 0,
+            in_use: 0,
+        });
+        for i in (0 .. self.slots_per_slab).rev() {
+            let slot = addr + self.first_slot + i * self.slot_size;
+            *(slot as *mut usize) = (*slab).free;
+            (*slab).free = slot;
+        }
+        self.push(slab);
+        self.slabs += 1;
+        trace!("new slab at 0x{:x}", addr);
+        Ok(())
+    }
+
+}
+
+// The part of a cache that does not depend on the type of its objects.
+struct RawCache {
+    name: &'static str,
+    registered: AtomicBool,
+    next: AtomicUsize,
+    state: Mutex<CacheState>,
+}
+
+// The list of caches that have been used, linked through `RawCache::next`.
+static CACHES: Mutex<usize> = Mutex::new(0);
+
+impl RawCache {
+
+    const fn new(name: &'static str) -> RawCache {
+        RawCache {
+            name: name,
+            registered: ATOMIC_BOOL_INIT,
+            next: ATOMIC_USIZE_INIT,
+            state: Mutex::new(CacheState {
+                slot_size: 0,
+                first_slot: 0,
+                slots_per_slab: 0,
+                partial: 0 as *mut Slab,
+                slabs: 0,
+                active: 0,
+                allocs: 0,
+                frees: 0,
+                failures: 0,
+            }),
+        }
+    }
+
+    // Adds the cache to the list of caches the first time it is used.
+    fn register(&'static self) {
+        if !self.registered.swap(true, Ordering::SeqCst) {
+            let mut head = CACHES.lock();
+            self.next.store(*head, Ordering::SeqCst);
+            *head = self as *const RawCache as usize;
+        }
+    }
+
+    // Allocates a slot for an object with the given size and alignment on behalf of `site`.
+    #[cfg(not(ALLOC_DEBUG="yes"))]
+    fn allocate(&'static self, size: usize, align: usize, site: usize) -> KernResult<usize> {
+        self.register();
+        let mut state = self.state.lock();
+        if state.slot_size == 0 {
+            state.setup(size, align);
+        }
+        // We know this is safe because the slabs on the partial list are owned by this cache.
+        unsafe {
+            if state.partial.is_null() {
+                if let Err(e) = state.grow(self, site) {
+                    state.failures += 1;
+                    return Err(e);
+                }
+            }
+            let slab = state.partial;
+            let slot = (*slab).free;
+            (*slab).free = *(slot as *const usize);
+            (*slab).in_use += 1;
+            if (*slab).free == 0 {
+                state.unlink(slab);
+            }
+            state.active += 1;
+            state.allocs += 1;
+            Ok(slot)
+        }
+    }
+
+    // Allocates a heap block for an object with the given size and alignment on behalf of `site`.
+    #[cfg(ALLOC_DEBUG="yes")]
+    fn allocate(&'static self, size: usize, align: usize, site: usize) -> KernResult<usize> {
+        self.register();
+        let mut state = self.state.lock();
+        if state.slot_size == 0 {
+            state.setup(size, align);
+        }
+        match ::heap_allocate(size, align, site) {
+            Ok(addr) => {
+                state.allocs += 1;
+                Ok(addr)
+            }
+            Err(e) => {
+                state.failures += 1;
+                Err(e)
+            }
+        }
+    }
+
+    fn stats(&self) -> CacheStats {
+        let state = self.state.lock();
+        CacheStats {
+            name: self.name,
+            slot_size: state.slot_size,
+            slots_per_slab: state.slots_per_slab,
+            slabs: state.slabs,
+            active: state.active,
+            allocs: state.allocs,
+            frees: state.frees,
+            failures: state.failures,
+        }
+    }
+
+}
+
+/// A named cache of `T`s. Caches are meant to be statics.
+pub struct Cache<T> {
+    raw: RawCache,
+    ctor: Option<fn(&mut T)>,
+}
+
+// The cache only hands out memory. The objects themselves are owned by their boxes.
+unsafe impl<T> Sync for Cache<T> { }
+
+impl<T> Cache<T> {
+
+    /// Creates an empty cache. No memory is allocated until the first object is.
+    pub const fn new(name: &'static str) -> Cache<T> {
+        Cache { raw: RawCache::new(name), ctor: None }
+    }
+
+    /// Creates an empty cache whose objects can be initialized by `ctor` (see `construct`).
+    pub const fn with_ctor(name: &'static str, ctor: fn(&mut T)) -> Cache<T> {
+        Cache { raw: RawCache::new(name), ctor: Some(ctor) }
+    }
+
+    /// Returns the name of the cache.
+    pub fn name(&self) -> &'static str {
+        self.raw.name
+    }
+
+    /// Tries to allocate a slot and moves `elem` into it. Returns a unique pointer to the object.
+    /// The slot goes back to the cache when the pointer is passed to `alloc::deallocate`.
+    ///
+    /// # Failures
+    ///
+    /// Fails if the cache is full and there is no memory for another slab.
+    #[inline(never)]
+    pub fn allocate(&'static self, elem: T) -> KernResult<*mut T> {
+        let (size, align) = (mem::size_of::<T>(), min_align_of::<T>());
+        let site = unsafe { track::return_address(0) } as usize;
+        let addr = try!(self.raw.allocate(size, align, site)) as *mut T;
+        track::add(addr as usize, size, align, site);
+        unsafe { ptr::write(addr, elem) };
+        Ok(addr)
+    }
+
+    /// Tries to allocate a slot and initializes it with the cache's constructor. Returns a unique
+    /// pointer to the object.
+    ///
+    /// # Failures
+    ///
+    /// Fails if the cache is full and there is no memory for another slab.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the cache has no constructor.
+    #[inline(never)]
+    pub fn construct(&'static self) -> KernResult<*mut T> {
+        let ctor = self.ctor.expect("cache has no constructor");
+        let (size, align) = (mem::size_of::<T>(), min_align_of::<T>());
+        let site = unsafe { track::return_address(0) } as usize;
+        let addr = try!(self.raw.allocate(size, align, site)) as *mut T;
+        track::add(addr as usize, size, align, site);
+        ctor(unsafe { &mut *addr });
+        Ok(addr)
+    }
+
+    /// Returns a snapshot of the cache's statistics.
+    pub fn stats(&self) -> CacheStats {
+        self.raw.stats()
+    }
+
+}
+
+/// Sets up the slab bitmap for the heap between `heap_start` and `heap_end`.
+pub fn init(heap_start: usize, heap_end: usize) {
+    let mut pages = SLAB_PAGES.lock();
+    pages.first = heap_start / PAGE_SIZE;
+    pages.pages = align_up!(heap_end, PAGE_SIZE) / PAGE_SIZE - pages.first;
+    assert!(pages.pages <= MAX_HEAP_PAGES);
+}
+
+/// Returns the number of bytes `add_window` needs to track the slabs between `start` and `end`.
+pub fn window_size(start: usize, end: usize) -> usize {
+    let pages = (end - start) / PAGE_SIZE;
+    mem::size_of::<Window>() + align_up!(pages, BITS_PER_WORD) / BITS_PER_WORD * 4
+}
+
+/// Starts tracking slabs in the pages between `start` and `end`, which the heap has grown by. The
+/// window is kept in the first `window_size(start, end)` bytes at `storage`.
+///
+/// # Unsafety
+///
+/// `storage` must stay mapped and unused by anything else until `remove_window` is called.
+pub unsafe fn add_window(storage: usize, start: usize, end: usize) {
+    let window = storage as *mut Window;
+    ptr::write_bytes(storage as *mut u8, 0, window_size(start, end));
+    let mut pages = SLAB_PAGES.lock();
+    ptr::write(window, Window {
+        next: pages.windows,
+        first: start / PAGE_SIZE,
+        pages: (end - start) / PAGE_SIZE,
+    });
+    pages.windows = window;
+}
+
+/// Stops tracking the window containing `addr` and returns the bounds it was added with.
+///
+/// # Panics
+///
+/// This function panics if no window contains `addr` or the window still contains slabs.
+pub fn remove_window(addr: usize) -> (usize, usize) {
+    let page = addr / PAGE_SIZE;
+    let mut pages = SLAB_PAGES.lock();
+    let mut link: *mut *mut Window = &mut pages.windows;
+    // We know this is safe because windows stay mapped until they are removed.
+    unsafe {
+        while !(*link).is_null() {
+            let window = &mut **link;
+            if page >= window.first && page < window.first + window.pages {
+                let words = align_up!(window.pages, BITS_PER_WORD) / BITS_PER_WORD;
+                for i in 0 .. words {
+                    assert!(*window.bitmap().offset(i as isize) == 0, "window still has slabs");
+                }
+                *link = window.next;
+                return (window.first * PAGE_SIZE, (window.first + window.pages) * PAGE_SIZE);
+            }
+            link = &mut window.next;
+        }
+    }
+    panic!("no slab window at 0x{:x}", addr);
+}
+
+/// Returns whether `addr` is an object allocated from a cache.
+pub fn owns(addr: usize) -> bool {
+    SLAB_PAGES.lock().contains(addr)
+}
+
+/// Returns the object at `addr` to the cache it was allocated from. The object must already have
+/// been dropped.
+///
+/// # Panics
+///
+/// This function panics if `addr` was not allocated from a cache.
+pub fn free(addr: usize) {
+    assert!(owns(addr));
+    let slab = page_align(addr) as *mut Slab;
+
+    // We know this is safe because the page is a slab so it starts with a header.
+    unsafe {
+        let cache = &*(*slab).cache;
+        let mut state = cache.state.lock();
+        let was_full = (*slab).free == 0;
+        *(addr as *mut usize) = (*slab).free;
+        (*slab).free = addr;
+        (*slab).in_use -= 1;
+        state.active -= 1;
+        state.frees += 1;
+        if was_full {
+            state.push(slab);
+        }
+
+        // Keep the slab if it is the only one with free slots.
+        let alone = (*slab).prev.is_null() && (*slab).next.is_null();
+        if (*slab).in_use == 0 && !alone {
+            state.unlink(slab);
+            state.slabs -= 1;
+            SLAB_PAGES.lock().set(slab as usize, false);
+            ::heap_free(slab as usize, PAGE_SIZE);
+            trace!("released slab at 0x{:x}", slab as usize);
+        }
+    }
+}
+
+/// Calls `op` with the statistics of every cache that has been used, most recently used first.
+pub fn for_each_cache<F>(mut op: F) where F: FnMut(&CacheStats) {
+    let mut addr = *CACHES.lock();
+    while addr != 0 {
+        // We know this is safe because caches are statics and never go away.
+        let cache = unsafe { &*(addr as *const RawCache) };
+        op(&cache.stats());
+        addr = cache.next.load(Ordering::SeqCst);
+    }
+}
+
+/// Writes the statistics of every cache that has been used to `w`, one per line.
+pub fn dump_caches<W: fmt::Write>(w: &mut W) -> fmt::Result {
+    let mut res = Ok(());
+    for_each_cache(|stats| if res.is_ok() {
+        res = write!(w, "{:?}\n", stats);
+    });
+    res
+}
@@ -0,0 +1,438 @@
+//!
+//! A Two-Level Segregated Fit allocator.
+//!
+//! Free blocks are kept in lists by size. The first level splits sizes by powers of two and the
+//! second level splits every power of two into `SL_COUNT` equal ranges. A bitmap for each level
+//! records which lists are non-empty, so finding a free block that fits takes a couple of bit scans
+//! instead of a walk over the heap. Allocation and freeing take constant time.
+//!
+//! More information can be found in "TLSF: a New Dynamic Memory Allocator for Real-Time Systems"
+//! by Masmano et al.
+//!
+//! Every block starts with a header holding its size and the block before it in memory, so freed
+//! blocks can be merged with their neighbours right away. Free blocks additionally link to the
+//! other blocks in their list. Each region of the heap begins with a `Region` and ends with an
+//! empty block that is never free, so merging stops at region boundaries.
+//!
+use core::prelude::*;
+use core::{cmp, mem, ptr};
+use Allocator;
+use util::KernResult;
+use util::KernError::OutOfMemory;
+logger_init!(Error);
+
+// Block sizes and addresses are multiples of this. It is also the size of a block header.
+const ALIGN: usize = 8;
+
+// The log2 of the number of second level lists for each first level list.
+const SL_LOG2: usize = 4;
+const SL_COUNT: usize = 1 << SL_LOG2;
+
+// Blocks smaller than this all go into the first first level list, which is split linearly.
+const FL_SHIFT: usize = SL_LOG2 + 3;
+const SMALL_BLOCK: usize = 1 << FL_SHIFT;
+
+// The number of first level lists. This covers every size that fits in 32 bits.
+const FL_COUNT: usize = 32 - FL_SHIFT + 1;
+
+// The largest allocation that can be made.
+const MAX_SIZE: usize = 1 << 30;
+
+// Set in the size of a block that is free.
+const FREE: usize = 0x1;
+
+#[repr(C)]
+struct Block {
+    // The block before this one in memory, or null for the first block of a region.
+    prev_phys: *mut Block,
+    // The size of the block without its header, plus the `FREE` bit.
+    size: usize,
+    // The neighbours in the free list. These overlap the contents of blocks that are in use.
+    next_free: *mut Block,
+    prev_free: *mut Block,
+}
+
+impl Block {
+
+    fn size(&self) -> usize {
+        self.size & !FREE
+    }
+
+    fn is_free(&self) -> bool {
+        self.size & FREE != 0
+    }
+
+    fn set_size(&mut self, size: usize) {
+        self.size = size | (self.size & FREE);
+    }
+
+    fn set_free(&mut self, free: bool) {
+        self.size = if free { self.size | FREE } else { self.size & !FREE };
+    }
+
+    fn payload(&self) -> usize {
+        self as *const Block as usize + ALIGN
+    }
+
+    fn next_phys(&self) -> *mut Block {
+        (self.payload() + self.size()) as *mut Block
+    }
+
+}
+
+// The start of a region of the heap.
+#[repr(C)]
+struct Region {
+    next: *mut Region,
+    end: usize,
+    first: *mut Block,
+}
+
+pub struct TLSFAllocator {
+    fl_bitmap: u32,
+    sl_bitmap: [u32; FL_COUNT],
+    lists: [[*mut Block; SL_COUNT]; FL_COUNT],
+    regions: *mut Region,
+    initial: *mut Region,
+    // The total size of the free blocks, not counting their headers.
+    free: usize,
+}
+
+pub const TLSF_ALLOCATOR_INIT: TLSFAllocator = TLSFAllocator {
+    fl_bitmap: 0,
+    sl_bitmap: [0; FL_COUNT],
+    lists: [[0 as *mut Block; SL_COUNT]; FL_COUNT],
+    regions: 0 as *mut Region,
+    initial: 0 as *mut Region,
+    free: 0,
+};
+
+// Returns the index of the highest set bit of `x`, which must not be 0.
+fn log2(x: usize) -> usize {
+    mem::size_of::<usize>() * 8 - 1 - x.leading_zeros() as usize
+}
+
+// Returns the lists that blocks of `size` bytes are kept in.
+fn mapping(size: usize) -> (usize, usize) {
+    if size < SMALL_BLOCK {
+        (0, size / (SMALL_BLOCK / SL_COUNT))
+    } else {
+        let fl = log2(size);
+        let sl = (size >> (fl - SL_LOG2)) ^ SL_COUNT;
+        (fl - FL_SHIFT + 1, sl)
+    }
+}
+
+// Returns the first list in which every block has room for `size` bytes.
+fn mapping_search(size: usize) -> (usize, usize) {
+    if size < SMALL_BLOCK {
+        mapping(size)
+    } else {
+        mapping(size + (1 << (log2(size) - SL_LOG2)) - 1)
+    }
+}
+
+impl TLSFAllocator {
+
+    pub fn init(&mut self, heap_start: usize, heap_end: usize) {
+        trace!("initializing allocator with heap 0x{:x}-0x{:x} ({} bytes)",
+               heap_start, heap_end, heap_end - heap_start);
+        assert!(mem::size_of::<usize>() * 2 == ALIGN);
+        assert!(self.regions.is_null());
+        self.add_region(heap_start, heap_end);
+        self.initial = self.regions;
+    }
+
+    // Links a free block into the list for its size.
+    unsafe fn insert_free(&mut self, block: *mut Block) {
+        let (fl, sl) = mapping((*block).size());
+        let head = self.lists[fl][sl];
+        (*block).next_free = head;
+        (*block).prev_free = ptr::null_mut();
+        if !head.is_null() {
+            (*head).prev_free = block;
+        }
+        self.lists[fl][sl] = block;
+        self.fl_bitmap |= 1 << fl;
+        self.sl_bitmap[fl] |= 1 << sl;
+    }
+
+    // Unlinks a free block from the list for its size.
+    unsafe fn remove_free(&mut self, block: *mut Block) {
+        let (fl, sl) = mapping((*block).size());
+        let (next, prev) = ((*block).next_free, (*block).prev_free);
+        if !next.is_null() {
+            (*next).prev_free = prev;
+        }
+        if !prev.is_null() {
+            (*prev).next_free = next;
+        } else {
+            self.lists[fl][sl] = next;
+            if next.is_null() {
+                self.sl_bitmap[fl] &= !(1 << sl);
+                if self.sl_bitmap[fl] == 0 {
+                    self.fl_bitmap &= !(1 << fl);
+                }
+            }
+        }
+    }
+
+    // Returns the first free block in the list `(fl, sl)` or any larger one.
+    fn find_suitable(&self, fl: usize, sl: usize) -> Option<*mut Block> {
+        if fl >= FL_COUNT {
+            return None;
+        }
+        let mut fl = fl;
+        let mut sl_map = self.sl_bitmap[fl] & (!0 << sl);
+        if sl_map == 0 {
+            let fl_map = self.fl_bitmap & (!0 << (fl + 1));
+            if fl_map == 0 {
+                return None;
+            }
+            fl = fl_map.trailing_zeros() as usize;
+            sl_map = self.sl_bitmap[fl];
+        }
+        Some(self.lists[fl][sl_map.trailing_zeros() as usize])
+    }
+
+    // Walks the list that blocks of `size` bytes are kept in for one that has room for them. The
+    // lists `find_suitable` looks at skip this one since it may not have such a block, but when
+    // the heap is nearly full it might.
+    fn find_in_list(&self, size: usize) -> Option<*mut Block> {
+        let (fl, sl) = mapping(size);
+        if fl >= FL_COUNT {
+            return None;
+        }
+        let mut block = self.lists[fl][sl];
+        // We know this is safe because only free blocks are on the lists.
+        unsafe {
+            while !block.is_null() && (*block).size() < size {
+                block = (*block).next_free;
+            }
+        }
+        if block.is_null() { None } else { Some(block) }
+    }
+
+    // Cuts `block` down to `size` bytes if there is room for another block after it, and returns
+    // the rest to the free lists.
+    unsafe fn split(&mut self, block: *mut Block, size: usize) {
+        let rest = (*block).size() - size;
+        if rest < 2 * ALIGN {
+            return;
+        }
+        let next = (*block).next_phys();
+        let tail = ((*block).payload() + size) as *mut Block;
+        (*tail).prev_phys = block;
+        (*tail).size = rest - ALIGN;
+        (*next).prev_phys = tail;
+        (*block).set_size(size);
+        // The new header comes out of free space.
+        self.free -= ALIGN;
+        let tail = self.merge_next(tail);
+        (*tail).set_free(true);
+        self.insert_free(tail);
+    }
+
+    // Splits off the start of the free `block` so that its payload is aligned to `align`. The
+    // start goes back to the free lists. `block` must have enough room for this.
+    unsafe fn align_block(&mut self, block: *mut Block, align: usize) -> *mut Block {
+        let payload = (*block).payload();
+        let mut aligned = align_up!(payload, align);
+        if aligned == payload {
+            return block;
+        }
+        // The gap has to hold a block of its own.
+        if aligned - payload < 2 * ALIGN {
+            aligned = align_up!(payload + 2 * ALIGN, align);
+        }
+        let gap = aligned - payload;
+        let next = (*block).next_phys();
+        let new = (aligned - ALIGN) as *mut Block;
+        (*new).prev_phys = block;
+        (*new).size = (*block).size() - gap;
+        (*next).prev_phys = new;
+        (*block).set_size(gap - ALIGN);
+        (*block).set_free(true);
+        self.insert_free(block);
+        self.free -= ALIGN;
+        new
+    }
+
+    // Merges `block` with the block after it if that one is free. Returns the merged block.
+    unsafe fn merge_next(&mut self, block: *mut Block) -> *mut Block {
+        let next = (*block).next_phys();
+        if (*next).is_free() {
+            self.remove_free(next);
+            let size = (*block).size() + ALIGN + (*next).size();
+            (*block).set_size(size);
+            (*(*block).next_phys()).prev_phys = block;
+            // The header of `next` is free space again.
+            self.free += ALIGN;
+        }
+        block
+    }
+
+    // Merges `block` with the block before it if that one is free. Returns the merged block.
+    unsafe fn merge_prev(&mut self, block: *mut Block) -> *mut Block {
+        let prev = (*block).prev_phys;
+        if prev.is_null() || !(*prev).is_free() {
+            return block;
+        }
+        self.remove_free(prev);
+        let size = (*prev).size() + ALIGN + (*block).size();
+        (*prev).set_size(size);
+        (*(*prev).next_phys()).prev_phys = prev;
+        self.free += ALIGN;
+        prev
+    }
+
+    // Returns the size of the block for a request of `size` bytes. Every block has room for the
+    // free list links.
+    fn adjust(size: usize) -> usize {
+        cmp::max(align_up!(size, ALIGN), ALIGN)
+    }
+
+}
+
+impl Allocator for TLSFAllocator {
+
+    fn allocate_raw(&mut self, size: usize, align: usize) -> KernResult<usize> {
+        trace!("trying to allocate {} bytes aligned to {:x}", size, align);
+        if size > MAX_SIZE || align > MAX_SIZE {
+            return Err(OutOfMemory);
+        }
+        let size = TLSFAllocator::adjust(size);
+        let align = cmp::max(align, ALIGN);
+        // A block that is not aligned already needs room to move its start.
+        let search = if align > ALIGN { size + align + 2 * ALIGN } else { size };
+        let (fl, sl) = mapping_search(search);
+        let block = try!(self.find_suitable(fl, sl).or_else(|| self.find_in_list(search))
+                             .ok_or(OutOfMemory));
+
+        // We know this is safe because the block came from the free lists.
+        unsafe {
+            self.remove_free(block);
+            (*block).set_free(false);
+            let block = self.align_block(block, align);
+            self.split(block, size);
+            self.free -= (*block).size();
+            trace!("allocated {} bytes at 0x{:x}", size, (*block).payload());
+            Ok((*block).payload())
+        }
+    }
+
+    fn reallocate_raw(&mut self, old_addr: usize, old_size: usize, new_size: usize, align: usize)
+                      -> KernResult<usize> {
+        trace!("trying to reallocate {} bytes at 0x{:x} to {} bytes", old_size, old_addr, new_size);
+        let size = TLSFAllocator::adjust(new_size);
+        let block = (old_addr - ALIGN) as *mut Block;
+
+        // Try to grow or shrink the block where it is first.
+        // We know this is safe because the caller gives us a block we handed out.
+        unsafe {
+            let next = (*block).next_phys();
+            let room = if (*next).is_free() {
+                (*block).size() + ALIGN + (*next).size()
+            } else {
+                (*block).size()
+            };
+            if old_addr % align == 0 && size <= MAX_SIZE && size <= room {
+                self.free += (*block).size();
+                self.merge_next(block);
+                self.split(block, size);
+                self.free -= (*block).size();
+                return Ok(old_addr);
+            }
+        }
+
+        let new_addr = try!(self.allocate_raw(new_size, align));
+        let len = cmp::min(old_size, new_size);
+        unsafe { ptr::copy_nonoverlapping(old_addr as *const u8, new_addr as *mut u8, len) };
+        self.deallocate_raw(old_addr, old_size);
+        Ok(new_addr)
+    }
+
+    fn deallocate_raw(&mut self, addr: usize, size: usize) {
+        trace!("freeing {} bytes at 0x{:x}", size, addr);
+        let block = (addr - ALIGN) as *mut Block;
+        // We know this is safe because the caller gives us a block we handed out.
+        unsafe {
+            assert!(!(*block).is_free(), "double free of 0x{:x}", addr);
+            self.free += (*block).size();
+            let block = self.merge_prev(block);
+            let block = self.merge_next(block);
+            (*block).set_free(true);
+            self.insert_free(block);
+        }
+    }
+
+    fn get_free_space(&self) -> usize {
+        self.free
+    }
+
+    fn largest_free_block(&self) -> usize {
+        if self.fl_bitmap == 0 {
+            return 0;
+        }
+        // Only the highest list can hold the largest block, but its blocks vary in size.
+        let fl = log2(self.fl_bitmap as usize);
+        let sl = log2(self.sl_bitmap[fl] as usize);
+        let mut block = self.lists[fl][sl];
+        let mut largest = 0;
+        // We know this is safe because only free blocks are on the lists.
+        unsafe {
+            while !block.is_null() {
+                largest = cmp::max(largest, (*block).size());
+                block = (*block).next_free;
+            }
+        }
+        largest
+    }
+
+    fn add_region(&mut self, start: usize, end: usize) {
+        trace!("adding region 0x{:x}-0x{:x} to the heap", start, end);
+        let first = align_up!(start + mem::size_of::<Region>(), ALIGN);
+        let last = align!(end, ALIGN) - ALIGN;
+        assert!(first + 2 * ALIGN <= last);
+
+        // We know this is safe because the caller hands the whole range over to us.
+        unsafe {
+            let region = start as *mut Region;
+            let first = first as *mut Block;
+            let last = last as *mut Block;
+            ptr::write(region, Region { next: self.regions, end: end, first: first });
+            (*first).prev_phys = ptr::null_mut();
+            (*first).size = last as usize - first as usize - ALIGN;
+            (*last).prev_phys = first;
+            (*last).size = 0;
+            (*first).set_free(true);
+            self.insert_free(first);
+            self.free += (*first).size();
+            self.regions = region;
+        }
+    }
+
+    fn remove_free_region(&mut self) -> Option<(usize, usize)> {
+        let mut link: *mut *mut Region = &mut self.regions;
+        // We know this is safe because only regions that are part of the heap are linked.
+        unsafe {
+            while !(*link).is_null() {
+                let region = *link;
+                let first = (*region).first;
+                // A region is unused once its first block is free and reaches the end block.
+                if region != self.initial && (*first).is_free() &&
+                   (*(*first).next_phys()).size() == 0 {
+                    self.remove_free(first);
+                    self.free -= (*first).size();
+                    *link = (*region).next;
+                    trace!("removed region 0x{:x}-0x{:x} from the heap",
+                           region as usize, (*region).end);
+                    return Some((region as usize, (*region).end));
+                }
+                link = &mut (**link).next;
+            }
+        }
+        None
+    }
+
+}
@@ -0,0 +1,264 @@
+//!
+//! Allocation tracking.
+//!
+//! While tracking is enabled every live allocation is recorded with its size, alignment and call
+//! site. The call site is the return address of the allocation function, which is an address in
+//! the code that asked for the memory. `Box::new` and friends are always inlined so boxes are
+//! attributed to the code that creates them rather than to the box itself. Sites can be resolved to
+//! a file and line with `addr2line -e bin/kernel`.
+//!
+//! Records live in a fixed table rather than on the heap so recording an allocation never
+//! allocates. Allocations that don't fit in the table are counted but not recorded, and freeing
+//! something that was never recorded (for example because it was allocated before tracking was
+//! enabled) is ignored.
+//!
+use core::prelude::*;
+use core::fmt;
+use core::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use mutex::Mutex;
+logger_init!(Info);
+
+#[allow(improper_ctypes)]
+extern {
+    /// Returns the return address of the current function, or of its callers for higher levels.
+    #[link_name = "llvm.returnaddress"]
+    pub fn return_address(level: i32) -> *const u8;
+}
+
+/// The most allocations that can be tracked at once.
+pub const MAX_RECORDS: usize = 4096;
+
+/// The most call sites a dump can tell apart. Allocations from any other site are left out.
+pub const MAX_SITES: usize = 256;
+
+// A live allocation. Empty slots have an address of 0.
+#[derive(Clone, Copy)]
+struct Record {
+    addr: usize,
+    size: usize,
+    align: usize,
+    site: usize,
+}
+
+const EMPTY_RECORD: Record = Record { addr: 0, size: 0, align: 0, site: 0 };
+
+// An open addressing hash table of live allocations keyed by address. Collisions are resolved by
+// linear probing, and removals shift the rest of the probe sequence back instead of leaving
+// tombstones, so lookups stay short no matter how many allocations come and go.
+struct Table {
+    records: [Record; MAX_RECORDS],
+    live: usize,
+    dropped: usize,
+}
+
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+static TABLE: Mutex<Table> = Mutex::new(Table {
+    records: [EMPTY_RECORD; MAX_RECORDS],
+    live: 0,
+    dropped: 0,
+});
+
+impl Table {
+
+    // Returns the slot to start probing at for `addr`. The low bits are dropped since they are
+    // mostly zero due to alignment.
+    fn hash(addr: usize) -> usize {
+        (addr >> 3).wrapping_mul(2654435761) % MAX_RECORDS
+    }
+
+    fn insert(&mut self, record: Record) {
+        let start = Table::hash(record.addr);
+        for i in 0 .. MAX_RECORDS {
+            let slot = &mut self.records[(start + i) % MAX_RECORDS];
+            if slot.addr == 0 {
+                *slot = record;
+                self.live += 1;
+                return;
+            }
+        }
+        self.dropped += 1;
+    }
+
+    // Returns the slot holding the record of `addr`.
+    fn find(&self, addr: usize) -> Option<usize> {
+        let start = Table::hash(addr);
+        for i in 0 .. MAX_RECORDS {
+            let slot = (start + i) % MAX_RECORDS;
+            if self.records[slot].addr == addr {
+                return Some(slot);
+            } else if self.records[slot].addr == 0 {
+                return None;
+            }
+        }
+        None
+    }
+
+    fn remove(&mut self, addr: usize) {
+        let mut hole = match self.find(addr) {
+            Some(slot) => slot,
+            None => return,
+        };
+        // Move records that probed past the hole back into it until the probe sequence ends.
+        let mut slot = hole;
+        for _ in 1 .. MAX_RECORDS {
+            slot = (slot + 1) % MAX_RECORDS;
+            let next = self.records[slot];
+            if next.addr == 0 {
+                break;
+            }
+            // The record can fill the hole if the hole is between its home slot and its slot.
+            let from_home = (slot + MAX_RECORDS - Table::hash(next.addr)) % MAX_RECORDS;
+            if from_home >= (slot + MAX_RECORDS - hole) % MAX_RECORDS {
+                self.records[hole] = next;
+                hole = slot;
+            }
+        }
+        self.records[hole] = EMPTY_RECORD;
+        self.live -= 1;
+    }
+
+    fn clear(&mut self) {
+        for slot in self.records.iter_mut() {
+            *slot = EMPTY_RECORD;
+        }
+        self.live = 0;
+        self.dropped = 0;
+    }
+
+}
+
+/// The outstanding allocations made from a single call site.
+#[derive(Clone, Copy)]
+pub struct SiteStats {
+    /// The address of the code that made the allocations.
+    pub site: usize,
+    /// The number of outstanding allocations.
+    pub count: usize,
+    /// The total size of the outstanding allocations in bytes.
+    pub bytes: usize,
+    /// The largest alignment any of the allocations asked for.
+    pub align: usize,
+}
+
+impl fmt::Debug for SiteStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:08x} {:>6} allocs {:>8} bytes align {}",
+               self.site, self.count, self.bytes, self.align)
+    }
+}
+
+const EMPTY_SITE: SiteStats = SiteStats { site: 0, count: 0, bytes: 0, align: 0 };
+
+// The sites of the last dump, sorted by the number of bytes they hold. This is kept apart from the
+// table so the table is not locked while a dump is written, since writing may allocate.
+struct Sites {
+    sites: [SiteStats; MAX_SITES],
+    len: usize,
+}
+
+static SITES: Mutex<Sites> = Mutex::new(Sites { sites: [EMPTY_SITE; MAX_SITES], len: 0 });
+
+impl Sites {
+
+    fn add(&mut self, record: &Record) {
+        let len = self.len;
+        let index = match self.sites[..len].iter().position(|s| s.site == record.site) {
+            Some(i) => i,
+            None if len < MAX_SITES => {
+                self.sites[len] = SiteStats { site: record.site, ..EMPTY_SITE };
+                self.len += 1;
+                len
+            }
+            None => return,
+        };
+        let stats = &mut self.sites[index];
+        stats.count += 1;
+        stats.bytes += record.size;
+        if record.align > stats.align {
+            stats.align = record.align;
+        }
+    }
+
+    // Sorts the sites so the ones holding the most memory come first.
+    fn sort(&mut self) {
+        for i in 1 .. self.len {
+            let mut j = i;
+            while j > 0 && self.sites[j - 1].bytes < self.sites[j].bytes {
+                self.sites.swap(j - 1, j);
+                j -= 1;
+            }
+        }
+    }
+
+}
+
+/// Starts recording allocations. Anything recorded by an earlier run is forgotten.
+pub fn enable() {
+    TABLE.lock().clear();
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Stops recording allocations. The records made so far are kept until tracking is enabled again.
+pub fn disable() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+/// Returns whether allocations are being recorded.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Records an allocation of `size` bytes aligned to `align` at `addr` made from `site`.
+pub fn add(addr: usize, size: usize, align: usize, site: usize) {
+    if is_enabled() && size != 0 {
+        TABLE.lock().insert(Record { addr: addr, size: size, align: align, site: site });
+    }
+}
+
+/// Forgets the allocation at `addr`.
+pub fn remove(addr: usize) {
+    if is_enabled() {
+        TABLE.lock().remove(addr);
+    }
+}
+
+/// Returns the number of allocations that are recorded and the number that did not fit.
+pub fn counts() -> (usize, usize) {
+    let table = TABLE.lock();
+    (table.live, table.dropped)
+}
+
+/// Calls `op` with the outstanding allocations of every site, the site holding the most memory
+/// first.
+pub fn for_each_site<F>(mut op: F) where F: FnMut(&SiteStats) {
+    let mut sites = SITES.lock();
+    sites.len = 0;
+    {
+        let table = TABLE.lock();
+        for record in table.records.iter().filter(|r| r.addr != 0) {
+            sites.add(record);
+        }
+    }
+    sites.sort();
+    for stats in sites.sites[..sites.len].iter() {
+        op(stats);
+    }
+}
+
+/// Writes the outstanding allocations grouped by site to `w`, one site per line.
+pub fn dump_sites<W: fmt::Write>(w: &mut W) -> fmt::Result {
+    let (live, dropped) = counts();
+    let mut res = write!(w, "{} allocations tracked, {} untracked\n", live, dropped);
+    for_each_site(|stats| if res.is_ok() {
+        res = write!(w, "{:?}\n", stats);
+    });
+    res
+}
+
+/// Logs the outstanding allocations grouped by site.
+pub fn log_sites() {
+    let (live, dropped) = counts();
+    info!("{} allocations tracked, {} untracked", live, dropped);
+    for_each_site(|stats| info!("{:?}", stats));
+}
@@ -1,6 +1,6 @@
 #![crate_name="boot"]
 #![crate_type="rlib"]
-#![feature(no_std,core,core_prelude,core_str_ext)]
+#![feature(no_std,core,core_prelude,core_str_ext,step_by,const_fn)]
 #![no_std]
 //!
 //! This module is the entry point of the kernel. It is responsible for initializing all other
@@ -21,7 +21,10 @@ extern crate fs;
 
 mod test;
 
+use collections::string::String;
+use fs::Path;
 use util::multiboot::MultibootHeader;
+use alloc::HeapBackend;
 use interrupt::{timer, BREAKPOINT_IRQ, Regs, IRet};
 use task::thread::Thread;
 logger_init!(Trace);
@@ -42,8 +45,15 @@ pub extern fn kernel_main (hdr: &MultibootHeader) -> ! {
     interrupt::set_isr(BREAKPOINT_IRQ, nop);
     timer::set_frequency(19);
 
-    // Initialize the allocator.
-    alloc::init();
+    // Initialize the allocator with the heap backend given by `heap=<name>` on the command line.
+    let backend = match hdr.cmdline_option("heap") {
+        Some(name) => HeapBackend::from_name(name).unwrap_or_else(|| {
+            println!(io::console::CON, "Unknown heap backend {}, using lmm", name);
+            HeapBackend::LMM
+        }),
+        None => HeapBackend::LMM,
+    };
+    alloc::init(backend);
 
     // Initialize physical memory and virtual memory and enable paging.
     mem::init(hdr);
@@ -54,7 +64,11 @@ pub extern fn kernel_main (hdr: &MultibootHeader) -> ! {
     // Initialize all devices.
     devices::init();
 
-    // Initialize the scheduler.
+    // Expose debugging information about memory under /sys/mem.
+    init_sysfs();
+
+    // Initialize threads and the scheduler.
+    task::init();
     sched::init();
 
     // Perform some self tests.
@@ -66,6 +80,21 @@ pub extern fn kernel_main (hdr: &MultibootHeader) -> ! {
     sched::begin();
 }
 
+fn init_sysfs() {
+    let mut cursor = fs::root_cursor();
+    cursor.cd(Path::from_str("sys")).unwrap();
+    cursor.make_node(String::from_str("mem")).unwrap();
+    cursor.cd(Path::from_str("mem")).unwrap();
+    cursor.make_generated_file(String::from_str("kmap"), mem::dump_kernel_mappings::<String>)
+          .unwrap();
+    cursor.make_generated_file(String::from_str("slabs"), alloc::slab::dump_caches::<String>)
+          .unwrap();
+    cursor.make_generated_file(String::from_str("allocs"), alloc::track::dump_sites::<String>)
+          .unwrap();
+    cursor.make_generated_file(String::from_str("heap"), alloc::dump_heap_stats::<String>)
+          .unwrap();
+}
+
 fn threadfn() -> ! {
     let tid = sched::get_tid();
     loop { trace!("hello from thread {}", tid) }
@@ -0,0 +1,40 @@
+use core::prelude::*;
+use alloc::boxed::Box;
+use alloc::track;
+logger_init!(Trace);
+
+#[inline(never)]
+fn make_box() -> Box<[u8; 100]> {
+    Box::new([0; 100]).unwrap()
+}
+
+#[inline(never)]
+pub fn test() {
+    trace!("\ntesting allocation tracking");
+
+    let was_enabled = track::is_enabled();
+    if !was_enabled {
+        track::enable();
+    }
+
+    // A box is recorded with the code that created it as its site.
+    let (live, _) = track::counts();
+    let b = make_box();
+    assert!(track::counts().0 == live + 1);
+    let start = make_box as usize;
+    let mut found = None;
+    track::for_each_site(|stats| if start < stats.site && stats.site < start + 0x100 {
+        found = Some(*stats);
+    });
+    let stats = found.unwrap();
+    assert!(stats.count == 1 && stats.bytes == 100 && stats.align == 1);
+
+    // Freeing it removes the record.
+    drop(b);
+    assert!(track::counts().0 == live);
+    track::for_each_site(|stats| assert!(stats.site != found.unwrap().site));
+
+    if !was_enabled {
+        track::disable();
+    }
+}
@@ -0,0 +1,40 @@
+use core::prelude::*;
+use alloc;
+use alloc::cow::Cow;
+use collections::string::String;
+logger_init!(Trace);
+
+#[inline(never)]
+pub fn test() {
+    trace!("\ntesting copy on write pointers");
+
+    let free_start = alloc::get_free_space();
+    {
+        let mut s = String::new();
+        assert!(s.append("shared").is_ok());
+
+        // Clones share the value.
+        let a = Cow::new(s).unwrap();
+        let mut b = a.clone();
+        assert!(a.is_shared() && b.is_shared());
+        assert!(a.as_str() as *const str == b.as_str() as *const str);
+
+        // The first mutation copies it.
+        assert!(b.make_mut().unwrap().append(" and copied").is_ok());
+        assert!(!a.is_shared() && !b.is_shared());
+        assert!(a.as_str() == "shared");
+        assert!(b.as_str() == "shared and copied");
+
+        // Mutating a value nobody else sees does not copy it.
+        let before = b.as_str().as_ptr();
+        b.make_mut().unwrap().pop();
+        assert!(b.as_str().as_ptr() == before);
+
+        // A unique value is moved out and a shared one copied.
+        let c = a.clone();
+        let inner = c.into_inner().unwrap();
+        assert!(inner.as_str() == "shared" && !a.is_shared());
+        assert!(a.into_inner().unwrap().as_str() == "shared");
+    }
+    assert!(alloc::get_free_space() == free_start);
+}
@@ -0,0 +1,48 @@
+use core::prelude::*;
+use mem::phys;
+use mem::phys::{FRAME_FREE, FRAME_KERNEL, Zone, DMA_ZONE_END};
+use util::{virt_to_phys, PAGE_SIZE};
+logger_init!(Trace);
+
+#[inline(never)]
+pub fn test() {
+    trace!("\ntesting frames");
+
+    let free_start = phys::free_frame_count();
+
+    // Kernel frames are described but never free.
+    let kernel_start = virt_to_phys(linker_sym!(__kernel_start));
+    let desc = phys::lookup(kernel_start).unwrap();
+    assert!(desc.flags().contains(FRAME_KERNEL));
+    assert!(!desc.flags().contains(FRAME_FREE));
+
+    // Blocks are aligned to their size and leave the free list.
+    let block = phys::alloc_frames(2).unwrap();
+    let addr = &*block as *const phys::Frame as usize;
+    assert!(is_aligned!(addr, phys::order_size(2)));
+    assert!(phys::free_frame_count() == free_start - 4);
+    for page in (addr .. addr + phys::order_size(2)).step_by(PAGE_SIZE) {
+        assert!(!phys::lookup(page).unwrap().flags().contains(FRAME_FREE));
+    }
+
+    // Reference counts track get/put pairs.
+    assert!(phys::ref_count(addr) == 0);
+    assert!(phys::get_ref(addr) == 1);
+    assert!(phys::get_ref(addr) == 2);
+    assert!(phys::put_ref(addr) == 1);
+    assert!(phys::put_ref(addr) == 0);
+
+    phys::free_frames(block, 2);
+    assert!(phys::lookup(addr).unwrap().flags().contains(FRAME_FREE));
+    assert!(phys::free_frame_count() == free_start);
+
+    // DMA frames come from below 16MB and go back to the DMA zone.
+    let dma_start = phys::free_frame_count_in(Zone::Dma);
+    let frame = phys::get_frame_in(Zone::Dma).unwrap();
+    let addr = &*frame as *const phys::Frame as usize;
+    assert!(addr < DMA_ZONE_END);
+    assert!(Zone::from_addr(addr) == Zone::Dma);
+    assert!(phys::free_frame_count_in(Zone::Dma) == dma_start - 1);
+    phys::return_frame(frame);
+    assert!(phys::free_frame_count_in(Zone::Dma) == dma_start);
+}
@@ -1,9 +1,29 @@
+use core::prelude::*;
 use alloc::boxed::Box;
 use collections::link::{SingleLink, HasSingleLink};
-use collections::hashmap::{HashMap, HasKey};
+use collections::hashmap::{Entry, HashMap, HasKey};
 use collections::string::String;
+use core::hash::Hasher;
 logger_init!(Trace);
 
+/// Hashes everything to the same bucket.
+#[derive(Clone)]
+struct BadHasher;
+
+impl Hasher for BadHasher {
+    fn finish(&self) -> u64 {
+        0
+    }
+    fn write(&mut self, _bytes: &[u8]) {
+    }
+}
+
+fn name(i: usize) -> String {
+    let mut name = String::new();
+    print!(name, "n{}", i);
+    name
+}
+
 #[derive(Debug)]
 struct Node {
     key: String,
@@ -100,8 +120,78 @@ pub fn test() {
         let res = map.remove(&name).unwrap();
         trace!("removed {:?}", res);
     }
+    assert_eq!(map.count(), 0);
+
+    trace!("growing");
+    let mut map = HashMap::new().unwrap();
+    assert_eq!(map.capacity(), 16);
+    for i in 0 .. 100 {
+        map.insert(Box::new(Node::new(name(i), i)).unwrap());
+    }
+    assert_eq!(map.count(), 100);
+    assert!(map.capacity() * 75 >= 100 * 100);
+    for i in 0 .. 100 {
+        assert_eq!(map.lookup(&name(i)).unwrap().val, i);
+    }
+    let mut sum = 0;
+    for (key, val) in map.iter() {
+        assert_eq!(*key, val.key);
+        sum += val.val;
+    }
+    assert_eq!(sum, 99 * 100 / 2);
 
+    trace!("retain");
+    map.retain(|_, val| val.val % 2 == 0);
+    assert_eq!(map.count(), 50);
+    for i in 0 .. 100 {
+        assert_eq!(map.contains(&name(i)), i % 2 == 0);
+    }
 
-    
+    trace!("entries");
+    {
+        let key = name(1);
+        let val = map.entry(&key).or_insert_with(|| Box::new(Node::new(name(1), 1))).unwrap();
+        assert_eq!(val.val, 1);
+        val.val = 1001;
+    }
+    assert_eq!(map.lookup(&name(1)).unwrap().val, 1001);
+    {
+        let key = name(2);
+        let val = map.entry(&key).or_insert_with(|| panic!("n2 is in the map")).unwrap();
+        assert_eq!(val.val, 2);
+    }
+    {
+        let key = name(4);
+        match map.entry(&key) {
+            Entry::Occupied(entry) => assert_eq!(entry.remove().val, 4),
+            Entry::Vacant(_) => panic!("n4 is in the map"),
+        }
+    }
+    assert!(!map.contains(&name(4)));
+    assert_eq!(map.count(), 50);
+
+    trace!("reserve");
+    let mut map = HashMap::new().unwrap();
+    map.reserve(1000).unwrap();
+    let capacity = map.capacity();
+    assert!(capacity * 75 >= 1000 * 100);
+    for i in 0 .. 1000 {
+        map.insert(Box::new(Node::new(name(i), i)).unwrap());
+    }
+    assert_eq!(map.capacity(), capacity);
+    assert!(map.reserve(usize::max_value()).is_err());
+    assert!(map.reserve(usize::max_value() / 100).is_err());
+    assert_eq!(map.capacity(), capacity);
+    drop(map);
 
+    trace!("custom hasher");
+    let mut map = HashMap::with_hasher(BadHasher).unwrap();
+    for i in 0 .. 20 {
+        map.insert(Box::new(Node::new(name(i), i)).unwrap());
+    }
+    for i in 0 .. 20 {
+        assert_eq!(map.lookup(&name(i)).unwrap().val, i);
+    }
+    assert_eq!(map.remove(&name(7)).unwrap().val, 7);
+    assert_eq!(map.count(), 19);
 }
@@ -0,0 +1,33 @@
+use core::prelude::*;
+use alloc;
+use alloc::slab;
+use mem::phys;
+use mem::virt::{VMALLOC_START, VMALLOC_END};
+use util::PAGE_SIZE;
+logger_init!(Trace);
+
+#[inline(never)]
+pub fn test() {
+    trace!("\ntesting heap growth");
+
+    let free_start = alloc::get_free_space();
+    let frames_start = phys::free_frame_count();
+
+    // An allocation larger than what is left grows the heap with frames from the frame allocator.
+    let size = free_start + PAGE_SIZE;
+    let big = alloc::allocate_raw(size, 8).unwrap();
+    assert!(big >= VMALLOC_START && big + size <= VMALLOC_END);
+    assert!(phys::free_frame_count() + size / PAGE_SIZE <= frames_start);
+    assert!(alloc::get_free_space() < free_start + alloc::HEAP_GROW_SIZE);
+    assert!(!slab::owns(big));
+    unsafe {
+        *(big as *mut u32) = 0xdeadbeef;
+        *((big + size - 4) as *mut u32) = 0xcafebabe;
+        assert!(*(big as *const u32) == 0xdeadbeef);
+    }
+
+    // Once nothing in the region is allocated it goes back.
+    alloc::deallocate_raw(big, size);
+    assert!(alloc::get_free_space() == free_start);
+    assert!(phys::free_frame_count() == frames_start);
+}
@@ -0,0 +1,30 @@
+use core::prelude::*;
+use alloc;
+use alloc::HeapBackend;
+logger_init!(Trace);
+
+#[inline(never)]
+pub fn test() {
+    trace!("\ntesting heap statistics");
+
+    let stats = alloc::heap_stats();
+    trace!("{:?}", stats);
+    assert!(HeapBackend::from_name(stats.backend.name()) == Some(stats.backend));
+    assert!(HeapBackend::from_name("slob").is_none());
+    assert!(stats.free == alloc::get_free_space());
+    assert!(stats.largest_free_block <= stats.free);
+    assert!(stats.fragmentation() <= 100);
+
+    // An allocation that fits in the largest free block comes out of the free space we have.
+    let size = stats.largest_free_block / 2;
+    let addr = alloc::allocate_raw(size, 8).unwrap();
+    let during = alloc::heap_stats();
+    assert!(during.free <= stats.free - size);
+    assert!(during.largest_free_block <= stats.largest_free_block);
+
+    // Freeing it puts things back.
+    alloc::deallocate_raw(addr, size);
+    let after = alloc::heap_stats();
+    assert!(after.free == stats.free);
+    assert!(after.largest_free_block == stats.largest_free_block);
+}
@@ -0,0 +1,41 @@
+use core::prelude::*;
+use mem;
+use mem::{ioremap, iounmap, CacheMode};
+use mem::phys;
+use mem::virt::{PTE_CACHEDISABLE, PTE_WRITETHROUGH};
+use util::{phys_to_virt, PAGE_SIZE};
+logger_init!(Trace);
+
+// Video memory is the only device memory we are sure exists.
+const VMEM: usize = 0xB8000;
+
+#[inline(never)]
+pub fn test() {
+    trace!("\ntesting ioremap");
+
+    let free_start = phys::free_frame_count();
+
+    // Unaligned ranges are mapped with the requested cache mode and alias the direct map.
+    let io = ioremap(VMEM + 2, PAGE_SIZE, CacheMode::Uncached).unwrap();
+    assert!(io.addr() % PAGE_SIZE == 2);
+    let pte = mem::kernel_space().lock().with_pd(|pd| pd.get_pte(io.addr()).unwrap());
+    assert!(pte.contains(PTE_CACHEDISABLE | PTE_WRITETHROUGH));
+    let cell = io.read::<u16>(0);
+    io.write::<u16>(0, cell);
+    assert!(io.read::<u16>(0) == unsafe { *(phys_to_virt(VMEM + 2) as *const u16) });
+
+    // Ranges crossing a page boundary map both pages.
+    let last = io.len() - 2;
+    assert!(io.read::<u16>(last) == unsafe { *(phys_to_virt(VMEM + 2 + last) as *const u16) });
+    let addr = io.addr();
+    iounmap(io);
+    assert!(!mem::kernel_space().lock().has_page(addr));
+
+    let io = ioremap(VMEM, 4, CacheMode::WriteThrough).unwrap();
+    let pte = mem::kernel_space().lock().with_pd(|pd| pd.get_pte(io.addr()).unwrap());
+    assert!(pte.contains(PTE_WRITETHROUGH) && !pte.contains(PTE_CACHEDISABLE));
+    drop(io);
+
+    // Device memory never comes from or goes back to the frame allocator.
+    assert!(phys::free_frame_count() == free_start);
+}
@@ -1,4 +1,5 @@
 use alloc;
+use alloc::track;
 
 mod boxes;
 mod rc;
@@ -8,12 +9,28 @@ mod string;
 mod vfs;
 mod hashmap;
 mod slist;
+mod tree;
+mod frames;
+mod paging;
+mod vma;
+mod vmalloc;
+mod thread;
+mod ioremap;
+mod slab;
+mod heap;
+mod allocs;
+mod redzone;
+mod cow;
+mod pool;
+mod heapstats;
 
 logger_init!(Trace);
 
 pub fn test_all() {
     trace!("\n\n ==== BEGINNING TESTS ====\n");
 
+    // Record every allocation so leaks can be traced back to where they were made.
+    track::enable();
     let free_start = alloc::get_free_space();
     boxes::test();
     rc::test();
@@ -23,12 +40,34 @@ pub fn test_all() {
     hashmap::test();
     vfs::test();
     slist::test();
+    tree::test();
+    frames::test();
+    paging::test();
+    paging::test_cow();
+    paging::test_large();
+    paging::test_translate();
+    vma::test();
+    vmalloc::test();
+    thread::test();
+    ioremap::test();
+    slab::test();
+    heap::test();
+    allocs::test();
+    redzone::test();
+    cow::test();
+    pool::test();
+    heapstats::test();
     let free_end = alloc::get_free_space();
+    alloc::check_heap();
 
     // VFS may "leak" bytes so perform it after we check for leaks.
 
     trace!("\n==== ENDING TESTS ====");
     trace!("leaked {} bytes\n", free_start - free_end);
+    if free_start != free_end {
+        track::log_sites();
+    }
+    track::disable();
 }
 
 pub fn vfs_shell() -> ! {
@@ -0,0 +1,225 @@
+use core::prelude::*;
+use mem;
+use mem::phys;
+use mem::vma::{Vma, VmaFlags, Backing, PROT_READ, PROT_WRITE};
+use mem::{ioremap, CacheMode};
+use mem::virt::{kmap, Mapping, PTE_WRITABLE, PTE_COW, PDE_WRITABLE};
+use mem::virt::{PTE_CACHEDISABLE, PTE_GLOBAL, PTE_SUPERVISOR, PTE_NOEXEC};
+use mem::virt::{is_pae, pde_mapped_size, large_page_order};
+use util::{PAGE_SIZE, KERNEL_BASE, page_align, virt_to_phys};
+use fs;
+use fs::Path;
+logger_init!(Trace);
+
+// An address outside of the kernel's part of the address space whose page table we own.
+const TEST_ADDR: usize = 0x40000000;
+
+// An address in the middle of the kernel that is mapped by a large page in both paging modes.
+const LARGE_ADDR: usize = KERNEL_BASE + 0x800000;
+
+#[inline(never)]
+pub fn test() {
+    trace!("\ntesting paging");
+
+    let free_start = phys::free_frame_count();
+    {
+        let mut space = mem::kernel_space().lock();
+
+        // Map a page through the recursive window and write to it.
+        let frame = phys::get_frame().unwrap();
+        let frame_addr = &*frame as *const phys::Frame as usize;
+        space.map_page(TEST_ADDR, frame, PTE_WRITABLE).unwrap();
+        assert!(space.has_page(TEST_ADDR));
+        assert!(phys::ref_count(frame_addr) == 1);
+        unsafe { *(TEST_ADDR as *mut usize) = 0xdeadbeef };
+        assert!(unsafe { *(TEST_ADDR as *const usize) } == 0xdeadbeef);
+
+        // Unmapping the last page frees its page table.
+        let frame = space.unmap_page(TEST_ADDR);
+        assert!(&*frame as *const phys::Frame as usize == frame_addr);
+        assert!(phys::ref_count(frame_addr) == 0);
+        assert!(!space.has_page(TEST_ADDR));
+        phys::return_frame(frame);
+        assert!(phys::free_frame_count() == free_start);
+
+        // Unmap a range spanning two page tables.
+        let start = TEST_ADDR + pde_mapped_size() - 2 * PAGE_SIZE;
+        let end = TEST_ADDR + pde_mapped_size() + 2 * PAGE_SIZE;
+        for addr in (start .. end).step_by(PAGE_SIZE) {
+            space.map_page(addr, phys::get_frame().unwrap(), PTE_WRITABLE).unwrap();
+        }
+        let mut count = 0;
+        space.unmap_range(start, end, |_, frame| {
+            phys::return_frame(frame);
+            count += 1;
+        });
+        assert!(count == 4);
+        assert!(phys::free_frame_count() == free_start);
+
+        // Set up an anonymous area. The lock must be released before touching it.
+        let vma = Vma::new(TEST_ADDR, TEST_ADDR + 2 * PAGE_SIZE, PROT_READ | PROT_WRITE,
+                           Backing::Anonymous, VmaFlags::empty());
+        space.vmas_mut().insert(vma).unwrap();
+        assert!(!space.has_page(TEST_ADDR));
+    }
+
+    // Touching the area faults in zeroed pages.
+    let page = TEST_ADDR + PAGE_SIZE;
+    assert!(unsafe { *(page as *const usize) } == 0);
+    unsafe { *(page as *mut usize) = 0xdeadbeef };
+    assert!(unsafe { *(page as *const usize) } == 0xdeadbeef);
+
+    let mut space = mem::kernel_space().lock();
+    assert!(space.has_page(page));
+    assert!(!space.has_page(TEST_ADDR));
+    space.unmap_range(TEST_ADDR, TEST_ADDR + 2 * PAGE_SIZE, |_, frame| phys::return_frame(frame));
+    space.vmas_mut().remove(TEST_ADDR, TEST_ADDR + 2 * PAGE_SIZE, |_| {}).unwrap();
+    assert!(phys::free_frame_count() == free_start);
+}
+
+#[inline(never)]
+pub fn test_cow() {
+    trace!("\ntesting copy on write");
+
+    let free_start = phys::free_frame_count();
+    let child = {
+        let mut space = mem::kernel_space().lock();
+        space.map_page(TEST_ADDR, phys::get_frame().unwrap(), PTE_WRITABLE).unwrap();
+        unsafe { *(TEST_ADDR as *mut usize) = 1 };
+
+        // Cloning shares the frame read-only.
+        let child = space.clone_cow().unwrap();
+        let pte = space.with_pd(|pd| pd.get_pte(TEST_ADDR)).unwrap();
+        assert!(pte.contains(PTE_COW));
+        assert!(!pte.contains(PTE_WRITABLE));
+        assert!(phys::ref_count(pte.frame_addr()) == 2);
+        child
+    };
+
+    // The first write gives us a private copy.
+    unsafe { *(TEST_ADDR as *mut usize) = 2 };
+    assert!(unsafe { *(TEST_ADDR as *const usize) } == 2);
+
+    {
+        let mut space = mem::kernel_space().lock();
+        let pte = space.with_pd(|pd| pd.get_pte(TEST_ADDR)).unwrap();
+        let child_pte = child.with_pd(|pd| pd.get_pte(TEST_ADDR)).unwrap();
+        assert!(pte.contains(PTE_WRITABLE));
+        assert!(!pte.contains(PTE_COW));
+        assert!(pte.frame_addr() != child_pte.frame_addr());
+        assert!(phys::ref_count(pte.frame_addr()) == 1);
+        assert!(phys::ref_count(child_pte.frame_addr()) == 1);
+
+        // The child still sees the original contents.
+        let window = kmap(child_pte.frame_addr());
+        assert!(unsafe { *(window.addr() as *const usize) } == 1);
+
+        phys::return_frame(space.unmap_page(TEST_ADDR));
+    }
+
+    // Dropping the child frees its frame, page table and page directory.
+    {
+        let _child = child;
+    }
+    assert!(phys::free_frame_count() == free_start);
+}
+
+#[inline(never)]
+pub fn test_large() {
+    trace!("\ntesting large pages");
+
+    let free_start = phys::free_frame_count();
+    let mut space = mem::kernel_space().lock();
+
+    // The middle of the kernel is mapped with large pages but low memory is not.
+    assert!(space.with_pd(|pd| pd.is_large(LARGE_ADDR)));
+    assert!(!space.with_pd(|pd| pd.is_large(KERNEL_BASE)));
+
+    // Map a large page and touch both ends of it.
+    let region = phys::alloc_frames(large_page_order()).unwrap();
+    space.with_pd(|pd| pd.map_large(TEST_ADDR, region, PDE_WRITABLE));
+    assert!(space.with_pd(|pd| pd.is_large(TEST_ADDR + PAGE_SIZE)));
+    assert!(!space.has_page(TEST_ADDR));
+    let last = TEST_ADDR + pde_mapped_size() - 4;
+    unsafe {
+        *(TEST_ADDR as *mut u32) = 0xdeadbeef;
+        *(last as *mut u32) = 0xcafebabe;
+        assert!(*(TEST_ADDR as *const u32) == 0xdeadbeef);
+        assert!(*(last as *const u32) == 0xcafebabe);
+    }
+
+    let (region, refs) = space.with_pd(|pd| pd.unmap_large(TEST_ADDR));
+    assert!(refs == 0);
+    assert!(!space.with_pd(|pd| pd.is_large(TEST_ADDR)));
+    phys::free_frames(region, large_page_order());
+    assert!(phys::free_frame_count() == free_start);
+}
+
+#[inline(never)]
+pub fn test_translate() {
+    trace!("\ntesting translation");
+
+    // The kernel translates to its physical address whether it is mapped by a page table or a large
+    // page.
+    let kernel_start = linker_sym!(__kernel_start);
+    let large = LARGE_ADDR + 0x123;
+    {
+        let space = mem::kernel_space().lock();
+        space.with_pd(|pd| {
+            let t = pd.translate(kernel_start).unwrap();
+            assert!(t.phys == virt_to_phys(kernel_start) && !t.is_large());
+            let t = pd.translate(large).unwrap();
+            assert!(t.phys == virt_to_phys(large) && t.is_large());
+            assert!(t.frame() == page_align(virt_to_phys(large)));
+            assert!(pd.translate(TEST_ADDR).is_none());
+
+            // No kernel page is both writable and executable. Only PAE can forbid execution.
+            let code = pd.translate(test_translate as usize).unwrap().flags();
+            assert!(!code.contains(PTE_WRITABLE) && !code.contains(PTE_NOEXEC));
+            let local = 0usize;
+            let stack = pd.translate(&local as *const usize as usize).unwrap().flags();
+            assert!(stack.contains(PTE_WRITABLE));
+            assert!(stack.contains(PTE_NOEXEC) == is_pae());
+            let data = pd.translate(large).unwrap().flags();
+            assert!(data.contains(PTE_NOEXEC) == is_pae());
+        });
+    }
+
+    // Device mappings show up as a single run with their cache mode and nothing around them.
+    let io = ioremap(0xB8000, 2 * PAGE_SIZE, CacheMode::Uncached).unwrap();
+    {
+        let space = mem::kernel_space().lock();
+        space.with_pd(|pd| {
+            let t = pd.translate(io.addr() + PAGE_SIZE + 0x10).unwrap();
+            assert!(t.phys == 0xB9010);
+            assert!(t.flags().contains(PTE_WRITABLE | PTE_CACHEDISABLE | PTE_GLOBAL));
+            assert!(t.flags().contains(PTE_NOEXEC) == is_pae());
+            assert!(!t.flags().contains(PTE_SUPERVISOR));
+            assert!(pd.translate(io.addr() + 2 * PAGE_SIZE).is_none());
+
+            let mapping = pd.mappings().find(|m| m.start <= io.addr() && io.addr() < m.end);
+            let mapping = mapping.unwrap();
+            assert!(mapping.start == io.addr() && mapping.size() == 2 * PAGE_SIZE);
+            assert!(mapping.phys == 0xB8000);
+
+            // Runs are sorted and never touch a compatible neighbour.
+            let mut prev: Option<Mapping> = None;
+            for mapping in pd.mappings() {
+                if let Some(prev) = prev {
+                    assert!(prev.end <= mapping.start);
+                    assert!(prev.end < mapping.start || prev.flags != mapping.flags ||
+                            prev.phys + prev.size() != mapping.phys);
+                }
+                prev = Some(mapping);
+            }
+        });
+    }
+    drop(io);
+
+    // The kernel's mappings can be read from the file system.
+    let mut cursor = fs::root_cursor();
+    cursor.cd(Path::from_str("sys/mem")).unwrap();
+    let file = cursor.open_file("kmap").unwrap();
+    let mut buf = [0u8; 8];
+    assert!(unsafe { file.read(buf.as_mut_ptr() as usize, 0, buf.len()) } == buf.len());
+}
@@ -0,0 +1,99 @@
+use core::prelude::*;
+use core::mem;
+use alloc;
+use alloc::{ALLOC_ATOMIC, ALLOC_ZEROED, ALLOC_RESERVE, ALLOC_BLOCK};
+use alloc::boxed::Box;
+use alloc::pool;
+use collections::vec::Vec;
+use util::asm;
+logger_init!(Trace);
+
+#[inline(never)]
+pub fn test() {
+    trace!("\ntesting allocation flags");
+
+    let free_start = alloc::get_free_space();
+    let stats_start = pool::stats();
+    {
+        // Zeroed memory from the heap.
+        let addr = alloc::allocate_raw_flags(100, 4, ALLOC_ZEROED).unwrap();
+        assert!(!pool::owns(addr));
+        assert!((0 .. 100).all(|i| unsafe { *((addr + i) as *const u8) } == 0));
+        alloc::deallocate_raw(addr, 100);
+
+        // Atomic allocations come from the pool, even with interrupts off.
+        let reenable = asm::interrupts_enabled();
+        asm::disable_interrupts();
+        let b = Box::new_flags(0xdeadbeef_u32, ALLOC_ATOMIC).unwrap();
+        assert!(pool::owns(&*b as *const u32 as usize));
+        assert!(*b == 0xdeadbeef);
+        drop(b);
+        if reenable {
+            asm::enable_interrupts();
+        }
+
+        // The smallest class that fits is used and blocks are aligned to their size.
+        let addr = alloc::allocate_raw_flags(100, 4, ALLOC_ATOMIC | ALLOC_ZEROED).unwrap();
+        assert!(pool::block_size(addr) == 128 && addr % 128 == 0);
+        assert!((0 .. 100).all(|i| unsafe { *((addr + i) as *const u8) } == 0));
+        alloc::deallocate_raw(addr, 100);
+
+        // Anything bigger than the largest class can't be served.
+        assert!(alloc::allocate_raw_flags(4096, 4, ALLOC_ATOMIC).is_err());
+
+        // Atomic vectors stay in the pool as they grow.
+        let mut v = Vec::new_flags(1, ALLOC_ATOMIC).unwrap();
+        for i in 0 .. 100_u32 {
+            assert!(v.push(i).is_ok());
+        }
+        assert!(pool::owns(v.as_slice().as_ptr() as usize));
+        assert!(v.as_slice().iter().enumerate().all(|(i, &x)| x == i as u32));
+        drop(v);
+
+        // Once a class is used up larger classes are used, and then the pool is exhausted.
+        let mut blocks = [0; pool::BLOCKS_PER_CLASS * pool::CLASSES];
+        let mut count = 0;
+        while let Ok(addr) = alloc::allocate_raw_flags(32, 4, ALLOC_ATOMIC) {
+            blocks[count] = addr;
+            count += 1;
+        }
+        assert!(count == blocks.len());
+        assert!(pool::stats().available.iter().all(|&n| n == 0));
+        assert!(pool::stats().failures == stats_start.failures + 2);
+        for &addr in blocks.iter() {
+            alloc::deallocate_raw(addr, 32);
+        }
+        assert!(pool::stats().available == stats_start.available);
+
+        // Reserve allocations only use the pool when the heap can't serve them.
+        let b = Box::emplace_flags(|x: &mut [u8; 64]| x[0] = 1, ALLOC_RESERVE).unwrap();
+        assert!(!pool::owns(&*b as *const [u8; 64] as usize));
+        drop(b);
+
+        // Atomic reallocations stay in the block while they fit and move to another pool block
+        // when they don't.
+        let addr = alloc::allocate_raw_flags(40, 4, ALLOC_ATOMIC).unwrap();
+        unsafe { *(addr as *mut u32) = 0xcafe };
+        let addr2 = alloc::reallocate_raw_flags(addr, 40, 60, 4, ALLOC_ATOMIC).unwrap();
+        assert!(addr2 == addr);
+        let addr3 = alloc::reallocate_raw_flags(addr2, 60, 1000, 4, ALLOC_ATOMIC).unwrap();
+        assert!(pool::owns(addr3) && pool::block_size(addr3) == 1024);
+        assert!(unsafe { *(addr3 as *const u32) } == 0xcafe);
+        alloc::deallocate_raw(addr3, 1000);
+
+        // Heap memory can't be reallocated atomically.
+        let addr = alloc::allocate_raw(64, 4).unwrap();
+        assert!(alloc::reallocate_raw_flags(addr, 64, 128, 4, ALLOC_ATOMIC).is_err());
+        alloc::deallocate_raw(addr, 64);
+
+        // Memory moves from the pool to the heap when it is reallocated without ALLOC_ATOMIC.
+        let addr = alloc::allocate_raw_flags(mem::size_of::<u64>(), 8, ALLOC_ATOMIC).unwrap();
+        unsafe { *(addr as *mut u64) = 42 };
+        let addr = alloc::reallocate_raw_flags(addr, 8, 4096, 8, ALLOC_BLOCK).unwrap();
+        assert!(!pool::owns(addr));
+        assert!(unsafe { *(addr as *const u64) } == 42);
+        alloc::deallocate_raw(addr, 4096);
+    }
+    assert!(pool::stats().available == stats_start.available);
+    assert!(free_start == alloc::get_free_space());
+}
@@ -1,7 +1,8 @@
 use core::prelude::*;
+use alloc;
 use alloc::boxed::Box;
-use alloc::rc::{Rc, HasRc};
-use core::atomic::{AtomicUsize, ATOMIC_USIZE_INIT};
+use alloc::rc::{Rc, Weak, HasRc};
+use core::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 use core::fmt;
 logger_init!(Trace);
 
@@ -16,6 +17,21 @@ impl HasRc for Baz {
     }
 }
 
+struct Qux {
+    rc: AtomicUsize,
+    weak: AtomicUsize,
+    val: usize
+}
+
+impl HasRc for Qux {
+    fn get_count(&self) -> &AtomicUsize {
+        &self.rc
+    }
+    fn get_weak_count(&self) -> Option<&AtomicUsize> {
+        Some(&self.weak)
+    }
+}
+
 impl fmt::Debug for Baz {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Baz {{ val: {:?} }}", self.val)
@@ -38,5 +54,20 @@ pub fn test() {
     trace!("rcx2 still live: {:?}", rcx2);
 
     drop(rcx2);
-}
 
+    // Weak references don't keep the value alive but can get it back while it is.
+    let free = alloc::get_free_space();
+    let x = Box::new(Qux { rc: ATOMIC_USIZE_INIT, weak: ATOMIC_USIZE_INIT, val: 5 }).unwrap();
+    let rc = Rc::new(x);
+    let weak = rc.downgrade();
+    let weak2: Weak<Qux> = Weak::from_ref(&*rc);
+    assert!(weak.upgrade().map(|rc| rc.val) == Some(5));
+    assert!(rc.get_count().load(Ordering::Relaxed) == 1);
+    assert!(rc.weak.load(Ordering::Relaxed) == 3);
+    drop(rc);
+    assert!(weak.upgrade().is_none());
+    drop(weak2);
+    drop(weak.clone());
+    drop(weak);
+    assert!(alloc::get_free_space() == free);
+}
@@ -0,0 +1,48 @@
+use core::prelude::*;
+use alloc;
+#[cfg(ALLOC_DEBUG="yes")]
+use alloc::debug::{RED_ZONE, RED_ZONE_BYTE, FRESH_BYTE, POISON_BYTE, QUARANTINE_SIZE};
+logger_init!(Trace);
+
+// The debug allocator is only there in kernels built with `ALLOC_DEBUG := yes`.
+#[cfg(not(ALLOC_DEBUG="yes"))]
+pub fn test() {
+    alloc::check_heap();
+}
+
+#[cfg(ALLOC_DEBUG="yes")]
+fn byte(addr: usize) -> u8 {
+    unsafe { *(addr as *const u8) }
+}
+
+#[cfg(ALLOC_DEBUG="yes")]
+#[inline(never)]
+pub fn test() {
+    trace!("\ntesting the debug allocator");
+
+    // New blocks are filled with a pattern and surrounded by red zones.
+    let addr = alloc::allocate_raw(10, 1).unwrap();
+    assert!((0 .. 10).all(|i| byte(addr + i) == FRESH_BYTE));
+    assert!((1 .. RED_ZONE + 1).all(|i| byte(addr - i) == RED_ZONE_BYTE));
+    assert!((10 .. 10 + RED_ZONE).all(|i| byte(addr + i) == RED_ZONE_BYTE));
+
+    // Freed blocks are poisoned and kept in the quarantine for a while.
+    unsafe { *(addr as *mut u8) = 1 };
+    alloc::deallocate_raw(addr, 10);
+    assert!((0 .. 10).all(|i| byte(addr + i) == POISON_BYTE));
+    assert!(alloc::is_quarantined(addr));
+    alloc::check_heap();
+
+    // The block goes back once enough other blocks were freed after it.
+    let free = alloc::get_free_space();
+    for _ in 0 .. QUARANTINE_SIZE {
+        assert!(alloc::is_quarantined(addr));
+        let other = alloc::allocate_raw(10, 1).unwrap();
+        assert!(other != addr);
+        alloc::deallocate_raw(other, 10);
+        assert!(alloc::is_quarantined(other));
+    }
+    assert!(!alloc::is_quarantined(addr));
+    assert!(alloc::get_free_space() == free);
+    alloc::check_heap();
+}
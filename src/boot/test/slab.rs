@@ -0,0 +1,102 @@
+use core::prelude::*;
+#[cfg(ALLOC_DEBUG="yes")]
+use core::mem;
+use alloc;
+use alloc::boxed::Box;
+#[cfg(ALLOC_DEBUG="yes")]
+use alloc::debug::{RED_ZONE, RED_ZONE_BYTE, POISON_BYTE};
+use alloc::slab::{self, Cache};
+#[cfg(not(ALLOC_DEBUG="yes"))]
+use collections::vec::Vec;
+#[cfg(not(ALLOC_DEBUG="yes"))]
+use util::PAGE_SIZE;
+logger_init!(Trace);
+
+struct Obj {
+    a: usize,
+    b: [u8; 20],
+}
+
+fn init_obj(obj: &mut Obj) {
+    obj.a = 7;
+    obj.b = [1; 20];
+}
+
+static OBJ_CACHE: Cache<Obj> = Cache::with_ctor("test_obj", init_obj);
+
+#[cfg(not(ALLOC_DEBUG="yes"))]
+#[inline(never)]
+pub fn test() {
+    trace!("\ntesting slab caches");
+
+    let free_start = alloc::get_free_space();
+
+    // Objects come from the cache and go back to it when dropped.
+    let a = Box::new_in(Obj { a: 1, b: [0; 20] }, &OBJ_CACHE).unwrap();
+    let b = Box::construct_in(&OBJ_CACHE).unwrap();
+    assert!(a.a == 1 && b.a == 7 && b.b[19] == 1);
+    let stats = OBJ_CACHE.stats();
+    assert!(stats.name == "test_obj" && stats.slot_size >= 24);
+    assert!(stats.active == 2 && stats.slabs == 1 && stats.allocs == 2);
+    let a_addr = &*a as *const Obj as usize;
+    drop(a);
+    assert!(OBJ_CACHE.stats().active == 1 && OBJ_CACHE.stats().frees == 1);
+
+    // The last freed slot is handed out first.
+    let c = Box::new_in(Obj { a: 3, b: [0; 20] }, &OBJ_CACHE).unwrap();
+    assert!(&*c as *const Obj as usize == a_addr);
+    drop(c);
+    drop(b);
+
+    // Filling more than one slab adds slabs. Emptied slabs go back to the heap except the last.
+    let per_slab = OBJ_CACHE.stats().slots_per_slab;
+    let mut boxes = Vec::new(2 * per_slab + 1).unwrap();
+    for i in 0 .. 2 * per_slab + 1 {
+        let obj = Box::new_in(Obj { a: i, b: [0; 20] }, &OBJ_CACHE).unwrap();
+        assert!(boxes.push(obj).is_ok());
+    }
+    assert!(OBJ_CACHE.stats().slabs == 3);
+    assert!(boxes.as_slice().iter().all(|obj| (&**obj as *const Obj as usize) % PAGE_SIZE != 0));
+    drop(boxes);
+    let stats = OBJ_CACHE.stats();
+    assert!(stats.active == 0 && stats.slabs == 1);
+    assert!(stats.allocs == stats.frees);
+
+    // The cache shows up in the list of caches.
+    let mut found = false;
+    slab::for_each_cache(|stats| found |= stats.name == "test_obj");
+    assert!(found);
+
+    // Only the slab we keep around is missing from the heap.
+    assert!(free_start - alloc::get_free_space() <= 2 * PAGE_SIZE);
+}
+
+#[cfg(ALLOC_DEBUG="yes")]
+fn byte(addr: usize) -> u8 {
+    unsafe { *(addr as *const u8) }
+}
+
+// Kernels built with `ALLOC_DEBUG := yes` give every object a heap block of its own.
+#[cfg(ALLOC_DEBUG="yes")]
+#[inline(never)]
+pub fn test() {
+    trace!("\ntesting slab caches");
+
+    let free_start = alloc::get_free_space();
+    let allocs = OBJ_CACHE.stats().allocs;
+
+    // Objects are red zoned heap blocks instead of slots in a slab.
+    let a = Box::construct_in(&OBJ_CACHE).unwrap();
+    assert!(a.a == 7 && a.b[19] == 1);
+    let addr = &*a as *const Obj as usize;
+    assert!(!slab::owns(addr));
+    assert!((1 .. RED_ZONE + 1).all(|i| byte(addr - i) == RED_ZONE_BYTE));
+    let stats = OBJ_CACHE.stats();
+    assert!(stats.allocs == allocs + 1 && stats.slabs == 0);
+
+    // Freed objects are poisoned and quarantined like any other block.
+    drop(a);
+    assert!((0 .. mem::size_of::<Obj>()).all(|i| byte(addr + i) == POISON_BYTE));
+    alloc::check_heap();
+    assert!(alloc::get_free_space() == free_start);
+}
@@ -0,0 +1,29 @@
+use core::prelude::*;
+use mem::phys;
+use task::thread::{Thread, DEFAULT_STACK_SIZE};
+use util::PAGE_SIZE;
+logger_init!(Trace);
+
+fn never_run() -> ! {
+    panic!("test thread was scheduled");
+}
+
+#[inline(never)]
+pub fn test() {
+    trace!("\ntesting thread stacks");
+
+    let free_start = phys::free_frame_count();
+
+    // Stacks are mapped page by page from frames.
+    let thread = Thread::new(never_run).unwrap();
+    assert!(thread.stack_size() == DEFAULT_STACK_SIZE);
+    assert!(phys::free_frame_count() == free_start - DEFAULT_STACK_SIZE / PAGE_SIZE);
+    drop(thread);
+    assert!(phys::free_frame_count() == free_start);
+
+    // Stack sizes are rounded up to whole pages.
+    let thread = Thread::with_stack_size(never_run, 5 * PAGE_SIZE + 1).unwrap();
+    assert!(thread.stack_size() == 6 * PAGE_SIZE);
+    drop(thread);
+    assert!(phys::free_frame_count() == free_start);
+}
@@ -0,0 +1,119 @@
+use core::prelude::*;
+use alloc;
+use alloc::boxed::Box;
+use collections::hashmap::HasKey;
+use collections::link::{TreeLink, HasTreeLink};
+use collections::tree::Tree;
+logger_init!(Trace);
+
+#[derive(Default)]
+struct X {
+    key: usize,
+    val: usize,
+    node: TreeLink<X>
+}
+
+impl HasKey<usize> for X {
+    fn get_key(&self) -> &usize {
+        &self.key
+    }
+}
+
+impl HasTreeLink<X> for X {
+    fn tlink(&self) -> &TreeLink<X> { &self.node }
+    fn tlink_mut(&mut self) -> &mut TreeLink<X> { &mut self.node }
+}
+
+fn new_x(key: usize, val: usize) -> Box<X> {
+    Box::new(X { key: key, val: val, node: TreeLink::default() }).unwrap()
+}
+
+// The number of elements. Keys are the multiples of 10 below 10 * COUNT.
+const COUNT: usize = 200;
+
+#[inline(never)]
+pub fn test() {
+    trace!("\ntesting tree");
+
+    let free_start = alloc::get_free_space();
+    {
+        let mut tree: Tree<usize, X> = Tree::new();
+        assert!(tree.is_empty() && tree.first().is_none() && tree.iter().next().is_none());
+
+        // Insert in a scrambled order. 7 is coprime to COUNT so every key comes up once.
+        for i in 0 .. COUNT {
+            let key = (i * 7) % COUNT * 10;
+            assert!(tree.insert(new_x(key, key + 1)).is_none());
+        }
+        assert!(tree.len() == COUNT);
+        // An AVL tree of 200 elements is at most 10 high.
+        assert!(tree.height() <= 10);
+
+        // The tree iterates in order.
+        let mut count = 0;
+        for (i, x) in tree.iter().enumerate() {
+            assert!(x.key == i * 10 && x.val == x.key + 1);
+            count += 1;
+        }
+        assert!(count == COUNT);
+
+        // Lookups.
+        assert!(tree.lookup(&500).unwrap().val == 501);
+        assert!(tree.lookup(&505).is_none());
+        tree.lookup_mut(&500).unwrap().val = 42;
+        assert!(tree.lookup(&500).unwrap().val == 42);
+        assert!(tree.first().unwrap().key == 0);
+        assert!(tree.last().unwrap().key == (COUNT - 1) * 10);
+
+        // Bounds.
+        assert!(tree.lower_bound(&500).unwrap().key == 500);
+        assert!(tree.lower_bound(&501).unwrap().key == 510);
+        assert!(tree.upper_bound(&500).unwrap().key == 510);
+        assert!(tree.floor(&509).unwrap().key == 500);
+        assert!(tree.floor(&500).unwrap().key == 500);
+        assert!(tree.lower_bound(&(COUNT * 10)).is_none());
+        assert!(tree.upper_bound(&((COUNT - 1) * 10)).is_none());
+
+        // Ranges are half open.
+        let mut expected = 300;
+        for x in tree.range(&295, &400) {
+            assert!(x.key == expected);
+            expected += 10;
+        }
+        assert!(expected == 400);
+        assert!(tree.range(&301, &305).next().is_none());
+
+        // Inserting an existing key evicts the old element.
+        let old = tree.insert(new_x(500, 7)).unwrap();
+        assert!(old.key == 500 && old.val == 42);
+        assert!(tree.lookup(&500).unwrap().val == 7);
+        assert!(tree.len() == COUNT);
+
+        // Remove every other element, inner nodes included.
+        for i in 0 .. COUNT / 2 {
+            let key = i * 20;
+            assert!(tree.remove(&key).unwrap().key == key);
+        }
+        assert!(tree.remove(&0).is_none());
+        assert!(tree.len() == COUNT / 2);
+        assert!(tree.height() <= 9);
+        for (i, x) in tree.iter().enumerate() {
+            assert!(x.key == i * 20 + 10);
+        }
+        assert!(tree.floor(&20).unwrap().key == 10);
+
+        // Popping empties the tree in order.
+        let mut expected = 10;
+        while let Some(x) = tree.pop_first() {
+            assert!(x.key == expected);
+            expected += 20;
+        }
+        assert!(tree.is_empty() && tree.height() == 0);
+
+        // The tree frees what is left in it when it is dropped.
+        for i in 0 .. 10 {
+            tree.insert(new_x(i, i));
+        }
+    }
+    assert!(free_start == alloc::get_free_space());
+}
@@ -220,6 +220,32 @@ pub fn vfs_shell() -> ! {
                     }
                 }
             }
+            "cat" => {
+                debug!("cat");
+                match words.next() {
+                    None => println!(CON, "cat NAME"),
+                    Some(arg) => {
+                        match cursor.open_file(arg) {
+                            Err(b) => println!(CON, "error: {:?}", b),
+                            Ok(file) => {
+                                let mut buf = [0u8; 64];
+                                let mut offset = 0;
+                                loop {
+                                    let into = buf.as_mut_ptr() as usize;
+                                    let count = unsafe { file.read(into, offset, buf.len()) };
+                                    if count == 0 {
+                                        break;
+                                    }
+                                    for &b in buf[..count].iter() {
+                                        print!(CON, "{}", b as char);
+                                    }
+                                    offset += count;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
             _ => {
                 println!(CON, "unknown command '{}'", cmd);
             }
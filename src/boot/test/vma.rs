@@ -0,0 +1,65 @@
+use core::prelude::*;
+use mem::vma::{Vma, VmaList, VmaFlags, Backing, Protection, PROT_READ, PROT_WRITE};
+use mem::vma::VMA_SHARED;
+use util::PAGE_SIZE;
+logger_init!(Trace);
+
+const BASE: usize = 0x40000000;
+
+fn anon(start: usize, end: usize, prot: Protection) -> Vma {
+    Vma::new(BASE + start * PAGE_SIZE, BASE + end * PAGE_SIZE, prot, Backing::Anonymous,
+             VmaFlags::empty())
+}
+
+#[inline(never)]
+pub fn test() {
+    trace!("\ntesting vmas");
+
+    let rw = PROT_READ | PROT_WRITE;
+    let mut list = VmaList::new().unwrap();
+
+    // Adjacent compatible areas are merged, incompatible ones are not.
+    list.insert(anon(0, 2, rw)).unwrap();
+    list.insert(anon(2, 4, rw)).unwrap();
+    assert!(list.len() == 1);
+    list.insert(anon(6, 8, PROT_READ)).unwrap();
+    list.insert(anon(4, 6, rw)).unwrap();
+    assert!(list.len() == 2);
+    let shared = Vma::new(BASE + 8 * PAGE_SIZE, BASE + 9 * PAGE_SIZE, PROT_READ,
+                          Backing::Anonymous, VMA_SHARED);
+    list.insert(shared).unwrap();
+    assert!(list.len() == 3);
+
+    // Overlapping areas are rejected.
+    assert!(list.insert(anon(3, 5, rw)).is_err());
+    assert!(list.len() == 3);
+
+    // Lookups.
+    assert!(list.lookup(BASE - 1).is_none());
+    assert!(list.lookup(BASE).unwrap().end == BASE + 6 * PAGE_SIZE);
+    assert!(list.lookup(BASE + 6 * PAGE_SIZE).unwrap().prot == PROT_READ);
+    assert!(list.lookup(BASE + 9 * PAGE_SIZE).is_none());
+
+    // Splitting a device area advances its backing.
+    let dev = Vma::new(BASE + 16 * PAGE_SIZE, BASE + 20 * PAGE_SIZE, rw,
+                       Backing::Device { phys: 0xb8000 }, VmaFlags::empty());
+    list.insert(dev).unwrap();
+    list.split(BASE + 18 * PAGE_SIZE).unwrap();
+    assert!(list.len() == 5);
+    let upper = *list.lookup(BASE + 18 * PAGE_SIZE).unwrap();
+    assert!(upper.start == BASE + 18 * PAGE_SIZE);
+    assert!(upper.backing == Backing::Device { phys: 0xb8000 + 2 * PAGE_SIZE });
+    list.merge();
+    assert!(list.len() == 4);
+
+    // Removing a range from the middle of an area leaves both ends behind.
+    let mut removed = 0;
+    list.remove(BASE + PAGE_SIZE, BASE + 7 * PAGE_SIZE, |vma| removed += vma.size()).unwrap();
+    assert!(removed == 6 * PAGE_SIZE);
+    assert!(list.lookup(BASE).unwrap().end == BASE + PAGE_SIZE);
+    assert!(list.lookup(BASE + 7 * PAGE_SIZE).unwrap().start == BASE + 7 * PAGE_SIZE);
+    assert!(list.lookup(BASE + 3 * PAGE_SIZE).is_none());
+    assert!(list.len() == 4);
+
+    trace!("{:?}", list);
+}
@@ -0,0 +1,45 @@
+use core::prelude::*;
+use mem;
+use mem::phys;
+use mem::virt::{vmalloc, vfree, VMALLOC_START, VMALLOC_END};
+use util::PAGE_SIZE;
+logger_init!(Trace);
+
+#[inline(never)]
+pub fn test() {
+    trace!("\ntesting vmalloc");
+
+    let free_start = phys::free_frame_count();
+
+    // Allocations are rounded up to whole pages and backed by frames.
+    let mut a = vmalloc(3 * PAGE_SIZE - 1).unwrap();
+    assert!(a.size() == 3 * PAGE_SIZE);
+    assert!(VMALLOC_START <= a.addr() && a.addr() + a.size() <= VMALLOC_END);
+    assert!(phys::free_frame_count() == free_start - 3);
+    for (i, byte) in a.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    assert!(a[PAGE_SIZE + 7] == 7);
+
+    // The page after every allocation is left unmapped.
+    let b = vmalloc(PAGE_SIZE).unwrap();
+    assert!(b.addr() >= a.addr() + a.size() + PAGE_SIZE);
+    {
+        let space = mem::kernel_space().lock();
+        assert!(space.has_page(a.addr() + 2 * PAGE_SIZE));
+        assert!(!space.has_page(a.addr() + a.size()));
+    }
+
+    // Freed ranges are reused.
+    let a_addr = a.addr();
+    vfree(a);
+    let c = vmalloc(2 * PAGE_SIZE).unwrap();
+    assert!(c.addr() == a_addr);
+    drop(c);
+    drop(b);
+    assert!(phys::free_frame_count() == free_start);
+
+    // Allocations larger than the area fail.
+    assert!(vmalloc(VMALLOC_END - VMALLOC_START).is_err());
+    assert!(phys::free_frame_count() == free_start);
+}
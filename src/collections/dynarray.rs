@@ -8,6 +8,7 @@ use core::{mem, ptr, marker};
 use core::ops::{Index, IndexMut};
 use core::intrinsics::drop_in_place;
 use alloc::{allocate_raw, reallocate_raw, deallocate_raw};
+use alloc::cow::TryClone;
 use util::KernResult;
 
 /// A dynamicly resizable array. 
@@ -96,6 +97,12 @@ impl<T: Default> DynArray<T> {
 
 }
 
+impl<T: Default + Clone> TryClone for DynArray<T> {
+    fn try_clone(&self) -> KernResult<DynArray<T>> {
+        self.clone()
+    }
+}
+
 impl<T> Drop for DynArray<T> {
     fn drop(&mut self) {
         if self.len != mem::POST_DROP_USIZE {
@@ -4,9 +4,12 @@
 /// open-addressing. A consequence of this however is that keys must always be derivable from their
 /// values. 
 ///
-/// Currently uses lookup_{or_insert}_{mut} for variations of looking up but I would like to use
-/// the more ergonomic Entry method used in libstd. I'm not sure if this makes sense for separate
-/// chaining however.
+/// The number of buckets doubles whenever the map gets more than `MAX_LOAD` percent full. Growing
+/// needs memory for the new buckets, so if that fails the map simply stays at its size and gets
+/// slower. `reserve` grows the map up front and reports failure.
+///
+/// Keys are hashed with `FNVHasher` unless the map is created with another hasher by
+/// `with_hasher`.
 ///
 use alloc::boxed::Box;
 use core::prelude::*;
@@ -17,61 +20,126 @@ use dynarray::{self, DynArray};
 use link::HasSingleLink;
 use slist::{self, SList};
 use util::KernResult;
+use util::KernError::OutOfMemory;
 
 /// We assume the hash function is uniformly distributed in the lowest bits so that this doesn't
 /// result in horrible collisions. Otherwise this should be prime!
 const DEFAULT_SIZE: usize = 16;
 
-pub trait HasKey<K: ?Sized> where K: Eq + Hash {
+/// The percentage of the number of buckets the number of elements may reach before the map grows.
+const MAX_LOAD: usize = 75;
+
+/// An object that knows its own key. Maps keep objects by the key they return, which must not
+/// change while they are in a map.
+pub trait HasKey<K: ?Sized> {
     fn get_key(&self) -> &K;
 }
 
-pub struct HashMap<K: ?Sized, V: ?Sized> 
+pub struct HashMap<K: ?Sized, V: ?Sized, H = FNVHasher>
 where K: Eq + Hash,
-      V: HasKey<K> + HasSingleLink<V> {
+      V: HasKey<K> + HasSingleLink<V>,
+      H: Hasher + Clone {
     count: usize,
     table: DynArray<SList<V>>,
+    hasher: H,
     _marker: marker::PhantomData<K>,
 }
 
 impl<K: ?Sized, V: ?Sized> HashMap<K, V>
 where K: Eq + Hash,
       V: HasKey<K> + HasSingleLink<V> {
-    
-    fn hash(&self, key: &K) -> u64 {
-        let mut state = FNVHasher::new();
-        key.hash(&mut state);
-        state.finish()
-    }
-
-    fn entry(&self, key: &K) -> usize {
-        self.hash(key) as usize % self.table.len()
-    }
 
     /// Attempts to construct a new hashmap.
     pub fn new() -> KernResult<HashMap<K, V>> {
+        HashMap::with_hasher(FNVHasher::new())
+    }
+
+}
+
+impl<K: ?Sized, V: ?Sized, H> HashMap<K, V, H>
+where K: Eq + Hash,
+      V: HasKey<K> + HasSingleLink<V>,
+      H: Hasher + Clone {
+
+    /// Attempts to construct a new hashmap that hashes keys with copies of `hasher`.
+    pub fn with_hasher(hasher: H) -> KernResult<HashMap<K, V, H>> {
         let dyn = try!(DynArray::new(DEFAULT_SIZE));
         Ok(HashMap {
             count: 0,
             table: dyn,
+            hasher: hasher,
             _marker: marker::PhantomData
         })
     }
 
+    fn hash(&self, key: &K) -> u64 {
+        let mut state = self.hasher.clone();
+        key.hash(&mut state);
+        state.finish()
+    }
+
+    fn bucket(&self, key: &K) -> usize {
+        self.hash(key) as usize % self.table.len()
+    }
+
     pub fn count(&self) -> usize {
         self.count
     }
 
+    /// Returns the number of buckets.
+    pub fn capacity(&self) -> usize {
+        self.table.len()
+    }
+
+    /// Attempts to grow the map so that `additional` more elements fit without it growing again.
+    ///
+    /// # Failures
+    ///
+    /// Fails if there is no memory for the new buckets or their number would overflow.
+    pub fn reserve(&mut self, additional: usize) -> KernResult<()> {
+        let needed = try!(self.count.checked_add(additional)
+                              .and_then(|n| n.checked_mul(100))
+                              .ok_or(OutOfMemory));
+        let mut buckets = self.table.len();
+        while needed > try!(buckets.checked_mul(MAX_LOAD).ok_or(OutOfMemory)) {
+            buckets = try!(buckets.checked_mul(2).ok_or(OutOfMemory));
+        }
+        if buckets == self.table.len() {
+            Ok(())
+        } else {
+            self.resize(buckets)
+        }
+    }
+
+    // Moves every element into a new table of `buckets` buckets.
+    fn resize(&mut self, buckets: usize) -> KernResult<()> {
+        let mut table: DynArray<SList<V>> = try!(DynArray::new(buckets));
+        for i in 0 .. self.table.len() {
+            while let Some(val) = self.table[i].pop() {
+                let bucket = self.hash(val.get_key()) as usize % buckets;
+                table[bucket].push(val);
+            }
+        }
+        self.table = table;
+        Ok(())
+    }
+
+    // Grows the map if one more element would make it too full. The map still works if this
+    // fails, so failure is ignored.
+    fn grow(&mut self) {
+        if (self.count + 1) * 100 > self.table.len() * MAX_LOAD {
+            let buckets = self.table.len() * 2;
+            let _ = self.resize(buckets);
+        }
+    }
+
     /// Inserts a new entry into the hash map and returns the evicted value if there was one.
     pub fn insert(&mut self, val: Box<V>) -> Option<Box<V>> {
-        let (res, entry) = {
-            let key = val.get_key();
-            let entry = self.entry(key);
-            let res = self.remove(key);
-            (res, entry)
-        };
+        let res = self.remove(val.get_key());
+        self.grow();
+        let bucket = self.bucket(val.get_key());
         self.count += 1;
-        self.table[entry].push(val);
+        self.table[bucket].push(val);
         res
     }
 
@@ -82,24 +150,39 @@ where K: Eq + Hash,
 
     /// Tries to remove an element with the given key.
     pub fn remove(&mut self, key: &K) -> Option<Box<V>> {
-        let entry = self.entry(key);
-        let res = self.table[entry].remove_where(|elem| elem.get_key() == key);
+        let bucket = self.bucket(key);
+        let res = self.table[bucket].remove_where(|elem| elem.get_key() == key);
         if res.is_some() {
             self.count -= 1; 
         }
         res
     }
 
+    /// Removes every element for which `keep` returns false.
+    pub fn retain<F>(&mut self, mut keep: F) where F: FnMut(&K, &V) -> bool {
+        for i in 0 .. self.table.len() {
+            let mut kept = SList::new();
+            while let Some(val) = self.table[i].pop() {
+                if keep(val.get_key(), &*val) {
+                    kept.push(val);
+                } else {
+                    self.count -= 1;
+                }
+            }
+            self.table[i] = kept;
+        }
+    }
+
     /// Tries to borrow an element with the given key.
     pub fn lookup(&self, key: &K) -> Option<&V> {
-        let entry = self.entry(key);
-        self.table[entry].borrow_where(|elem| elem.get_key() == key)
+        let bucket = self.bucket(key);
+        self.table[bucket].borrow_where(|elem| elem.get_key() == key)
     }
 
     /// Tries to mutably borrow an element with the given key.
     pub fn lookup_mut(&mut self, key: &K) -> Option<&mut V> {
-        let entry = self.entry(key);
-        self.table[entry].borrow_mut_where(|elem| elem.get_key() == key)
+        let bucket = self.bucket(key);
+        self.table[bucket].borrow_mut_where(|elem| elem.get_key() == key)
     }
 
     /// Tries to lookup an element in the map and if it is not present, inserts an element. Returns
@@ -120,6 +203,22 @@ where K: Eq + Hash,
         self.lookup_mut(key).unwrap()
     }
 
+    /// Finds the place of the element with the given key, whether or not it is in the map, so it
+    /// can be used or filled in without hashing the key again.
+    pub fn entry<'a>(&'a mut self, key: &'a K) -> Entry<'a, K, V, H> {
+        let bucket = self.bucket(key);
+        if self.table[bucket].borrow_where(|elem| elem.get_key() == key).is_some() {
+            Entry::Occupied(OccupiedEntry { map: self, bucket: bucket, key: key })
+        } else {
+            Entry::Vacant(VacantEntry { map: self, key: key })
+        }
+    }
+
+    /// Iterates over the keys and values of the map.
+    pub fn iter(&self) -> Iter<K, V> {
+        Iter { value_iter: self.iter_values() }
+    }
+
     pub fn iter_keys(&self) -> KeyIter<K, V> {
         KeyIter { value_iter: self.iter_values() }
     }
@@ -136,6 +235,111 @@ where K: Eq + Hash,
     }
 }
 
+/// The place of an element in a map.
+pub enum Entry<'a, K: ?Sized + 'a, V: ?Sized + 'a, H: 'a>
+where K: Eq + Hash,
+      V: HasKey<K> + HasSingleLink<V>,
+      H: Hasher + Clone {
+    /// The element is in the map.
+    Occupied(OccupiedEntry<'a, K, V, H>),
+    /// The element is not in the map.
+    Vacant(VacantEntry<'a, K, V, H>),
+}
+
+/// The place of an element that is in a map.
+pub struct OccupiedEntry<'a, K: ?Sized + 'a, V: ?Sized + 'a, H: 'a>
+where K: Eq + Hash,
+      V: HasKey<K> + HasSingleLink<V>,
+      H: Hasher + Clone {
+    map: &'a mut HashMap<K, V, H>,
+    bucket: usize,
+    key: &'a K,
+}
+
+/// The place of an element that is not in a map.
+pub struct VacantEntry<'a, K: ?Sized + 'a, V: ?Sized + 'a, H: 'a>
+where K: Eq + Hash,
+      V: HasKey<K> + HasSingleLink<V>,
+      H: Hasher + Clone {
+    map: &'a mut HashMap<K, V, H>,
+    key: &'a K,
+}
+
+impl<'a, K: ?Sized, V: ?Sized, H> Entry<'a, K, V, H>
+where K: Eq + Hash,
+      V: HasKey<K> + HasSingleLink<V>,
+      H: Hasher + Clone {
+
+    /// Mutably borrows the element, inserting the one made by `new` first if there is none.
+    ///
+    /// # Failures
+    ///
+    /// Fails if `new` fails. The map is not changed.
+    pub fn or_insert_with<F>(self, new: F) -> KernResult<&'a mut V>
+        where F: FnOnce() -> KernResult<Box<V>> {
+        match self {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => Ok(entry.insert(try!(new()))),
+        }
+    }
+
+}
+
+impl<'a, K: ?Sized, V: ?Sized, H> OccupiedEntry<'a, K, V, H>
+where K: Eq + Hash,
+      V: HasKey<K> + HasSingleLink<V>,
+      H: Hasher + Clone {
+
+    /// Borrows the element.
+    pub fn get(&self) -> &V {
+        let key = self.key;
+        self.map.table[self.bucket].borrow_where(|elem| elem.get_key() == key).unwrap()
+    }
+
+    /// Mutably borrows the element.
+    pub fn get_mut(&mut self) -> &mut V {
+        let key = self.key;
+        self.map.table[self.bucket].borrow_mut_where(|elem| elem.get_key() == key).unwrap()
+    }
+
+    /// Mutably borrows the element for as long as the map was borrowed.
+    pub fn into_mut(self) -> &'a mut V {
+        let (map, key) = (self.map, self.key);
+        map.table[self.bucket].borrow_mut_where(|elem| elem.get_key() == key).unwrap()
+    }
+
+    /// Removes the element from the map.
+    pub fn remove(self) -> Box<V> {
+        let (map, key) = (self.map, self.key);
+        let res = map.table[self.bucket].remove_where(|elem| elem.get_key() == key).unwrap();
+        map.count -= 1;
+        res
+    }
+
+}
+
+impl<'a, K: ?Sized, V: ?Sized, H> VacantEntry<'a, K, V, H>
+where K: Eq + Hash,
+      V: HasKey<K> + HasSingleLink<V>,
+      H: Hasher + Clone {
+
+    /// Inserts `val` into the map and mutably borrows it.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the key of `val` is not the key of the entry.
+    pub fn insert(self, val: Box<V>) -> &'a mut V {
+        assert!(val.get_key() == self.key);
+        let map = self.map;
+        map.grow();
+        let bucket = map.bucket(val.get_key());
+        map.count += 1;
+        map.table[bucket].push(val);
+        map.table[bucket].iter_mut().next().unwrap()
+    }
+
+}
+
 pub struct ValueIter<'a, K: ?Sized, V: ?Sized> 
 where K: Eq + Hash + 'a, 
       V: HasKey<K> + HasSingleLink<V> + 'a {
@@ -177,11 +381,28 @@ where K: Eq + Hash + 'a,
     }
 }
 
+pub struct Iter<'a, K: ?Sized, V: ?Sized>
+where K: Eq + Hash + 'a,
+      V: HasKey<K> + HasSingleLink<V> + 'a {
+    value_iter: ValueIter<'a, K, V>
+}
+
+impl<'a, K: ?Sized, V: ?Sized> Iterator for Iter<'a, K, V>
+where K: Eq + Hash + 'a,
+      V: HasKey<K> + HasSingleLink<V> + 'a {
+    type Item = (&'a K, &'a V);
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        self.value_iter.next().map(|v| (v.get_key(), v))
+    }
+}
+
 /// See https://en.wikipedia.org/wiki/Fowler-Noll-Vo_hash_function
 const FNV_BASE: u64 = 0xcbf29ce484222325;
 const FNV_PRIME: u64 = 0x100000001b3;
 
-struct FNVHasher {
+/// The default hasher of maps.
+#[derive(Clone)]
+pub struct FNVHasher {
     accum: Wrapping<u64>
 }
 
@@ -191,6 +412,12 @@ impl FNVHasher {
     }
 }
 
+impl Default for FNVHasher {
+    fn default() -> FNVHasher {
+        FNVHasher::new()
+    }
+}
+
 impl Hasher for FNVHasher {
     fn finish(&self) -> u64 {
         self.accum.0
@@ -203,4 +430,3 @@ impl Hasher for FNVHasher {
         }
     }
 }
-
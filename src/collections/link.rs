@@ -3,7 +3,8 @@
 //! various collections.
 //! 
 //! The `SingleLink` object owns the pointer to the next object. The `DoubleLink` object adds an
-//! unsafe back pointer to the `SingleLink`.
+//! unsafe back pointer to the `SingleLink`. The `TreeLink` object owns the pointers to both
+//! children of a tree node and has an unsafe back pointer to its parent.
 //!
 use alloc::boxed::Box;
 use core::prelude::*;
@@ -34,6 +35,24 @@ impl<T: ?Sized> DoubleLink<T> {
     }
 }
 
+pub struct TreeLink<T: ?Sized> {
+    pub left: Option<Box<T>>,
+    pub right: Option<Box<T>>,
+    pub parent: Option<Raw<T>>,
+    pub height: usize,
+}
+
+impl<T: ?Sized> TreeLink<T> {
+    pub const fn new() -> TreeLink<T> {
+        TreeLink {
+            left: None,
+            right: None,
+            parent: None,
+            height: 0,
+        }
+    }
+}
+
 pub trait HasSingleLink<T: ?Sized> {
     fn slink(&self) -> &SingleLink<T>;
     fn slink_mut(&mut self) -> &mut SingleLink<T>;
@@ -44,6 +63,11 @@ pub trait HasDoubleLink<T: ?Sized> {
     fn dlink_mut(&mut self) -> &mut DoubleLink<T>;
 }
 
+pub trait HasTreeLink<T: ?Sized> {
+    fn tlink(&self) -> &TreeLink<T>;
+    fn tlink_mut(&mut self) -> &mut TreeLink<T>;
+}
+
 impl<T: ?Sized> Default for SingleLink<T> {
     fn default() -> SingleLink<T> {
         SingleLink::new()
@@ -56,6 +80,12 @@ impl<T: ?Sized> Default for DoubleLink<T> {
     }
 }
 
+impl<T: ?Sized> Default for TreeLink<T> {
+    fn default() -> TreeLink<T> {
+        TreeLink::new()
+    }
+}
+
 /// Any type that as a double link also has a single link.
 impl<T: HasDoubleLink<T> + ?Sized> HasSingleLink<T> for T {
     fn slink(&self) -> &SingleLink<T> {
@@ -86,4 +116,13 @@ impl<T> fmt::Debug for DoubleLink<T> {
     }
 }
 
+impl<T> fmt::Debug for TreeLink<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.left.is_none() && self.right.is_none() && self.parent.is_none() {
+            write!(f, "EmptyLink")
+        } else {
+            write!(f, "FullLink")
+        }
+    }
+}
 
@@ -34,6 +34,9 @@ pub mod slist;
 /// An doubly linked list.
 pub mod dlist;
 
+/// An ordered map.
+pub mod tree;
+
 /// A separately-chained hash map.
 pub mod hashmap;
 
@@ -3,6 +3,7 @@ use core::{fmt, str};
 use core::hash::{Hash, Hasher};
 use core::cmp::max;
 use super::vec::Vec;
+use alloc::cow::TryClone;
 use util::KernResult;
 
 /// A dynamically growable string. If the string is never modified there is no extra overhead. 
@@ -118,6 +119,12 @@ impl String {
 
 }
 
+impl TryClone for String {
+    fn try_clone(&self) -> KernResult<String> {
+        self.clone()
+    }
+}
+
 impl PartialEq<String> for String {
     fn eq(&self, other: &String) -> bool {
         self.as_str() == other.as_str()
@@ -0,0 +1,447 @@
+//!
+//! This module contains the definition of an ordered map that uses embedded nodes.
+//!
+//! The map is an AVL tree. Every node owns its children and has a back pointer to its parent so
+//! the map can be walked in order without a stack. Since the nodes are embedded in the values,
+//! none of the operations allocate and none of them can fail.
+//!
+//! Values are ordered by the key they return through `HasKey`. Besides lookups by key, the map can
+//! find the values closest to a key, which makes questions like "which region contains this
+//! address" (`floor`) or "what happens next" (`first`) cheap.
+//!
+use alloc::boxed::Box;
+use core::prelude::*;
+use core::cmp::{self, Ordering};
+use core::marker;
+use hashmap::HasKey;
+use link::HasTreeLink;
+use raw::Raw;
+
+/// An ordered map.
+pub struct Tree<K, V> where K: Ord, V: HasKey<K> + HasTreeLink<V> {
+    len: usize,
+    root: Option<Box<V>>,
+    _marker: marker::PhantomData<K>,
+}
+
+impl<K, V> Tree<K, V> where K: Ord, V: HasKey<K> + HasTreeLink<V> {
+
+    /// Creates a new empty map.
+    pub const fn new() -> Tree<K, V> {
+        Tree {
+            len: 0,
+            root: None,
+            _marker: marker::PhantomData,
+        }
+    }
+
+    /// Returns the number of elements in the map.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the height of the tree. This is at most about 1.44 times the base 2 logarithm of
+    /// the number of elements.
+    pub fn height(&self) -> usize {
+        height(&self.root)
+    }
+
+    /// Inserts a new element into the map and returns the evicted element if there was one.
+    pub fn insert(&mut self, mut val: Box<V>) -> Option<Box<V>> {
+        assert!(val.tlink().left.is_none() && val.tlink().right.is_none());
+        assert!(val.tlink().parent.is_none());
+        val.tlink_mut().height = 1;
+        let res = insert_at(&mut self.root, None, val);
+        if res.is_none() {
+            self.len += 1;
+        }
+        res
+    }
+
+    /// Tries to remove the element with the given key.
+    pub fn remove(&mut self, key: &K) -> Option<Box<V>> {
+        let res = remove_at(&mut self.root, key);
+        if res.is_some() {
+            self.len -= 1;
+        }
+        res
+    }
+
+    /// Tries to remove the element with the smallest key.
+    pub fn pop_first(&mut self) -> Option<Box<V>> {
+        if self.root.is_none() {
+            return None;
+        }
+        self.len -= 1;
+        Some(remove_min(&mut self.root))
+    }
+
+    /// Returns whether or not an element with the given key is in the map.
+    pub fn contains(&self, key: &K) -> bool {
+        self.lookup(key).is_some()
+    }
+
+    /// Tries to borrow the element with the given key.
+    pub fn lookup(&self, key: &K) -> Option<&V> {
+        let mut cur = self.root.as_ref();
+        while let Some(node) = cur {
+            cur = match key.cmp(node.get_key()) {
+                Ordering::Less => node.tlink().left.as_ref(),
+                Ordering::Greater => node.tlink().right.as_ref(),
+                Ordering::Equal => return Some(&**node),
+            };
+        }
+        None
+    }
+
+    /// Tries to mutably borrow the element with the given key. Its key must not be changed.
+    pub fn lookup_mut(&mut self, key: &K) -> Option<&mut V> {
+        lookup_mut_at(&mut self.root, key)
+    }
+
+    /// Borrows the element with the smallest key.
+    pub fn first(&self) -> Option<&V> {
+        self.root.as_ref().map(|root| leftmost(&**root))
+    }
+
+    /// Borrows the element with the largest key.
+    pub fn last(&self) -> Option<&V> {
+        self.root.as_ref().map(|root| {
+            let mut cur = &**root;
+            while let Some(ref right) = cur.tlink().right {
+                cur = &**right;
+            }
+            cur
+        })
+    }
+
+    /// Borrows the first element whose key is not less than `key`.
+    pub fn lower_bound(&self, key: &K) -> Option<&V> {
+        self.search(|node| node >= key)
+    }
+
+    /// Borrows the first element whose key is greater than `key`.
+    pub fn upper_bound(&self, key: &K) -> Option<&V> {
+        self.search(|node| node > key)
+    }
+
+    /// Borrows the last element whose key is not greater than `key`. If elements are the starts of
+    /// ranges, this is the one that would contain `key`.
+    pub fn floor(&self, key: &K) -> Option<&V> {
+        let mut cur = self.root.as_ref();
+        let mut res = None;
+        while let Some(node) = cur {
+            if node.get_key() <= key {
+                res = Some(&**node);
+                cur = node.tlink().right.as_ref();
+            } else {
+                cur = node.tlink().left.as_ref();
+            }
+        }
+        res
+    }
+
+    // Borrows the first element for which `after` holds. `after` must hold for every element after
+    // one it holds for.
+    fn search<F>(&self, after: F) -> Option<&V> where F: Fn(&K) -> bool {
+        let mut cur = self.root.as_ref();
+        let mut res = None;
+        while let Some(node) = cur {
+            if after(node.get_key()) {
+                res = Some(&**node);
+                cur = node.tlink().left.as_ref();
+            } else {
+                cur = node.tlink().right.as_ref();
+            }
+        }
+        res
+    }
+
+    /// Iterates over the elements in order of their keys.
+    pub fn iter(&self) -> Iter<K, V> {
+        Iter { next: self.first(), end: None }
+    }
+
+    /// Iterates over the elements whose keys are not less than `start` and less than `end` in
+    /// order of their keys.
+    pub fn range<'a>(&'a self, start: &K, end: &'a K) -> Iter<'a, K, V> {
+        Iter { next: self.lower_bound(start), end: Some(end) }
+    }
+
+}
+
+impl<K, V> Default for Tree<K, V> where K: Ord, V: HasKey<K> + HasTreeLink<V> {
+    fn default() -> Tree<K, V> {
+        Tree::new()
+    }
+}
+
+impl<'a, K, V> IntoIterator for &'a Tree<K, V> where K: Ord, V: HasKey<K> + HasTreeLink<V> {
+    type Item = &'a V;
+    type IntoIter = Iter<'a, K, V>;
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+/// A borrowing iterator.
+pub struct Iter<'a, K: 'a, V: 'a> where K: Ord, V: HasKey<K> + HasTreeLink<V> {
+    next: Option<&'a V>,
+    end: Option<&'a K>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> where K: Ord, V: HasKey<K> + HasTreeLink<V> {
+    type Item = &'a V;
+    fn next(&mut self) -> Option<&'a V> {
+        let node = match self.next {
+            Some(node) => node,
+            None => return None,
+        };
+        if self.end.map_or(false, |end| node.get_key() >= end) {
+            self.next = None;
+            return None;
+        }
+        self.next = successor(node);
+        Some(node)
+    }
+}
+
+fn height<V: HasTreeLink<V>>(node: &Option<Box<V>>) -> usize {
+    node.as_ref().map_or(0, |node| node.tlink().height)
+}
+
+fn set_parent<V: HasTreeLink<V>>(node: &mut Option<Box<V>>, parent: Option<Raw<V>>) {
+    if let Some(ref mut node) = *node {
+        node.tlink_mut().parent = parent;
+    }
+}
+
+fn update_height<V: HasTreeLink<V>>(node: &mut V) {
+    let height = 1 + cmp::max(height(&node.tlink().left), height(&node.tlink().right));
+    node.tlink_mut().height = height;
+}
+
+fn leftmost<V: HasTreeLink<V>>(node: &V) -> &V {
+    let mut cur = node;
+    while let Some(ref left) = cur.tlink().left {
+        cur = &**left;
+    }
+    cur
+}
+
+// Returns the element after `node` in order.
+fn successor<'a, V: HasTreeLink<V>>(node: &'a V) -> Option<&'a V> {
+    if let Some(ref right) = node.tlink().right {
+        return Some(leftmost(&**right));
+    }
+    // Go up until we come from a left child.
+    let mut cur = node;
+    loop {
+        // We know this is safe because parents outlive their children.
+        let parent: &'a V = match cur.tlink().parent {
+            Some(ref parent) => unsafe { parent.as_ref() },
+            None => return None,
+        };
+        let from_left = parent.tlink().left.as_ref()
+                              .map_or(false, |left| &**left as *const V == cur as *const V);
+        if from_left {
+            return Some(parent);
+        }
+        cur = parent;
+    }
+}
+
+// Rotates the subtree in `slot` to the right, so that its left child becomes its root.
+fn rotate_right<V: HasTreeLink<V>>(slot: &mut Option<Box<V>>) {
+    let mut top = slot.take().unwrap();
+    let mut left = top.tlink_mut().left.take().unwrap();
+    let top_raw = unsafe { Raw::from_box(&mut top) };
+    let left_raw = unsafe { Raw::from_box(&mut left) };
+
+    // The right subtree of the left child moves over to the old root.
+    let mut inner = left.tlink_mut().right.take();
+    set_parent(&mut inner, Some(top_raw));
+    top.tlink_mut().left = inner;
+    left.tlink_mut().parent = top.tlink_mut().parent.take();
+    top.tlink_mut().parent = Some(left_raw);
+    update_height(&mut *top);
+    left.tlink_mut().right = Some(top);
+    update_height(&mut *left);
+    *slot = Some(left);
+}
+
+// Rotates the subtree in `slot` to the left, so that its right child becomes its root.
+fn rotate_left<V: HasTreeLink<V>>(slot: &mut Option<Box<V>>) {
+    let mut top = slot.take().unwrap();
+    let mut right = top.tlink_mut().right.take().unwrap();
+    let top_raw = unsafe { Raw::from_box(&mut top) };
+    let right_raw = unsafe { Raw::from_box(&mut right) };
+
+    // The left subtree of the right child moves over to the old root.
+    let mut inner = right.tlink_mut().left.take();
+    set_parent(&mut inner, Some(top_raw));
+    top.tlink_mut().right = inner;
+    right.tlink_mut().parent = top.tlink_mut().parent.take();
+    top.tlink_mut().parent = Some(right_raw);
+    update_height(&mut *top);
+    right.tlink_mut().left = Some(top);
+    update_height(&mut *right);
+    *slot = Some(right);
+}
+
+// Restores the balance of the subtree in `slot` after one of its subtrees changed height by one.
+fn rebalance<V: HasTreeLink<V>>(slot: &mut Option<Box<V>>) {
+    let (left, right) = match *slot {
+        Some(ref node) => (height(&node.tlink().left), height(&node.tlink().right)),
+        None => return,
+    };
+    if left > right + 1 {
+        {
+            let child = &mut slot.as_mut().unwrap().tlink_mut().left;
+            let heavy_inside = child.as_ref().map_or(false, |child| {
+                height(&child.tlink().right) > height(&child.tlink().left)
+            });
+            if heavy_inside {
+                rotate_left(child);
+            }
+        }
+        rotate_right(slot);
+    } else if right > left + 1 {
+        {
+            let child = &mut slot.as_mut().unwrap().tlink_mut().right;
+            let heavy_inside = child.as_ref().map_or(false, |child| {
+                height(&child.tlink().left) > height(&child.tlink().right)
+            });
+            if heavy_inside {
+                rotate_right(child);
+            }
+        }
+        rotate_left(slot);
+    } else {
+        update_height(&mut **slot.as_mut().unwrap());
+    }
+}
+
+// Inserts `val` into the subtree in `slot`, whose parent is `parent`, and returns the element it
+// replaced if there was one.
+fn insert_at<K, V>(slot: &mut Option<Box<V>>, parent: Option<Raw<V>>, mut val: Box<V>)
+    -> Option<Box<V>> where K: Ord, V: HasKey<K> + HasTreeLink<V> {
+    if slot.is_none() {
+        val.tlink_mut().parent = parent;
+        *slot = Some(val);
+        return None;
+    }
+    let ord = val.get_key().cmp(slot.as_ref().unwrap().get_key());
+    if ord == Ordering::Equal {
+        return Some(replace(slot, val));
+    }
+    let res = {
+        let node = slot.as_mut().unwrap();
+        let raw = unsafe { Raw::from_box(node) };
+        if ord == Ordering::Less {
+            insert_at(&mut node.tlink_mut().left, Some(raw), val)
+        } else {
+            insert_at(&mut node.tlink_mut().right, Some(raw), val)
+        }
+    };
+    if res.is_none() {
+        rebalance(slot);
+    }
+    res
+}
+
+// Puts `val` in the place of the element in `slot` and returns that element.
+fn replace<V: HasTreeLink<V>>(slot: &mut Option<Box<V>>, mut val: Box<V>) -> Box<V> {
+    let mut old = slot.take().unwrap();
+    let raw = unsafe { Raw::from_box(&mut val) };
+    let mut left = old.tlink_mut().left.take();
+    let mut right = old.tlink_mut().right.take();
+    set_parent(&mut left, Some(raw.clone()));
+    set_parent(&mut right, Some(raw));
+    val.tlink_mut().left = left;
+    val.tlink_mut().right = right;
+    val.tlink_mut().parent = old.tlink_mut().parent.take();
+    val.tlink_mut().height = old.tlink().height;
+    old.tlink_mut().height = 0;
+    *slot = Some(val);
+    old
+}
+
+fn lookup_mut_at<'a, K, V>(slot: &'a mut Option<Box<V>>, key: &K) -> Option<&'a mut V>
+    where K: Ord, V: HasKey<K> + HasTreeLink<V> {
+    match *slot {
+        Some(ref mut node) => {
+            let ord = key.cmp(node.get_key());
+            match ord {
+                Ordering::Less => lookup_mut_at(&mut node.tlink_mut().left, key),
+                Ordering::Greater => lookup_mut_at(&mut node.tlink_mut().right, key),
+                Ordering::Equal => Some(&mut **node),
+            }
+        }
+        None => None,
+    }
+}
+
+// Removes the element with the given key from the subtree in `slot`.
+fn remove_at<K, V>(slot: &mut Option<Box<V>>, key: &K) -> Option<Box<V>>
+    where K: Ord, V: HasKey<K> + HasTreeLink<V> {
+    let ord = match *slot {
+        Some(ref node) => key.cmp(node.get_key()),
+        None => return None,
+    };
+    let res = match ord {
+        Ordering::Less => remove_at(&mut slot.as_mut().unwrap().tlink_mut().left, key),
+        Ordering::Greater => remove_at(&mut slot.as_mut().unwrap().tlink_mut().right, key),
+        Ordering::Equal => Some(unlink(slot)),
+    };
+    if res.is_some() {
+        rebalance(slot);
+    }
+    res
+}
+
+// Removes the element with the smallest key from the non-empty subtree in `slot`.
+fn remove_min<V: HasTreeLink<V>>(slot: &mut Option<Box<V>>) -> Box<V> {
+    if slot.as_ref().unwrap().tlink().left.is_some() {
+        let res = remove_min(&mut slot.as_mut().unwrap().tlink_mut().left);
+        rebalance(slot);
+        return res;
+    }
+    let mut node = slot.take().unwrap();
+    let mut right = node.tlink_mut().right.take();
+    set_parent(&mut right, node.tlink_mut().parent.take());
+    node.tlink_mut().height = 0;
+    *slot = right;
+    node
+}
+
+// Removes the root of the subtree in `slot`. The subtree still has to be rebalanced.
+fn unlink<V: HasTreeLink<V>>(slot: &mut Option<Box<V>>) -> Box<V> {
+    let mut node = slot.take().unwrap();
+    let parent = node.tlink_mut().parent.take();
+    let mut left = node.tlink_mut().left.take();
+    let mut right = node.tlink_mut().right.take();
+    node.tlink_mut().height = 0;
+    if left.is_none() {
+        set_parent(&mut right, parent);
+        *slot = right;
+    } else if right.is_none() {
+        set_parent(&mut left, parent);
+        *slot = left;
+    } else {
+        // The smallest element on the right takes the place of the node.
+        let mut next = remove_min(&mut right);
+        let raw = unsafe { Raw::from_box(&mut next) };
+        set_parent(&mut left, Some(raw.clone()));
+        set_parent(&mut right, Some(raw));
+        next.tlink_mut().left = left;
+        next.tlink_mut().right = right;
+        next.tlink_mut().parent = parent;
+        *slot = Some(next);
+    }
+    node
+}
@@ -7,27 +7,36 @@ use core::{ptr, mem, marker};
 use core::slice;
 use core::cmp::max;
 use core::intrinsics::{drop_in_place, copy_nonoverlapping};
-use alloc::{allocate_raw, deallocate_raw, reallocate_raw};
+use alloc::{allocate_raw_flags, deallocate_raw, reallocate_raw_flags, AllocFlags, ALLOC_BLOCK};
+use alloc::cow::TryClone;
 use util::{KernResult, KernResultEx};
 
 /// A growable vector.
 pub struct Vec<T> {
     raw: *mut T,
     cap: usize,
-    len: usize
+    len: usize,
+    flags: AllocFlags,
 }
 
 impl<T> Vec<T> {
     
     /// Creates a new vector with the given capacity.
     pub fn new(capacity: usize) -> KernResult<Vec<T>> {
+        Vec::new_flags(capacity, ALLOC_BLOCK)
+    }
+
+    /// Creates a new vector with the given capacity whose memory is allocated as directed by
+    /// `flags`, now and whenever it grows.
+    pub fn new_flags(capacity: usize, flags: AllocFlags) -> KernResult<Vec<T>> {
         let size = capacity * mem::size_of::<T>();
         let align = mem::min_align_of::<T>();
-        let addr = try!(allocate_raw(size, align));
+        let addr = try!(allocate_raw_flags(size, align, flags));
         Ok(Vec {
             raw: addr as *mut T,
             cap: capacity,
-            len: 0
+            len: 0,
+            flags: flags,
         })
     }
 
@@ -35,11 +44,12 @@ impl<T> Vec<T> {
     pub fn clone(&self) -> KernResult<Vec<T>> where T: Clone {
         let size = self.len * mem::size_of::<T>();
         let align = mem::min_align_of::<T>();
-        let addr = try!(allocate_raw(size, align));
+        let addr = try!(allocate_raw_flags(size, align, ALLOC_BLOCK));
         let vec = Vec {
             raw: addr as *mut T,
             cap: self.len,
             len: self.len,
+            flags: ALLOC_BLOCK,
         };
         for i in 0..self.len {
             // Perform a ptr::write so we don't try to drop the contents of the destination.
@@ -60,7 +70,7 @@ impl<T> Vec<T> {
         let old_size = old_cap * mem::size_of::<T>();
         let new_size = new_cap * mem::size_of::<T>();
         let align = mem::min_align_of::<T>();
-        let new_addr = try!(reallocate_raw(old_addr, old_size, new_size, align));
+        let new_addr = try!(reallocate_raw_flags(old_addr, old_size, new_size, align, self.flags));
         self.raw = new_addr as *mut T;
         self.cap = new_cap;
         Ok(())
@@ -110,6 +120,45 @@ impl<T> Vec<T> {
         }
     }
 
+    /// Attempts to insert an element at position `idx`, shifting all elements after it to the
+    /// right. If the vector cannot allocate enough space to grow it returns Err(val), otherwise it
+    /// returns Ok(()).
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `idx` is greater than the length of the vector.
+    pub fn insert(&mut self, idx: usize, val: T) -> KernResultEx<(), T> {
+        assert!(idx <= self.len);
+        if self.len == self.cap {
+            let new_cap = max(self.cap, 1) * 2;
+            try!(self.resize(new_cap), val);
+        }
+        unsafe {
+            let slot = self.raw.offset(idx as isize);
+            ptr::copy(slot, slot.offset(1), self.len - idx);
+            ptr::write(slot, val);
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Removes and returns the element at position `idx`, shifting all elements after it to the
+    /// left.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `idx` is out of bounds.
+    pub fn remove(&mut self, idx: usize) -> T {
+        assert!(idx < self.len);
+        unsafe {
+            let slot = self.raw.offset(idx as isize);
+            let res = ptr::read(slot);
+            ptr::copy(slot.offset(1), slot, self.len - idx - 1);
+            self.len -= 1;
+            res
+        }
+    }
+
     /// Attempts to pop an element from the vector. If the vector is empty, returns None, otherwise
     /// returns Some(elem).
     pub fn pop(&mut self) -> Option<T>  {
@@ -127,7 +176,7 @@ impl<T> Vec<T> {
             Vec::new(0)
         } else {
             let new_count = self.len - idx;
-            let mut new = try!(Vec::new(new_count));
+            let mut new = try!(Vec::new_flags(new_count, self.flags));
             new.len = new_count;
             unsafe { 
                 copy_nonoverlapping(self.raw.offset(idx as isize), new.raw, new_count);
@@ -156,6 +205,12 @@ impl<T> Vec<T> {
     }
 }
 
+impl<T: Clone> TryClone for Vec<T> {
+    fn try_clone(&self) -> KernResult<Vec<T>> {
+        self.clone()
+    }
+}
+
 impl<T> Drop for Vec<T> {
     fn drop(&mut self) {
         if self.len != mem::POST_DROP_USIZE {
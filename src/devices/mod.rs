@@ -34,6 +34,7 @@ mod pci;
 
 use alloc::boxed::Box;
 use alloc::rc::{Rc, HasRc};
+use alloc::slab::Cache;
 use core::atomic::AtomicUsize;
 use core::prelude::*;
 use core::ops::Index;
@@ -211,13 +212,12 @@ impl DeviceManager {
         
         // Insert the device into the devices map.
         let rc = Rc::new(device);
-        if self.devices_map.contains(&class) {
-            let vec = self.devices_map.lookup_mut(&class).unwrap();
-            try!(vec.push(rc.clone()))
-        } else {
-            let mut vec = try!(Vec::new(4).map(Linked::new).and_then(Box::new));
+        {
+            let vec = try!(self.devices_map.entry(&class).or_insert_with(|| {
+                let vec = try!(Vec::new(4).map(Linked::new));
+                Box::new_in(vec, &DEVICE_LIST_CACHE)
+            }));
             try!(vec.push(rc.clone()));
-            self.devices_map.insert(vec);
         }
 
         // Get the device name.
@@ -244,6 +244,8 @@ impl DeviceManager {
 
 static CTX: Global<DeviceManager> = Global::new();
 
+static DEVICE_LIST_CACHE: Cache<Linked<Vec<Rc<Device>>>> = Cache::new("device_list");
+
 pub fn init() {
     // Construct a directory for the device file system.
     let mut root = fs::root_cursor();
@@ -16,6 +16,7 @@ use alloc::boxed::Box;
 use alloc::rc::{Rc, HasRc, RcAny};
 use core::prelude::*;
 use core::any::Any;
+use core::fmt;
 use util::global::Global;
 use collections::string::String;
 use self::vfs::VFS;
@@ -27,6 +28,10 @@ logger_init!(Trace);
 pub const PATH_SEP: &'static str = "/";
 pub const PARENT_DIR: &'static str = "..";
 
+/// Produces the contents of a generated file. It is called every time the file is opened so the
+/// contents are always current.
+pub type Generator = fn(&mut String) -> fmt::Result;
+
 pub trait Node : HasRc {
     
     fn count(&self) -> usize;
@@ -35,6 +40,8 @@ pub trait Node : HasRc {
 
     fn make_file(&self, file: String) -> KernResult<()>;
 
+    fn make_generated_file(&self, file: String, gen: Generator) -> KernResult<()>;
+
     fn make_node(&self, node: String) -> KernResult<()>; 
 
     fn make_object(&self, name: String, obj: Rc<RcAny>) -> KernResult<()>;
@@ -107,6 +114,21 @@ impl FileCursor {
         self.node.remove_node(name)
     }
 
+    pub fn make_generated_file(&self, name: String, gen: Generator) -> KernResult<()> {
+        trace!("making generated file {} at {}", name, self.curdir);
+        self.node.make_generated_file(name, gen)
+    }
+
+    pub fn open_file(&self, name: &str) -> KernResult<Box<File>> {
+        trace!("opening file {} at {}", name, self.curdir);
+        self.node.open_file(name)
+    }
+
+    pub fn remove_file(&self, name: &str) -> KernResult<()> {
+        trace!("removing file {} at {}", name, self.curdir);
+        self.node.remove_file(name)
+    }
+
     pub fn make_object<T: Any + HasRc>(&self, name: String, obj: Rc<T>) -> KernResult<()> {
         trace!("making object {} at {}", name, self.curdir);
         self.node.make_object(name, obj)
@@ -1,6 +1,7 @@
 use core::prelude::*;
 use core::{str, mem, fmt};
 use collections::string::String;
+use alloc::cow::TryClone;
 use util::KernResult;
 use super::PATH_SEP;
 
@@ -87,6 +88,12 @@ impl Path {
 
 }
 
+impl TryClone for Path {
+    fn try_clone(&self) -> KernResult<Path> {
+        self.clone()
+    }
+}
+
 impl fmt::Display for Path {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.path)
@@ -4,13 +4,14 @@
 //! Strings to VFSEntries. Synchronization is performed at each directory using a reader-writer
 //! lock.
 //! 
-//! Every directory (VFSNode) additionally contains a reference counted pointer to its parent.
-//! While this introduces a cycle in reference-counted graph, this is ok because we will manually
-//! tear down the graph when removing directories. It is illegal to remove a directory unless it is
-//! empty and thus there will be no backreferences to it when it is removed.
+//! Every directory (VFSNode) additionally contains a weak pointer to its parent. Children don't
+//! keep their parent alive, so dropping a directory tree frees it without having to tear it down
+//! first. It is still illegal to remove a directory unless it is empty.
 //!
 use alloc::boxed::Box;
-use alloc::rc::{Rc, HasRc, RcAny};
+use alloc::rc::{Rc, Weak, HasRc, RcAny};
+use alloc::slab::Cache;
+use alloc::cow::Cow;
 use core::prelude::*;
 use core::atomic::AtomicUsize;
 use collections::hashmap::{HashMap, HasKey, KeyIter};
@@ -18,7 +19,7 @@ use collections::dynarray::DynArray;
 use collections::link::{HasDoubleLink, DoubleLink};
 use collections::string::String;
 use sync::rwlock::{ReaderGuard, WriterGuard, ReaderGuardMap, RWLock};
-use super::{Node, File, FileSystem};
+use super::{Node, File, FileSystem, Generator};
 use util::KernResult;
 use util::KernError::*;
 use super::PARENT_DIR;
@@ -44,7 +45,7 @@ impl FileSystem for VFS {
     fn set_parent(&mut self, parent: Option<Rc<Node>>) {
         let mut state = self.root.state.lock_writer();
         state.parent = match parent {
-            Some(rc) => VFSParent::Linked(rc),
+            Some(rc) => VFSParent::Linked(rc.downgrade()),
             None => VFSParent::Root,
         };
     }
@@ -63,8 +64,8 @@ impl<'a> Iterator for VFSFileIter<'a> {
 
 /// Possible states for a VFSNode's parent.
 enum VFSParent {
-    /// The parent is alive and at the contained node.
-    Linked(Rc<Node>),
+    /// The parent is at the contained node, as long as it is alive.
+    Linked(Weak<Node>),
 
     /// The current node was removed from the parent.
     Unlinked,
@@ -80,6 +81,7 @@ struct VFSNodeState {
 
 struct VFSNode {
     rc: AtomicUsize,
+    weak: AtomicUsize,
     state: RWLock<VFSNodeState>,
 }
 
@@ -88,6 +90,7 @@ impl VFSNode {
         let map = try!(HashMap::new());
         Ok(VFSNode {
             rc: AtomicUsize::new(0),
+            weak: AtomicUsize::new(0),
             state: RWLock::new(VFSNodeState {
                 parent: parent,
                 entries: map,
@@ -122,6 +125,9 @@ impl HasRc for VFSNode {
     fn get_count(&self) -> &AtomicUsize {
         &self.rc
     }
+    fn get_weak_count(&self) -> Option<&AtomicUsize> {
+        Some(&self.weak)
+    }
 }
 
 impl Node for VFSNode {
@@ -147,6 +153,13 @@ impl Node for VFSNode {
                 let boxed = try!(Box::new(clone));
                 Ok(boxed)
             }
+            Some(&VFSEntry::Generated { gen, .. }) => {
+                let mut contents = String::new();
+                try!(gen(&mut contents));
+                let file = try!(VFSFile::from_str(contents.as_str()));
+                let boxed = try!(Box::new(file));
+                Ok(boxed)
+            }
             _ => Err(NoSuchFile)
         }
         
@@ -166,7 +179,7 @@ impl Node for VFSNode {
         if node == PARENT_DIR {
             // The parent directory is contained in the parent field.
             match state.parent {
-                VFSParent::Linked(ref parent) => Ok(parent.clone()),
+                VFSParent::Linked(ref parent) => parent.upgrade().ok_or(NoSuchDirectory),
                 VFSParent::Root => Err(NoSuchDirectory),
                 VFSParent::Unlinked => unreachable!(),
             }
@@ -191,7 +204,20 @@ impl Node for VFSNode {
         } else {
             let file = try!(VFSFile::new());
             let entry = VFSEntry::File { name: name, file: file, link: DoubleLink::new() };
-            let entry = try!(Box::new(entry));
+            let entry = try!(Box::new_in(entry, &ENTRY_CACHE));
+            assert!(state.entries.insert(entry).is_none());
+            Ok(())
+        }
+    }
+
+    fn make_generated_file(&self, name: String, gen: Generator) -> KernResult<()> {
+        trace!("making generated file '{}'", name);
+        let mut state = try!(self.checked_lock_writer());
+        if state.entries.contains(name.as_str()) {
+            Err(FileExists)
+        } else {
+            let entry = VFSEntry::Generated { name: name, gen: gen, link: DoubleLink::new() };
+            let entry = try!(Box::new_in(entry, &ENTRY_CACHE));
             assert!(state.entries.insert(entry).is_none());
             Ok(())
         }
@@ -203,7 +229,7 @@ impl Node for VFSNode {
             Err(ObjectExists)
         } else {
             let entry = VFSEntry::Object { name: name, obj: obj, link: DoubleLink::new() };
-            let entry = try!(Box::new(entry));
+            let entry = try!(Box::new_in(entry, &ENTRY_CACHE));
             assert!(state.entries.insert(entry).is_none());
             Ok(())
         }
@@ -216,10 +242,10 @@ impl Node for VFSNode {
             trace!("failed, {} already exists", name);
             Err(DirectoryExists)
         } else {
-            let parent = VFSParent::Linked(Rc::from_ref(self));
+            let parent = VFSParent::Linked(Weak::from_ref(self));
             let node = try!(VFSNode::new(parent).and_then(Box::new).map(Rc::new));
             let entry = VFSEntry::Node { name: name, node: node, link: DoubleLink::new() };
-            let entry = try!(Box::new(entry));
+            let entry = try!(Box::new_in(entry, &ENTRY_CACHE));
             assert!(state.entries.insert(entry).is_none());
             Ok(())
         }
@@ -229,7 +255,7 @@ impl Node for VFSNode {
         trace!("removing file: '{}'", name);
         let mut state = try!(self.checked_lock_writer());
         match state.entries.lookup(name) {
-            Some(&VFSEntry::File { .. }) => { },
+            Some(&VFSEntry::File { .. }) | Some(&VFSEntry::Generated { .. }) => { },
             _ => return Err(NoSuchFile),
         }
         state.entries.remove(name);
@@ -267,7 +293,7 @@ impl Node for VFSNode {
             Err(DirectoryExists)
         } else {
             let entry = VFSEntry::Mount { name: name, fs: fs, link: DoubleLink::new() };
-            let entry = try!(Box::new(entry));
+            let entry = try!(Box::new_in(entry, &ENTRY_CACHE));
             assert!(state.entries.insert(entry).is_none());
             Ok(())
         }
@@ -286,9 +312,12 @@ impl Node for VFSNode {
 }
 
 
+static ENTRY_CACHE: Cache<VFSEntry> = Cache::new("vfs_entry");
+
 enum VFSEntry {
     Node { name: String, node: Rc<VFSNode>, link: DoubleLink<VFSEntry> },
     File { name: String, file: VFSFile, link: DoubleLink<VFSEntry> },
+    Generated { name: String, gen: Generator, link: DoubleLink<VFSEntry> },
     Mount { name: String, fs: Box<FileSystem>, link: DoubleLink<VFSEntry> },
     Object { name: String, obj: Rc<RcAny>, link: DoubleLink<VFSEntry> },
 }
@@ -298,6 +327,7 @@ impl HasKey<str> for VFSEntry {
         match self {
             &VFSEntry::Node { ref name, .. } => name.as_str(),
             &VFSEntry::File { ref name, .. } => name.as_str(),
+            &VFSEntry::Generated { ref name, .. } => name.as_str(),
             &VFSEntry::Mount { ref name, .. } => name.as_str(),
             &VFSEntry::Object { ref name, .. } => name.as_str(),
         }
@@ -309,6 +339,7 @@ impl HasDoubleLink<VFSEntry> for VFSEntry {
         match self {
             &VFSEntry::Node { ref link, .. } => link,
             &VFSEntry::File { ref link, .. } => link,
+            &VFSEntry::Generated { ref link, .. } => link,
             &VFSEntry::Mount { ref link, .. } => link,
             &VFSEntry::Object { ref link, .. } => link,
         }
@@ -318,14 +349,17 @@ impl HasDoubleLink<VFSEntry> for VFSEntry {
         match self {
             &mut VFSEntry::Node { ref mut link, .. } => link,
             &mut VFSEntry::File { ref mut link, .. } => link,
+            &mut VFSEntry::Generated { ref mut link, .. } => link,
             &mut VFSEntry::Mount { ref mut link, .. } => link,
             &mut VFSEntry::Object { ref mut link, .. } => link,
         }
     }
 }
 
+// The contents of a file. Open files share the contents with the directory entry until one of them
+// is written to.
 struct VFSFile {
-    data: DynArray<u8>
+    data: Cow<DynArray<u8>>
 }
 
 impl VFSFile {
@@ -333,14 +367,25 @@ impl VFSFile {
     pub fn new() -> KernResult<VFSFile> {
         let dyn = try!(DynArray::new(32));
         Ok(VFSFile {
-            data: dyn
+            data: try!(Cow::new(dyn))
+        })
+    }
+
+    /// Creates a file holding a copy of `s`.
+    pub fn from_str(s: &str) -> KernResult<VFSFile> {
+        let mut dyn = try!(DynArray::new(s.len()));
+        for (i, b) in s.bytes().enumerate() {
+            dyn[i] = b;
+        }
+        Ok(VFSFile {
+            data: try!(Cow::new(dyn))
         })
     }
 
+    /// Creates a file that shares its contents with this one until either is written to.
     pub fn clone(&self) -> KernResult<VFSFile> {
-        let dynclone = try!(self.data.clone());
         Ok(VFSFile {
-            data: dynclone
+            data: self.data.clone()
         })
     }
 
@@ -361,20 +406,26 @@ impl File for VFSFile {
     }
 
     unsafe fn write(&mut self, from: usize, offset: usize, count: usize) -> usize {
+        // Get a copy of the contents if they are shared. Nothing is written if that fails.
+        let data = match self.data.make_mut() {
+            Ok(data) => data,
+            Err(_) => return 0,
+        };
+
         // Try to expand the file if needed.
-        if offset + count > self.data.len() {
+        if offset + count > data.len() {
             // We can ignore whether this succeeds or not because we will respect data.len()
             // regardless.
-            let _ = self.data.resize(offset + count);
+            let _ = data.resize(offset + count);
         }
         
         // Write the data.
         let from_ptr = from as *const u8;
         for i in 0..count {
-            if offset + i >= self.data.len() {
+            if offset + i >= data.len() {
                 return i;
             } else {
-                self.data[offset + i] = *from_ptr.offset(i as isize);
+                data[offset + i] = *from_ptr.offset(i as isize);
             }
         }
         count
@@ -24,6 +24,8 @@ pub mod timer;
 mod idt;
 
 use core::prelude::*;
+use core::fmt;
+use core::fmt::{Debug, Formatter};
 use timer::init_timer;
 use pic::init_pic;
 use idt::init_idt;
@@ -38,6 +40,7 @@ pub const OVERFLOW_IRQ: u8              = 4;
 pub const BOUND_IRQ: u8                 = 5;
 pub const INV_OPCODE_IRQ: u8            = 6;
 pub const NO_MATH_IRQ: u8               = 7;
+pub const DOUBLE_FAULT_IRQ: u8          = 8;
 pub const COPROC_OVERRUN_IRQ: u8        = 9;
 pub const INVALID_TSS_IRQ: u8           = 10;
 pub const NOT_PRESENT_IRQ: u8           = 11;
@@ -83,6 +86,14 @@ pub struct Regs {
     pub eax: u32,
 }
 
+impl Debug for Regs {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "eax: 0x{:08x} ebx: 0x{:08x} ecx: 0x{:08x} edx: 0x{:08x}\n\
+                   esi: 0x{:08x} edi: 0x{:08x} ebp: 0x{:08x}",
+               self.eax, self.ebx, self.ecx, self.edx, self.esi, self.edi, self.ebp)
+    }
+}
+
 /// Encapuslates the data pushed to stack during an interrupt. It is important to prevent users
 /// from manually accessing the ESP/SS entries since they will not be valid if the interrupt
 /// occurred in kernel land.
@@ -125,6 +136,51 @@ impl IRet {
 
 }   
 
+/// A 32-bit task state segment. When a task switch occurs the processor saves the state of the
+/// outgoing task in its task state segment. Double faults are delivered through a task gate so
+/// this is where the state of the code that caused them can be found.
+#[repr(C, packed)]
+#[allow(missing_docs)]
+pub struct TaskState {
+    pub link: u32,
+    pub esp0: u32,
+    pub ss0: u32,
+    pub esp1: u32,
+    pub ss1: u32,
+    pub esp2: u32,
+    pub ss2: u32,
+    pub cr3: u32,
+    pub eip: u32,
+    pub eflags: u32,
+    pub eax: u32,
+    pub ecx: u32,
+    pub edx: u32,
+    pub ebx: u32,
+    pub esp: u32,
+    pub ebp: u32,
+    pub esi: u32,
+    pub edi: u32,
+    pub es: u32,
+    pub cs: u32,
+    pub ss: u32,
+    pub ds: u32,
+    pub fs: u32,
+    pub gs: u32,
+    pub ldt: u32,
+    pub trap: u16,
+    pub iomap: u16,
+}
+
+impl Debug for TaskState {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "eax: 0x{:08x} ebx: 0x{:08x} ecx: 0x{:08x} edx: 0x{:08x}\n\
+                   esi: 0x{:08x} edi: 0x{:08x} ebp: 0x{:08x} esp: 0x{:08x}\n\
+                   eip: 0x{:08x} eflags: 0x{:08x} cr3: 0x{:08x}",
+               self.eax, self.ebx, self.ecx, self.edx, self.esi, self.edi, self.ebp, self.esp,
+               self.eip, self.eflags, self.cr3)
+    }
+}
+
 /// An Interrupt Service Routine. These functions take the interrupt number and mutable references
 /// to the registers and return information. This allows them to fully control where they return
 /// to.
@@ -138,6 +194,13 @@ struct IVT {
 unsafe impl Sync for IVT { }
 static mut IVT: IVT = IVT { vectors: [None; 256] };
 
+/// A double fault handler. This receives the state of the task that caused the double fault. Double
+/// faults cannot be recovered from so handlers should report the fault and must not return.
+pub type DoubleFaultHandler = fn(&TaskState);
+
+// The registered double fault handler.
+static mut DOUBLE_FAULT_HANDLER: Option<DoubleFaultHandler> = None;
+
 /// Sets an interrupt number's handler to the given ISR.
 pub fn set_isr(irq: u8, isr: ISR) {
     // We know this is safe because the only place we only assign to this table with interrupts
@@ -149,6 +212,23 @@ pub fn set_isr(irq: u8, isr: ISR) {
     }
 }
 
+/// Sets the double fault handler.
+pub fn set_double_fault_handler(handler: DoubleFaultHandler) {
+    // We know this is safe for the same reason `set_isr` is.
+    assert!(!asm::interrupts_enabled());
+    unsafe {
+        assert!(DOUBLE_FAULT_HANDLER.is_none());
+        DOUBLE_FAULT_HANDLER = Some(handler);
+    }
+}
+
+/// Sets the page directory the double fault task runs with. This must be a page directory that
+/// maps the kernel and must be set once paging is enabled.
+pub fn set_double_fault_cr3(cr3: usize) {
+    // We know this is safe because the double fault task only runs once we are about to halt.
+    unsafe { (*(linker_sym!(_df_tss) as *mut TaskState)).cr3 = cr3 as u32 };
+}
+
 /// Initializes the interrupt module. 
 pub fn init() {
     debug!("initializing interrupt");
@@ -167,3 +247,17 @@ pub extern fn rust_interrupt_dispatch (irq: u8, regs: &mut Regs, ret: &mut IRet)
         None      => panic!("unhandled interrupt {}", irq)
     };
 }
+
+/// The double fault dispatcher. This is called by the double fault task, which runs on its own
+/// stack so that double faults caused by an unusable stack can still be reported. The state of the
+/// faulting code was saved in the kernel's task state segment by the task switch. If no handler is
+/// registered or the handler returns, the double fault task halts the machine.
+#[no_mangle]
+pub extern fn rust_double_fault() {
+    // We know this is safe because the kernel's task state segment is only written by the
+    // processor and nothing else runs while we handle a double fault.
+    let state = unsafe { &*(linker_sym!(_tss) as *const TaskState) };
+    if let Some(handler) = unsafe { DOUBLE_FAULT_HANDLER } {
+        handler(state);
+    }
+}
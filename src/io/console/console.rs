@@ -1,6 +1,7 @@
 //!
 //! This module contains the definition of the `Console` and `SafeConsole` objects which permit
-//! interaction with the VGA memory area located at `0xB8000`. 
+//! interaction with the VGA memory area located at physical address `0xB8000`. It is accessed
+//! through the kernel's direct map.
 //!
 //! This console supports a 80x25 screen area and 16 different colors for both the background and
 //! foreground.
@@ -13,6 +14,7 @@ use core::str::StrExt;
 use console::color::Color;
 
 use mutex::Mutex;
+use util::KERNEL_BASE;
 
 /// A VGA console.
 pub struct Console {
@@ -39,7 +41,7 @@ pub const CONSOLE_INIT: Console = Console {
     col: 0,
     color_fg: Color::White,
     color_bg: Color::Black,
-    base: 0xB8000 as *mut u16 ,
+    base: (KERNEL_BASE + 0xB8000) as *mut u16 ,
 };
 
 /// A thread-safe VGA console.
@@ -0,0 +1,153 @@
+//!
+//! The page fault handler.
+//!
+//! Page faults are decoded into a `PageFault` describing the faulting address, the kind of access
+//! and where it came from. Faults on unmapped pages inside a virtual memory area of the active
+//! address space are resolved according to what backs the area: anonymous areas get a zeroed
+//! frame and device areas get the matching device page. Writes to copy-on-write pages are resolved
+//! by copying the page. Anything else is a bug and produces a fault report.
+//!
+//! Faults are resolved against the address space last activated with `virt::switch_to`. Faults
+//! that occur while that address space is locked can't be resolved and are reported instead of
+//! waiting for a lock that may be held by the faulting code itself.
+//!
+use core::prelude::*;
+use core::{fmt, ptr};
+use core::fmt::{Debug, Formatter};
+use interrupt::{set_isr, Regs, IRet, PAGE_FAULT_IRQ};
+use util::{page_align, PAGE_SIZE};
+use util::asm::get_cr2;
+use phys;
+use phys::Frame;
+use vma::Backing;
+use virt;
+use virt::kmap;
+logger_init!(Trace);
+
+bitflags! {
+    flags FaultFlags: u32 {
+        /// The fault was a protection violation rather than a non-present page.
+        const FAULT_PRESENT  = 0x00000001,
+        /// The fault was caused by a write.
+        const FAULT_WRITE    = 0x00000002,
+        /// The fault occurred in user mode.
+        const FAULT_USER     = 0x00000004,
+        /// A reserved bit was set in a paging structure.
+        const FAULT_RESERVED = 0x00000008,
+        /// The fault was caused by an instruction fetch.
+        const FAULT_FETCH    = 0x00000010,
+    }
+}
+
+impl Debug for FaultFlags {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let kind = if self.contains(FAULT_PRESENT) { "protection" } else { "not-present" };
+        let access = if self.contains(FAULT_WRITE) { "write" } else { "read" };
+        let mode = if self.contains(FAULT_USER) { "user" } else { "kernel" };
+        try!(write!(f, "{} {} {}", kind, access, mode));
+        if self.contains(FAULT_RESERVED) {
+            try!(write!(f, " reserved"));
+        }
+        if self.contains(FAULT_FETCH) {
+            try!(write!(f, " fetch"));
+        }
+        Ok(())
+    }
+}
+
+/// A decoded page fault.
+pub struct PageFault {
+    /// The address that was accessed.
+    pub addr: usize,
+    /// The kind of access.
+    pub flags: FaultFlags,
+    /// The address of the faulting instruction.
+    pub eip: usize,
+}
+
+impl Debug for PageFault {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "page fault at 0x{:08x} (eip 0x{:08x}, {:?})", self.addr, self.eip, self.flags)
+    }
+}
+
+/// Tries to resolve a page fault. Returns a description of the problem if it can't be resolved.
+fn resolve(fault: &PageFault) -> Result<(), &'static str> {
+    if fault.flags.contains(FAULT_RESERVED) {
+        return Err("reserved bit set in paging structure");
+    }
+    if fault.flags.contains(FAULT_PRESENT | FAULT_FETCH) {
+        return Err("execution of a non-executable page");
+    }
+
+    // Waiting for the lock would deadlock if the fault happened while it is held.
+    let space = try!(virt::current_space().ok_or("no active address space"));
+    let mut space = try!(space.try_lock().ok_or("address space is locked"));
+    if !space.is_active() {
+        return Err("no address space for the active page directory");
+    }
+
+    if fault.flags.contains(FAULT_PRESENT) {
+        let is_cow = space.with_pd(|pd| pd.is_cow(fault.addr));
+        if !fault.flags.contains(FAULT_WRITE) || !is_cow {
+            return Err("protection violation");
+        }
+        try!(space.with_pd(|pd| pd.copy_on_write(fault.addr)).map_err(|_| "out of memory"));
+        trace!("copied page at 0x{:x}", page_align(fault.addr));
+        return Ok(());
+    }
+
+    let vma = *try!(space.vmas().lookup(fault.addr).ok_or("address is not mapped"));
+    if fault.flags.contains(FAULT_WRITE) && !vma.is_writable() {
+        return Err("write to read-only area");
+    }
+    if fault.flags.contains(FAULT_FETCH) && !vma.is_executable() {
+        return Err("execution of a non-executable area");
+    }
+
+    let page = page_align(fault.addr);
+    match vma.backing {
+        Backing::Anonymous => {
+            // Back the page with a zeroed frame. It is zeroed before it is mapped so the old
+            // contents are never visible and read-only areas don't fault again.
+            let frame = try!(phys::get_frame().ok_or("out of memory"));
+            let frame_addr = &*frame as *const Frame as usize;
+            {
+                let window = kmap(frame_addr);
+                unsafe { ptr::write_bytes(window.as_ptr(), 0, PAGE_SIZE) };
+            }
+            if space.map_page(page, frame, vma.pte_flags()).is_err() {
+                // We know this is safe because the frame was never mapped.
+                phys::return_frame(unsafe { Frame::from_addr(frame_addr) });
+                return Err("out of memory");
+            }
+            trace!("mapped zeroed page at 0x{:x}", page);
+        }
+        Backing::Device { phys } => {
+            // We know constructing the frame box is safe because device memory is never handed
+            // out by the frame allocator.
+            let frame = unsafe { Frame::from_addr(phys + (page - vma.start)) };
+            try!(space.map_page(page, frame, vma.pte_flags()).map_err(|_| "out of memory"));
+            trace!("mapped device page at 0x{:x}", page);
+        }
+        Backing::File { .. } => return Err("file backed areas are not supported"),
+    }
+    Ok(())
+}
+
+fn page_fault(_: u8, regs: &mut Regs, iret: &mut IRet) {
+    let fault = PageFault {
+        addr: get_cr2(),
+        flags: FaultFlags::from_bits_truncate(iret.error_code),
+        eip: iret.eip as usize,
+    };
+    trace!("{:?}", fault);
+    if let Err(reason) = resolve(&fault) {
+        panic!("unhandled {:?}: {}\n{:?}", fault, reason, regs);
+    }
+}
+
+/// Installs the page fault handler.
+pub fn init() {
+    set_isr(PAGE_FAULT_IRQ, page_fault);
+}
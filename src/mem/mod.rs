@@ -11,104 +11,180 @@
 extern crate mutex;
 extern crate io;
 extern crate alloc;
+extern crate collections;
+extern crate interrupt;
 
 pub mod phys;
 pub mod virt;
+pub mod vma;
+pub mod fault;
+
+pub use virt::{ioremap, iounmap, IoMem, CacheMode};
 
 use core::prelude::*;
+use core::fmt;
+use core::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use mutex::Mutex;
 use phys::Frame;
-use virt::{PageTable, PageDirectory};
-use virt::{PDE_WRITABLE, PDE_SUPERVISOR, PDE_MAPPED_SIZE, PD_RECMAP_ADDR};
-use virt::{PTE_WRITABLE, PTE_SUPERVISOR, PTE_GLOBAL};
-use util::{page_align, PAGE_SIZE};
+use virt::{AddressSpace, PageTable, PageDirectory, VBox, vmalloc};
+use virt::{PDE_WRITABLE, PDE_SUPERVISOR, PDE_GLOBAL, PDE_NOEXEC};
+use virt::{PTE_WRITABLE, PTE_SUPERVISOR, PTE_GLOBAL, PTE_NOEXEC};
+use virt::{pde_mapped_size, recmap_addr};
+use util::{page_align, phys_to_virt, virt_to_phys, PAGE_SIZE, KERNEL_BASE};
 use util::rawbox::RawBox;
 use util::global::Global;
 use util::multiboot::MultibootHeader;
-use util::asm::{enable_paging, enable_global_pages, set_cr3};
+use util::asm::{enable_paging, enable_global_pages, enable_4mb_pages, set_cr3};
 logger_init!(Trace);
 
-// The kernel page directory. This is the default page directory used by new tasks. 
-static KPD: Global<RawBox<PageDirectory>> = Global::new();
+// The kernel's address space. This is the address space that is active after initialization.
+static KERNEL_SPACE: Global<Mutex<AddressSpace>> = Global::new();
+
+// Whether the heap can grow. This needs the vmalloc area, so it is set at the end of `init`.
+static HEAP_CAN_GROW: AtomicBool = ATOMIC_BOOL_INIT;
 
 /// Initializes all memory related submodules. 
 ///
-/// This uses the `MultibootHeader` to populate the free frame list with all free physical frames.
-/// It then uses 5 frames to direct map the first 16 MB of the address space, which is reserved for
-/// the kernel. Finally, it enables paging.
+/// This uses the `MultibootHeader` to build the frame descriptor table and populate the frame
+/// allocator with all free physical frames.
+/// It then maps the kernel at `KERNEL_BASE` using large pages where possible and switches from the
+/// boot page directory to the kernel's own, which no longer identity maps low memory. Code and
+/// read-only data are never writable and, with PAE, nothing else is executable.
 pub fn init(hdr: &MultibootHeader) {
     debug!("initializing mem");
-    phys::init();
+    phys::init(hdr);
     virt::init();
+    debug!("using {} paging", if virt::is_pae() { "PAE" } else { "32-bit" });
     hdr.walk_mmap(add_range_safe);
     
     trace!("direct mapping kernel");
-    direct_map_kernel(); 
+    let kpd = direct_map_kernel();
 
-    trace!("enabling paging...");
-    set_cr3(KPD.borrow() as *const PageDirectory as usize);
+    // Paging is already enabled by the boot code. Enabling it again turns on write protection.
+    trace!("loading kernel page directory...");
+    let kpd_addr = kpd.borrow() as *const PageDirectory as usize;
+    let kcr3 = virt::boot_cr3(kpd_addr);
+    enable_4mb_pages();
     enable_global_pages();
+    set_cr3(kcr3);
     enable_paging();
+
+    // Double faults are handled by a task of their own which needs a page directory as well.
+    interrupt::set_double_fault_cr3(kcr3);
+
+    // From here on page tables may only be edited through an address space. We know this is safe
+    // because the kernel page directory maps itself and nothing else owns it.
+    let space = unsafe { AddressSpace::from_raw(kpd, kcr3) };
+    let space = space.expect("unable to allocate kernel space");
+    KERNEL_SPACE.init(Mutex::new(space));
+    // We know this is safe because the kernel's address space is static.
+    unsafe { virt::switch_to(&*KERNEL_SPACE) };
+    virt::init_paged();
+
+    // Page faults can now be resolved.
+    fault::init();
+    HEAP_CAN_GROW.store(true, Ordering::SeqCst);
+}
+
+/// Returns the kernel's address space.
+pub fn kernel_space() -> &'static Mutex<AddressSpace> {
+    &*KERNEL_SPACE
+}
+
+/// Writes every mapping of the kernel's address space to `w`, one per line.
+pub fn dump_kernel_mappings<W: fmt::Write>(w: &mut W) -> fmt::Result {
+    kernel_space().lock().with_pd(|pd| {
+        for mapping in pd.mappings() {
+            try!(write!(w, "{:?}\n", mapping));
+        }
+        Ok(())
+    })
+}
+
+/// This is the heap's interface to the memory manager. It maps at least `size` bytes for the heap
+/// to grow by in the vmalloc area, where every address space can see them.
+#[no_mangle]
+pub extern fn heap_map(size: usize) -> Option<usize> {
+    if !HEAP_CAN_GROW.load(Ordering::SeqCst) {
+        return None;
+    }
+    vmalloc(size).ok().map(|vbox| vbox.into_raw())
+}
+
+/// This is the heap's interface to the memory manager. It unmaps memory returned by `heap_map`.
+#[no_mangle]
+pub extern fn heap_unmap(addr: usize, size: usize) {
+    // We know this is safe because the heap only returns memory it got from heap_map.
+    drop(unsafe { VBox::from_raw(addr, size) });
 }
 
-fn direct_map_kernel() {
-    let mut pd = PageDirectory::new().expect("unable to allocate global page directory");
-    let pt0 = PageTable::new().expect("unable to allocate global page table 1");
-    let pt1 = PageTable::new().expect("unable to allocate global page table 2");
-    let pt2 = PageTable::new().expect("unable to allocate global page table 3");
-    let pt3 = PageTable::new().expect("unable to allocate global page table 4");
-    trace!("pd: {:?}", pd);
-    trace!("pt0: {:?}", pt0);
-    trace!("pt1: {:?}", pt1);
-    trace!("pt2: {:?}", pt2);
-    trace!("pt3: {:?}", pt3);
+fn direct_map_kernel() -> RawBox<PageDirectory> {
+    let pd_box = PageDirectory::new().expect("unable to allocate global page directory");
+    trace!("pd: {:?}", pd_box);
+
+    // The page directory and its page tables are edited through the direct map of the boot page
+    // directory until the kernel switches to it. We know this is safe because we own it.
+    let pd_addr = pd_box.into_raw() as usize;
+    let pd = unsafe { &mut *(phys_to_virt(pd_addr) as *mut PageDirectory) };
 
     // First, map the page directory into itself. This is ok because page directories look a lot
     // like page tables so by mapping the page directory into itself causes that entry to in the
     // page directory to map all page tables. See the following link if interested.
     // http://wiki.osdev.org/Page_Tables#Recursive_mapping
     let pdflags = PDE_SUPERVISOR | PDE_WRITABLE;
-    let pdrec = unsafe { pd.as_pagetable() }; //FIXME: RFC/811
-    pd.map_pagetable(PD_RECMAP_ADDR, pdrec, pdflags);
-
-    // Map in the four page tables.
-    pd.map_pagetable(0*PDE_MAPPED_SIZE, pt0, pdflags);
-    pd.map_pagetable(1*PDE_MAPPED_SIZE, pt1, pdflags);
-    pd.map_pagetable(2*PDE_MAPPED_SIZE, pt2, pdflags);
-    pd.map_pagetable(3*PDE_MAPPED_SIZE, pt3, pdflags);
-
-    // Map in the kernel. We know constructing the page_box variable is safe because we only map
-    // the kernel in once.
-    let ptflags = PTE_SUPERVISOR | PTE_WRITABLE | PTE_GLOBAL;
+    pd.map_window(recmap_addr(), pd_addr);
+
+    // Map the kernel with large pages wherever possible. Regions that are only partially kernel
+    // memory, contain video memory or contain read-only pages need 4K granularity so they get a
+    // page table instead. We know constructing the region boxes is safe because we only map the
+    // kernel in once. All addresses here are virtual.
     let kernel_start = linker_sym!(__kernel_start);
     let kernel_end = linker_sym!(__kernel_end);
+    let ro_start = linker_sym!(__ro_start);
+    let ro_end = linker_sym!(__ro_end);
+    let vmem = phys_to_virt(0xB8000);
+    for region in (KERNEL_BASE..kernel_end).step_by(pde_mapped_size()) {
+        let region_end = region + pde_mapped_size();
+        let partial = region < kernel_start || region_end > kernel_end;
+        let has_ro = region < ro_end && ro_start < region_end;
+        let has_vmem = region <= vmem && vmem < region_end;
+        if partial || has_ro || has_vmem {
+            let pt = PageTable::new().expect("unable to allocate global page table");
+            trace!("pt: {:?} for {:x}", pt, region);
+            pd.map_pagetable(region, pt, pdflags);
+        } else {
+            let region_box = unsafe { Frame::from_addr(virt_to_phys(region)) };
+            pd.map_large(region, region_box, pdflags | PDE_GLOBAL | PDE_NOEXEC);
+        }
+    }
+
+    // Map in the rest of the kernel. Code and read-only data are mapped read-only and everything
+    // else no-execute, so no page is both writable and executable. We know constructing the
+    // page_box variable is safe because we only map the kernel in once.
+    let ptflags = PTE_SUPERVISOR | PTE_WRITABLE | PTE_GLOBAL | PTE_NOEXEC;
+    let roflags = PTE_SUPERVISOR | PTE_GLOBAL;
     for page in (kernel_start..kernel_end).step_by(PAGE_SIZE) {
-        assert!(pd.has_pagetable(page));
-        let page_box = unsafe { RawBox::from_raw(page as *mut Frame) };
-        pd.map_page(page, page_box, ptflags);
+        if pd.has_pagetable(page) {
+            let flags = if ro_start <= page && page < ro_end { roflags } else { ptflags };
+            let page_box = unsafe { Frame::from_addr(virt_to_phys(page)) };
+            pd.map_page(page, page_box, flags);
+        }
     }
 
     // Map in video memory. We know constructing the vmem_box variable is safe because we only map
     // in video memory once.
-    let vmem: usize = 0xB8000;
-    let vmem_box = unsafe { RawBox::from_raw(vmem as *mut Frame) };
+    let vmem_box = unsafe { Frame::from_addr(virt_to_phys(vmem)) };
     pd.map_page(vmem, vmem_box, ptflags);
 
-    // Mark code/rodata as readonly to prevent a few bugs.
-    let ro_start = linker_sym!(__ro_start);
-    let ro_end = linker_sym!(__ro_end);
-    for page in (ro_start..ro_end).step_by(PAGE_SIZE) {
-        pd.remove_pte_flags(page, PTE_WRITABLE);
-    }
-
-    // Set the global default kernel page directory. We're subverting the 
-    KPD.init(pd);
+    // We know this is safe because this is the box we took the address from.
+    unsafe { RawBox::from_raw(pd_addr as *mut PageDirectory) }
 }
 
 // This function filters memory ranges reported by the bootloader to remove the
-// pages reserved for kernel memory.
+// pages reserved for kernel memory. The ranges are physical.
 fn add_range_safe(region_start: usize, region_end: usize) {
-    let kernel_start: usize = linker_sym!(__kernel_start);
-    let kernel_end: usize = linker_sym!(__kernel_end);
+    let kernel_start: usize = virt_to_phys(linker_sym!(__kernel_start));
+    let kernel_end: usize = virt_to_phys(linker_sym!(__kernel_end));
     let region_end = page_align(region_end);
     if region_start < kernel_start && region_end > kernel_start {
         // Region overlaps from the left. 
@@ -0,0 +1,150 @@
+//!
+//! The buddy allocator backing `mem::phys`.
+//!
+//! Free memory is kept as blocks of `2^order` physically contiguous frames where each block is
+//! aligned to its own size. Every order has its own doubly linked free list. The links live in the
+//! frame descriptor of the first frame of each block rather than in the frame itself so that the
+//! allocator never has to touch memory that may not be mapped.
+//!
+//! When a block is freed we check whether its buddy (the block it was split from) is also free
+//! and, if so, merge the two into a block of the next order. This keeps large contiguous runs
+//! available for as long as possible.
+//!
+use core::prelude::*;
+use super::{FrameDesc, FRAME_FREE, MAX_ORDER, desc_table};
+logger_init!(Trace);
+
+const NUM_ORDERS: usize = MAX_ORDER + 1;
+
+/// The index used to terminate free lists.
+pub const NO_FRAME: usize = !0;
+
+/// The order stored in a descriptor that is not the head of a free block.
+pub const NOT_HEAD: u8 = 0xff;
+
+pub struct BuddyAllocator {
+    free_lists: [usize; NUM_ORDERS],
+    free_frames: usize,
+}
+
+pub const BUDDY_ALLOCATOR_INIT: BuddyAllocator = BuddyAllocator {
+    free_lists: [NO_FRAME; NUM_ORDERS],
+    free_frames: 0,
+};
+
+// Returns the index of the buddy of the block at index `idx`.
+#[inline]
+fn buddy_of(idx: usize, order: usize) -> usize {
+    idx ^ (1 << order)
+}
+
+// Returns the descriptor for the frame at index `idx`. We know this is safe because the buddy
+// allocator lock protects the buddy fields of all descriptors.
+#[inline]
+fn desc<'a>(idx: usize) -> &'a mut FrameDesc {
+    unsafe { desc_table().get_mut(idx).expect("frame is not covered by the descriptor table") }
+}
+
+impl BuddyAllocator {
+
+    /// Returns the number of free frames.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    fn is_free_head(&self, idx: usize, order: usize) -> bool {
+        idx < desc_table().len() && desc(idx).buddy_order == order as u8
+    }
+
+    /// Pushes a block onto the free list for its order.
+    fn push(&mut self, idx: usize, order: usize) {
+        assert!(is_aligned!(idx, 1 << order));
+        let head = self.free_lists[order];
+        {
+            let d = desc(idx);
+            d.buddy_order = order as u8;
+            d.buddy_prev = NO_FRAME;
+            d.buddy_next = head;
+        }
+        if head != NO_FRAME {
+            desc(head).buddy_prev = idx;
+        }
+        self.free_lists[order] = idx;
+        self.free_frames += 1 << order;
+    }
+
+    /// Removes a specific free block from its free list.
+    fn remove(&mut self, idx: usize, order: usize) {
+        let (prev, next) = {
+            let d = desc(idx);
+            assert!(d.buddy_order == order as u8);
+            d.buddy_order = NOT_HEAD;
+            (d.buddy_prev, d.buddy_next)
+        };
+        if prev == NO_FRAME {
+            self.free_lists[order] = next;
+        } else {
+            desc(prev).buddy_next = next;
+        }
+        if next != NO_FRAME {
+            desc(next).buddy_prev = prev;
+        }
+        self.free_frames -= 1 << order;
+    }
+
+    /// Tries to allocate a block of the given order. Returns the index of the first frame.
+    pub fn alloc(&mut self, order: usize) -> Option<usize> {
+        assert!(order <= MAX_ORDER);
+
+        // Find the smallest order with a free block.
+        let mut cur = order;
+        while cur <= MAX_ORDER && self.free_lists[cur] == NO_FRAME {
+            cur += 1;
+        }
+        if cur > MAX_ORDER {
+            trace!("no free blocks of order {}", order);
+            return None;
+        }
+
+        // Split the block until it's the right size, returning the upper halves.
+        let idx = self.free_lists[cur];
+        self.remove(idx, cur);
+        while cur > order {
+            cur -= 1;
+            self.push(idx + (1 << cur), cur);
+        }
+
+        // The frames are now allocated.
+        for i in idx .. idx + (1 << order) {
+            desc(i).remove_flags(FRAME_FREE);
+        }
+        Some(idx)
+    }
+
+    /// Frees a block of the given order starting at frame index `idx`.
+    pub fn free(&mut self, idx: usize, order: usize) {
+        assert!(order <= MAX_ORDER);
+        assert!(is_aligned!(idx, 1 << order));
+        for i in idx .. idx + (1 << order) {
+            assert!(!desc(i).flags().contains(FRAME_FREE));
+            desc(i).insert_flags(FRAME_FREE);
+        }
+
+        // Coalesce with our buddy for as long as it's free and the same size as us.
+        let mut idx = idx;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = buddy_of(idx, order);
+            if !self.is_free_head(buddy, order) {
+                break;
+            }
+            self.remove(buddy, order);
+            if buddy < idx {
+                idx = buddy;
+            }
+            order += 1;
+        }
+        self.push(idx, order);
+    }
+}
+
@@ -1,30 +1,278 @@
+//!
+//! This module contains the physical frame allocator and the frame descriptor table.
+//!
+//! Every physical frame below the highest available address reported by the bootloader has a
+//! `FrameDesc` describing it. Descriptors hold a reference count of the number of mappings of the
+//! frame, a set of flags and an owner tag. This lets copy-on-write, shared memory and page reclaim
+//! know when the last mapping of a frame goes away.
+//!
+//! Free frames are managed by a buddy allocator (see `buddy`) which keeps all of its bookkeeping
+//! in the descriptor table.
+//!
+//! Physical memory is split into zones which each have their own buddy allocator:
+//!
+//! * `Zone::Dma` covers the first 16MB. This is all legacy ISA DMA can address and also contains
+//!   the frames below 1MB that real-mode code needs.
+//! * `Zone::Normal` covers 16MB to 896MB, which is memory the kernel can always reach.
+//! * `Zone::High` covers everything else.
+//!
+//! Allocations from a zone fall back to lower zones when it is exhausted, but never to higher ones,
+//! so a caller always gets a frame it can actually use.
+//!
 use core::prelude::*;
-use core::fmt;
+use core::{cmp, fmt, mem, ptr};
+use core::cell::Cell;
+use core::atomic::{AtomicUsize, Ordering};
 use core::fmt::{Debug, Formatter};
 use mutex::Mutex;
-use util::{PAGE_SIZE, is_page_aligned};
+use util::{PAGE_SIZE, PAGE_SHIFT, is_page_aligned, page_align, virt_to_phys};
+use util::global::Global;
+use util::multiboot::MultibootHeader;
 use util::rawbox::{RawBox, Unallocated};
+use self::buddy::{BuddyAllocator, BUDDY_ALLOCATOR_INIT, NO_FRAME, NOT_HEAD};
 logger_init!(Trace);
 
-static FREE_FRAME_LIST: Mutex<Option<RawBox<Frame>>> = Mutex::new(None);
+mod buddy;
 
-/// A frame available for allocation.
+/// The largest order block the allocator manages. A block of order `MAX_ORDER` is 4MB, which is
+/// exactly what a large page directory entry maps.
+pub const MAX_ORDER: usize = 10;
+
+/// The end of the DMA zone.
+pub const DMA_ZONE_END: usize = 0x01000000;
+
+/// The end of the normal zone.
+pub const NORMAL_ZONE_END: usize = 0x38000000;
+
+const NUM_ZONES: usize = 3;
+
+static ZONES: [Mutex<BuddyAllocator>; NUM_ZONES] = [
+    Mutex::new(BUDDY_ALLOCATOR_INIT),
+    Mutex::new(BUDDY_ALLOCATOR_INIT),
+    Mutex::new(BUDDY_ALLOCATOR_INIT),
+];
+static DESCS: Global<DescTable> = Global::new();
+
+bitflags! {
+    flags FrameFlags: usize {
+        /// The frame belongs to the kernel image or a kernel data structure.
+        const FRAME_KERNEL = 0x00000001,
+        /// The frame may never be reclaimed.
+        const FRAME_PINNED = 0x00000002,
+        /// The frame has been written to since it was last cleaned.
+        const FRAME_DIRTY  = 0x00000004,
+        /// The frame is owned by the frame allocator.
+        const FRAME_FREE   = 0x00000008,
+    }
+}
+
+/// The owner tag of a frame nobody has claimed.
+pub const OWNER_NONE: usize = 0;
+
+/// The owner tag of frames belonging to the kernel.
+pub const OWNER_KERNEL: usize = 1;
+
+/// The owner tag of frames used as page tables or page directories.
+pub const OWNER_PAGETABLE: usize = 2;
+
+/// Describes a single physical frame.
+pub struct FrameDesc {
+    refcount: AtomicUsize,
+    flags: AtomicUsize,
+    owner: AtomicUsize,
+
+    // Buddy allocator bookkeeping. These are protected by the buddy allocator lock and are only
+    // meaningful for the first frame of a free block.
+    buddy_order: u8,
+    buddy_next: usize,
+    buddy_prev: usize,
+}
+
+impl FrameDesc {
+
+    fn new() -> FrameDesc {
+        FrameDesc {
+            refcount: AtomicUsize::new(0),
+            flags: AtomicUsize::new(0),
+            owner: AtomicUsize::new(OWNER_NONE),
+            buddy_order: NOT_HEAD,
+            buddy_next: NO_FRAME,
+            buddy_prev: NO_FRAME,
+        }
+    }
+
+    /// Returns the physical address of the frame this descriptor describes.
+    pub fn addr(&self) -> usize {
+        let base = DESCS.descs as usize;
+        let idx = (self as *const FrameDesc as usize - base) / mem::size_of::<FrameDesc>();
+        idx_to_addr(idx)
+    }
+
+    /// Returns the number of references to the frame.
+    pub fn get_count(&self) -> usize {
+        self.refcount.load(Ordering::SeqCst)
+    }
+
+    /// Returns the frame's flags.
+    pub fn flags(&self) -> FrameFlags {
+        FrameFlags::from_bits_truncate(self.flags.load(Ordering::SeqCst))
+    }
+
+    /// Atomically sets a set of flags on the frame.
+    pub fn insert_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_or(flags.bits(), Ordering::SeqCst);
+    }
+
+    /// Atomically clears a set of flags on the frame.
+    pub fn remove_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_and(!flags.bits(), Ordering::SeqCst);
+    }
+
+    /// Returns the frame's owner tag.
+    pub fn owner(&self) -> usize {
+        self.owner.load(Ordering::SeqCst)
+    }
+
+    /// Sets the frame's owner tag.
+    pub fn set_owner(&self, owner: usize) {
+        self.owner.store(owner, Ordering::SeqCst);
+    }
+
+}
+
+impl Debug for FrameDesc {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "FrameDesc {{ addr: 0x{:x}, refcount: {}, flags: 0x{:x}, owner: {} }}",
+               self.addr(), self.get_count(), self.flags().bits(), self.owner())
+    }
+}
+
+/// A range of physical memory with its own frame allocator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Zone {
+    /// Memory below 16MB.
+    Dma,
+    /// Memory between 16MB and 896MB.
+    Normal,
+    /// Memory above 896MB.
+    High,
+}
+
+impl Zone {
+
+    /// Returns the zone containing the physical address `addr`.
+    pub fn from_addr(addr: usize) -> Zone {
+        if addr < DMA_ZONE_END {
+            Zone::Dma
+        } else if addr < NORMAL_ZONE_END {
+            Zone::Normal
+        } else {
+            Zone::High
+        }
+    }
+
+    /// Returns the first address of the zone.
+    pub fn start(self) -> usize {
+        match self {
+            Zone::Dma => 0,
+            Zone::Normal => DMA_ZONE_END,
+            Zone::High => NORMAL_ZONE_END,
+        }
+    }
+
+    /// Returns the address just past the end of the zone or `None` if the zone extends to the end
+    /// of the address space.
+    pub fn end(self) -> Option<usize> {
+        match self {
+            Zone::Dma => Some(DMA_ZONE_END),
+            Zone::Normal => Some(NORMAL_ZONE_END),
+            Zone::High => None,
+        }
+    }
+
+    /// Returns the zone allocations fall back to when this zone is exhausted.
+    pub fn fallback(self) -> Option<Zone> {
+        match self {
+            Zone::Dma => None,
+            Zone::Normal => Some(Zone::Dma),
+            Zone::High => Some(Zone::Normal),
+        }
+    }
+
+    fn allocator(self) -> &'static Mutex<BuddyAllocator> {
+        &ZONES[self as usize]
+    }
+
+}
+
+/// The table of all frame descriptors. This is allocated on the heap during initialization.
+struct DescTable {
+    descs: *mut FrameDesc,
+    len: usize,
+}
+
+unsafe impl Sync for DescTable { }
+
+impl DescTable {
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn get(&self, idx: usize) -> Option<&FrameDesc> {
+        if idx < self.len {
+            Some(unsafe { &*self.descs.offset(idx as isize) })
+        } else {
+            None
+        }
+    }
+
+    /// Mutably borrows a descriptor.
+    ///
+    /// # Safety
+    ///
+    /// This is unsafe because the caller must hold whatever lock protects the non-atomic fields of
+    /// the descriptor.
+    unsafe fn get_mut(&self, idx: usize) -> Option<&mut FrameDesc> {
+        if idx < self.len {
+            Some(&mut *self.descs.offset(idx as isize))
+        } else {
+            None
+        }
+    }
+
+}
+
+fn desc_table() -> &'static DescTable {
+    &*DESCS
+}
+
+#[inline]
+fn idx_to_addr(idx: usize) -> usize {
+    idx << PAGE_SHIFT
+}
+
+#[inline]
+fn addr_to_idx(addr: usize) -> usize {
+    addr >> PAGE_SHIFT
+}
+
+/// A frame available for allocation. This is merely a marker for 4K of physical memory; the
+/// allocator never reads or writes the contents of a frame.
 pub struct Frame {
-    next: Option<RawBox<Frame>>
+    _private: ()
 }
 
 impl Frame {
 
-    /// Creates and initializes a free frame from a unique memory address.
+    /// Creates a frame from a unique memory address.
     ///
     /// # Safety
     ///
     /// This is unsafe because the caller must guarantee that address is actually unique.
     pub unsafe fn from_addr(addr: usize) -> RawBox<Frame> {
         assert!(is_page_aligned(addr));
-        let mut frame = RawBox::from_raw(addr as *mut Frame);
-        frame.next = None;
-        frame
+        RawBox::from_raw(addr as *mut Frame)
     }
 
 }
@@ -39,51 +287,193 @@ impl Debug for Frame {
     }
 }
 
-/// Adds a range of physical memory to the free frame list. This assumes that these ranges do not
-/// overlap any ranges already added to the free frame list.
+/// Returns the size in bytes of a block of the given order.
+#[inline]
+pub fn order_size(order: usize) -> usize {
+    PAGE_SIZE << order
+}
+
+/// Returns the smallest order whose blocks can hold `size` bytes.
+pub fn size_to_order(size: usize) -> usize {
+    let mut order = 0;
+    while order_size(order) < size {
+        order += 1;
+    }
+    order
+}
+
+/// Returns the descriptor of the frame containing the physical address `addr`, if it has one.
+pub fn lookup(addr: usize) -> Option<&'static FrameDesc> {
+    desc_table().get(addr_to_idx(addr))
+}
+
+/// Takes a reference to the frame at `addr` and returns the new reference count. Frames without a
+/// descriptor (such as device memory) are not counted and always return 0.
+pub fn get_ref(addr: usize) -> usize {
+    lookup(addr).map(|desc| desc.refcount.fetch_add(1, Ordering::SeqCst) + 1).unwrap_or(0)
+}
+
+/// Drops a reference to the frame at `addr` and returns the new reference count. Frames without a
+/// descriptor (such as device memory) are not counted and always return 0.
+///
+/// # Panics
+///
+/// This function panics if the frame has no references.
+pub fn put_ref(addr: usize) -> usize {
+    lookup(addr).map(|desc| {
+        let old = desc.refcount.fetch_sub(1, Ordering::SeqCst);
+        assert!(old > 0);
+        old - 1
+    }).unwrap_or(0)
+}
+
+/// Returns the number of references to the frame at `addr`.
+pub fn ref_count(addr: usize) -> usize {
+    lookup(addr).map(|desc| desc.get_count()).unwrap_or(0)
+}
+
+/// Adds a range of physical memory to the frame allocator. This assumes that these ranges do not
+/// overlap any ranges already added to the allocator. Frames without a descriptor are skipped.
 pub fn add_range(start: usize, end: usize) {
     assert!(is_page_aligned(start));
     assert!(is_page_aligned(end));
     trace!("adding range: {:x}-{:x}", start, end);
 
-    let mut head = FREE_FRAME_LIST.lock();
-    for frame_addr in (start..end).step_by(PAGE_SIZE) {
-        // Filter out the zero frame because a frame with address 0 is "not present". This
-        // check probably does not belong here.
-        if frame_addr == 0 { continue }
-        
-        // Add the frame to the free frame list. We know this is safe because we assume that this
-        // range does not overlap any ranges already added to the free frame list.
-        let mut frame = unsafe { Frame::from_addr(frame_addr) };
-        frame.next = head.take();
-        *head = Some(frame);
+    // Filter out the zero frame because a frame with address 0 is "not present". This check
+    // probably does not belong here.
+    let mut idx = addr_to_idx(if start == 0 { PAGE_SIZE } else { start });
+    let end = cmp::min(addr_to_idx(end), desc_table().len());
+
+    // Carve the range into the largest naturally aligned blocks that fit in it. Zone boundaries
+    // are aligned to the largest block size so no block will ever straddle two zones.
+    while idx < end {
+        let zone = Zone::from_addr(idx_to_addr(idx));
+        let zone_end = zone.end().map_or(end, |zone_end| cmp::min(end, addr_to_idx(zone_end)));
+        let mut buddy = zone.allocator().lock();
+        while idx < zone_end {
+            let mut order = MAX_ORDER;
+            while !is_aligned!(idx, 1 << order) || idx + (1 << order) > zone_end {
+                order -= 1;
+            }
+            buddy.free(idx, order);
+            idx += 1 << order;
+        }
+    }
+}
+
+/// Tries to allocate `2^order` physically contiguous frames aligned to their combined size from
+/// `zone`, falling back to lower zones if `zone` has no free block large enough.
+///
+/// # Failures
+///
+/// Returns `None` if neither `zone` nor its fallbacks have a free block large enough.
+pub fn alloc_frames_in(zone: Zone, order: usize) -> Option<RawBox<Frame>> {
+    let mut cur = Some(zone);
+    while let Some(zone) = cur {
+        if let Some(idx) = zone.allocator().lock().alloc(order) {
+            // We know this is safe because the buddy allocator just handed us this block.
+            return Some(unsafe { Frame::from_addr(idx_to_addr(idx)) });
+        }
+        trace!("zone {:?} exhausted for order {}", zone, order);
+        cur = zone.fallback();
     }
+    None
+}
+
+/// Tries to allocate `2^order` physically contiguous frames aligned to their combined size from
+/// the normal zone.
+///
+/// # Failures
+///
+/// Returns `None` if there is no free block large enough.
+pub fn alloc_frames(order: usize) -> Option<RawBox<Frame>> {
+    alloc_frames_in(Zone::Normal, order)
 }
 
-/// Tries to allocate a free frame. 
+/// Returns a block of `2^order` frames previously allocated with `alloc_frames` or
+/// `alloc_frames_in`. The block goes back to the zone containing it.
+pub fn free_frames(frames: RawBox<Frame>, order: usize) {
+    let addr = frames.into_raw() as usize;
+    assert!(is_aligned!(addr, order_size(order)));
+    Zone::from_addr(addr).allocator().lock().free(addr_to_idx(addr), order)
+}
+
+/// Tries to allocate a free frame from `zone` or one of its fallbacks.
+///
+/// # Failures
+///
+/// Returns `None` if there are no free frames in `zone` or its fallbacks.
+pub fn get_frame_in(zone: Zone) -> Option<RawBox<Frame>> {
+    alloc_frames_in(zone, 0)
+}
+
+/// Tries to allocate a free frame from the normal zone.
 ///
 /// # Failures
 ///
-/// Returns `None` if the free frame list is empty.
+/// Returns `None` if there are no free frames.
 pub fn get_frame() -> Option<RawBox<Frame>> {
-    let mut head = FREE_FRAME_LIST.lock();
-    head.take().and_then(|mut frame| {
-        assert!(is_page_aligned(&*frame as *const Frame as usize));
-        *head = frame.next.take();
-        Some(frame)
-    })
+    alloc_frames(0)
 }
 
-/// Returns a frame to the free frame list.
-pub fn return_frame(mut frame: RawBox<Frame>) {
+/// Returns a frame to the frame allocator.
+pub fn return_frame(frame: RawBox<Frame>) {
     assert!(is_page_aligned(&*frame as *const Frame as usize));
-    let mut head = FREE_FRAME_LIST.lock();
-    frame.next = head.take();
-    *head = Some(frame);
+    free_frames(frame, 0)
 }
 
-pub fn init () {
+/// Returns the number of free frames in `zone`, not counting its fallbacks.
+pub fn free_frame_count_in(zone: Zone) -> usize {
+    zone.allocator().lock().free_frames()
+}
 
+/// Returns the number of free frames in all zones.
+pub fn free_frame_count() -> usize {
+    free_frame_count_in(Zone::Dma) + free_frame_count_in(Zone::Normal) +
+        free_frame_count_in(Zone::High)
 }
 
+/// Initializes the frame descriptor table.
+///
+/// The table covers every frame up to the highest available address in the multiboot memory map.
+/// It has to come from the heap before it can grow, so it may take up at most half of the initial
+/// heap and memory beyond what it covers is ignored. Frames belonging to the kernel image are
+/// marked as pinned kernel frames. This must be called before any ranges are added to the
+/// allocator.
+pub fn init(hdr: &MultibootHeader) {
+    // Find the end of physical memory.
+    let mem_end = Cell::new(0);
+    hdr.walk_mmap(|_, region_end| {
+        if page_align(region_end) > mem_end.get() {
+            mem_end.set(page_align(region_end));
+        }
+    });
+    let mut count = addr_to_idx(mem_end.get());
+    let heap_size = linker_sym!(__heap_end) - linker_sym!(__heap_start);
+    let max_count = heap_size / 2 / mem::size_of::<FrameDesc>();
+    if count > max_count {
+        info!("ignoring memory above 0x{:x}", idx_to_addr(max_count));
+        count = max_count;
+    }
+    trace!("allocating {} frame descriptors", count);
 
+    // Allocate and initialize the descriptors.
+    let size = count * mem::size_of::<FrameDesc>();
+    let align = mem::min_align_of::<FrameDesc>();
+    let descs = ::alloc::allocate_raw(size, align)
+        .expect("unable to allocate frame descriptor table") as *mut FrameDesc;
+    for i in 0 .. count {
+        unsafe { ptr::write(descs.offset(i as isize), FrameDesc::new()) };
+    }
+    DESCS.init(DescTable { descs: descs, len: count });
+
+    // Mark the kernel's frames.
+    let kernel_start = virt_to_phys(linker_sym!(__kernel_start));
+    let kernel_end = virt_to_phys(linker_sym!(__kernel_end));
+    for addr in (kernel_start .. kernel_end).step_by(PAGE_SIZE) {
+        if let Some(desc) = lookup(addr) {
+            desc.insert_flags(FRAME_KERNEL | FRAME_PINNED);
+            desc.set_owner(OWNER_KERNEL);
+        }
+    }
+}
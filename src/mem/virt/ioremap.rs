@@ -0,0 +1,150 @@
+//!
+//! Mappings of device memory.
+//!
+//! Devices expose their registers and buffers as ranges of physical addresses (for example PCI
+//! BARs). `ioremap` maps such a range into the vmalloc area with the caching behaviour the device
+//! needs and returns an `IoMem` handle for volatile accesses. The range is unmapped when the handle
+//! is dropped. The frames are never handed to the frame allocator since they belong to the device.
+//!
+use core::prelude::*;
+use core::mem;
+use core::intrinsics::{volatile_load, volatile_store};
+use phys::Frame;
+use util::{KernResult, KernError, PAGE_SIZE, page_align};
+use util::asm::invlpg;
+use super::{PageTableEntry, PTE_WRITABLE, PTE_GLOBAL, PTE_WRITETHROUGH, PTE_CACHEDISABLE};
+use super::PTE_NOEXEC;
+use super::active_pd;
+use super::vmalloc::{reserve, release};
+
+/// How accesses to a device mapping are cached.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CacheMode {
+    /// Reads and writes are cached. Only suitable for memory that behaves like RAM.
+    WriteBack,
+    /// Reads are cached and writes go straight to the device. Useful for framebuffers.
+    WriteThrough,
+    /// Nothing is cached. This is what device registers need.
+    Uncached,
+}
+
+impl CacheMode {
+
+    /// Returns the page table entry flags that select this cache mode.
+    pub fn pte_flags(self) -> PageTableEntry {
+        match self {
+            CacheMode::WriteBack => PageTableEntry::empty(),
+            CacheMode::WriteThrough => PTE_WRITETHROUGH,
+            CacheMode::Uncached => PTE_CACHEDISABLE | PTE_WRITETHROUGH,
+        }
+    }
+
+}
+
+/// A mapping of device memory. All accesses are volatile and bounds checked.
+pub struct IoMem {
+    addr: usize,
+    len: usize,
+}
+
+impl IoMem {
+
+    /// Returns the virtual address the start of the physical range is mapped at.
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// Returns the length of the mapped range in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // Returns a pointer to a `T` at `offset` bytes into the range.
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(offset <= self.len && mem::size_of::<T>() <= self.len - offset);
+        assert!(is_aligned!(offset, mem::min_align_of::<T>()));
+        (self.addr + offset) as *mut T
+    }
+
+    /// Reads a `T` at `offset` bytes into the range.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the value is not inside the range or `offset` is not aligned for a
+    /// `T`.
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        // We know this is safe because the range is mapped for as long as we own it.
+        unsafe { volatile_load(self.ptr(offset)) }
+    }
+
+    /// Writes a `T` at `offset` bytes into the range.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the value is not inside the range or `offset` is not aligned for a
+    /// `T`.
+    pub fn write<T: Copy>(&self, offset: usize, val: T) {
+        unsafe { volatile_store(self.ptr(offset), val) }
+    }
+
+    // Returns the first mapped page and the number of mapped pages.
+    fn pages(&self) -> (usize, usize) {
+        let start = page_align(self.addr);
+        (start, page_count(self.addr, self.len))
+    }
+
+}
+
+// Returns the number of pages the `len` bytes at `addr` touch. This is computed from the last byte
+// so ranges that end at the top of the address space don't overflow.
+fn page_count(addr: usize, len: usize) -> usize {
+    let last = addr.checked_add(len - 1).expect("range extends past the end of the address space");
+    (page_align(last) - page_align(addr)) / PAGE_SIZE + 1
+}
+
+impl Drop for IoMem {
+    fn drop(&mut self) {
+        let (start, count) = self.pages();
+        let pd = active_pd();
+        for i in 0 .. count {
+            // The frame belongs to the device so we just forget about it.
+            pd.unmap_page(start + i * PAGE_SIZE).into_raw();
+        }
+        release(start, count);
+    }
+}
+
+/// Maps `len` bytes of device memory starting at the physical address `phys` into the kernel's
+/// address space with the given cache mode. `phys` does not need to be page aligned.
+///
+/// # Failures
+///
+/// Fails with `OutOfMemory` if there is no free range of the vmalloc area that is large enough.
+///
+/// # Panics
+///
+/// This function panics if `len` is zero or the range extends past the end of the physical address
+/// space.
+pub fn ioremap(phys: usize, len: usize, mode: CacheMode) -> KernResult<IoMem> {
+    assert!(len > 0);
+    let phys_start = page_align(phys);
+    let count = page_count(phys, len);
+    let start = try!(reserve(count).ok_or(KernError::OutOfMemory));
+
+    let pd = active_pd();
+    let flags = PTE_WRITABLE | PTE_GLOBAL | PTE_NOEXEC | mode.pte_flags();
+    for i in 0 .. count {
+        // We know constructing the frame box is safe because device memory is not handed out by the
+        // frame allocator and the mapping is removed before the handle goes away.
+        let page = start + i * PAGE_SIZE;
+        let frame = unsafe { Frame::from_addr(phys_start + i * PAGE_SIZE) };
+        pd.map_page(page, frame, flags);
+        invlpg(page);
+    }
+    Ok(IoMem { addr: start + (phys - phys_start), len: len })
+}
+
+/// Unmaps device memory mapped by `ioremap`. This is the same as dropping the handle.
+pub fn iounmap(io: IoMem) {
+    drop(io);
+}
@@ -0,0 +1,87 @@
+//!
+//! Temporary kernel mappings.
+//!
+//! Only the kernel image is permanently mapped, so any other frame can only be accessed by mapping
+//! it somewhere. `kmap` maps a frame into one of a handful of scratch
+//! slots for as long as the returned `KMap` lives. The page table for the slots is shared by every
+//! address space so a mapping is usable no matter which address space is active.
+//!
+use core::prelude::*;
+use mutex::Mutex;
+use phys::Frame;
+use util::{PAGE_SIZE, is_page_aligned};
+use util::asm::invlpg;
+use util::rawbox::RawBox;
+use super::{foreign_recmap_addr, pde_mapped_size, PTE_WRITABLE, PTE_NOEXEC};
+use super::{PDE_SUPERVISOR, PDE_WRITABLE, active_pd};
+
+/// Returns the start of the scratch slots. This is the page directory entry below the foreign
+/// window.
+pub fn kmap_addr() -> usize {
+    foreign_recmap_addr() - pde_mapped_size()
+}
+
+// The number of scratch slots. This is limited by the size of the slot bitmap.
+const KMAP_SLOTS: usize = 32;
+
+// A bitmap of the slots in use.
+static SLOTS: Mutex<u32> = Mutex::new(0);
+
+/// A temporary mapping of a frame. The frame is unmapped when this is dropped.
+pub struct KMap {
+    addr: usize,
+}
+
+impl KMap {
+
+    /// Returns the virtual address the frame is mapped at.
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// Returns a pointer to the start of the frame.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.addr as *mut u8
+    }
+
+}
+
+impl Drop for KMap {
+    fn drop(&mut self) {
+        // The frame was never ours so we just forget about it.
+        active_pd().unmap_page(self.addr).into_raw();
+        let slot = (self.addr - kmap_addr()) / PAGE_SIZE;
+        *SLOTS.lock() &= !(1 << slot);
+    }
+}
+
+/// Temporarily maps the frame at physical address `frame_addr` into the kernel's address space.
+///
+/// # Panics
+///
+/// This function panics if all slots are in use.
+pub fn kmap(frame_addr: usize) -> KMap {
+    assert!(is_page_aligned(frame_addr));
+    let slot = {
+        let mut slots = SLOTS.lock();
+        let slot = (0 .. KMAP_SLOTS).find(|i| *slots & (1 << *i) == 0)
+                                    .expect("out of kmap slots");
+        *slots |= 1 << slot;
+        slot
+    };
+
+    // We know constructing the frame box is safe because the mapping is dropped before the frame
+    // can be handed to anyone else by the caller.
+    let addr = kmap_addr() + slot * PAGE_SIZE;
+    let frame = unsafe { RawBox::from_raw(frame_addr as *mut Frame) };
+    active_pd().map_page(addr, frame, PTE_WRITABLE | PTE_NOEXEC);
+    invlpg(addr);
+    KMap { addr: addr }
+}
+
+/// Creates the page table for the scratch slots in the kernel's address space. This must be done
+/// before any other address spaces are created.
+pub fn init() {
+    active_pd().ensure_pagetable(kmap_addr(), PDE_SUPERVISOR | PDE_WRITABLE)
+               .expect("unable to allocate kmap page table");
+}
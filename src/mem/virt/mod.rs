@@ -1,34 +1,186 @@
+//!
+//! Paging.
+//!
+//! Two paging modes are supported behind the same interface. Classic 32-bit paging uses a single
+//! page directory of 1024 4-byte entries, each mapping 4MB. PAE uses four page directories of 512
+//! 8-byte entries, each mapping 2MB, and a page directory pointer table that CR3 points to. Only
+//! PAE has the no-execute bit. The boot code picks PAE if the processor supports both and `init`
+//! records the choice.
+//!
+//! Entries are always handled as 64-bit values. With classic paging the bits it doesn't have,
+//! including `PTE_NOEXEC`, are dropped when an entry is stored. PAE entries could hold frames
+//! above 4GB but the frame allocator only manages memory below 4GB, since physical addresses are
+//! kept in a `usize` throughout `mem::phys`. Using memory above 4GB is left for later.
+//!
+//! The four PAE page directories sit in consecutive pages, so a `PageDirectory` indexes its
+//! entries across the whole address space in both modes.
+//!
 use core::prelude::*;
-use core::mem;
+use core::{mem, ptr};
 use core::fmt;
 use core::fmt::{Debug, Formatter};
+use core::intrinsics::volatile_store;
 use phys;
-use phys::Frame;
-use util::{is_page_aligned, PAGE_SIZE};
+use phys::{Frame, Zone};
+use util::{is_page_aligned, page_align, phys_to_virt, PAGE_SIZE, KernResult, KernError};
+use util::KERNEL_BASE;
 use util::rawbox::RawBox;
+use util::asm::{invlpg, flush_tlb, pae_enabled};
+
+pub use self::space::{AddressSpace, switch_to, current_space};
+pub use self::kmap::{kmap, KMap, kmap_addr};
+pub use self::vmalloc::{vmalloc, vfree, VBox, VMALLOC_START, VMALLOC_END};
+pub use self::ioremap::{ioremap, iounmap, IoMem, CacheMode};
+pub use self::walk::{Translation, Mapping, Mappings};
+
+mod space;
+mod kmap;
+mod vmalloc;
+mod ioremap;
+mod walk;
 
-const ENTRY_MASK: usize = 0x3FF;
 const PT_SHIFT: usize = 12;
-const PD_SHIFT: usize = 22;
 pub const PTE_MAPPED_SIZE: usize = (1 << PT_SHIFT);
-pub const PDE_MAPPED_SIZE: usize = (1 << PD_SHIFT);
 
-#[allow(unsigned_negation)]
-// This is intentional. We want it to be the last page directory entry.
-pub const PD_RECMAP_ADDR: usize = -PDE_MAPPED_SIZE;
+// The number of page directories with PAE. Each maps a quarter of the address space.
+const PAE_PDS: usize = 4;
+
+// The only flag we set in page directory pointer table entries. Most other flags are reserved.
+const PDPTE_PRESENT: u64 = 0x00000001;
+
+// Ranges larger than this are invalidated by reloading CR3 rather than page by page.
+const INVLPG_MAX_RANGE: usize = 32 * PAGE_SIZE;
+
+/// The start of the kernel's part of the address space. Page tables above this address are shared
+/// by every address space, except for the recursive windows.
+pub const KERNEL_SPACE_START: usize = KERNEL_BASE;
+
+// Whether PAE is in use. This is set once by `init` before anything is mapped.
+static mut PAE: bool = false;
+
+/// Returns whether PAE paging is in use.
+pub fn is_pae() -> bool {
+    unsafe { PAE }
+}
+
+// Returns the size of a page table entry in bytes.
+fn entry_size() -> usize {
+    if is_pae() { 8 } else { 4 }
+}
+
+// Returns the number of entries in a page table or a single page directory.
+fn entries_per_table() -> usize {
+    PAGE_SIZE / entry_size()
+}
+
+/// Returns the number of consecutive pages a page directory takes up.
+pub fn pd_pages() -> usize {
+    if is_pae() { PAE_PDS } else { 1 }
+}
+
+/// Returns the order of the block of frames a page directory takes up.
+pub fn pd_order() -> usize {
+    if is_pae() { 2 } else { 0 }
+}
+
+// Returns the number of page directory entries covering the address space.
+fn pde_count() -> usize {
+    pd_pages() * entries_per_table()
+}
+
+// Returns the position of the address bits that select a page directory entry.
+fn pd_shift() -> usize {
+    if is_pae() { 21 } else { 22 }
+}
+
+/// Returns the amount of memory mapped by a page directory entry. This is also the size of a
+/// large page: 4MB, or 2MB with PAE.
+pub fn pde_mapped_size() -> usize {
+    1 << pd_shift()
+}
+
+/// Returns the order of the block of frames that backs a large page.
+pub fn large_page_order() -> usize {
+    pd_shift() - PT_SHIFT
+}
+
+/// Returns the start of the window in which the page tables of the active page directory are
+/// visible. The page directory maps itself here with its last entries.
+pub fn recmap_addr() -> usize {
+    0usize.wrapping_sub(pd_pages() * pde_mapped_size())
+}
+
+/// Returns the start of the window through which the page tables of a foreign address space are
+/// visible while it is being edited. This is right below the recursive window.
+pub fn foreign_recmap_addr() -> usize {
+    recmap_addr() - pd_pages() * pde_mapped_size()
+}
+
+/// Returns the address of the active page directory in the recursive window.
+pub fn active_pd_addr() -> usize {
+    recmap_addr() + addr_to_pde(recmap_addr()) * PAGE_SIZE
+}
+
+/// Returns the address of a foreign page directory while it is being edited.
+pub fn foreign_pd_addr() -> usize {
+    recmap_addr() + addr_to_pde(foreign_recmap_addr()) * PAGE_SIZE
+}
+
+/// Returns whether the page directory entry `pde` maps kernel memory and should therefore be
+/// shared between all address spaces.
+pub fn is_kernel_pde(pde: usize) -> bool {
+    pde >= addr_to_pde(KERNEL_SPACE_START) && !is_window_pde(pde)
+}
+
+/// Returns whether the page directory entry `pde` is one of the recursive windows. These are
+/// private to each address space and never copied. The windows take up the last entries.
+fn is_window_pde(pde: usize) -> bool {
+    pde >= addr_to_pde(foreign_recmap_addr())
+}
+
+// Returns the active page directory. We know this is safe because the active page directory always
+// maps itself here once paging is enabled.
+fn active_pd() -> &'static mut PageDirectory {
+    unsafe { &mut *(active_pd_addr() as *mut PageDirectory) }
+}
 
 // Converts an address to its page table index.
 fn addr_to_pte (addr: usize) -> usize {
-    (addr >> PT_SHIFT) & ENTRY_MASK
+    (addr >> PT_SHIFT) & (entries_per_table() - 1)
 }
 
 // Converts an address to its page directory index.
 fn addr_to_pde (addr: usize) -> usize {
-    (addr >> PD_SHIFT) & ENTRY_MASK
+    addr >> pd_shift()
+}
+
+// Reads entry `idx` of the table at `table`.
+unsafe fn read_entry(table: usize, idx: usize) -> u64 {
+    if is_pae() {
+        *(table as *const u64).offset(idx as isize)
+    } else {
+        *(table as *const u32).offset(idx as isize) as u64
+    }
+}
+
+// Writes entry `idx` of the table at `table`.
+//
+// PAE entries are written one half at a time so the processor may walk the table in between.
+// Clearing the half with the present flag first means it never sees a mix of the old and the new
+// entry.
+unsafe fn write_entry(table: usize, idx: usize, entry: u64) {
+    if is_pae() {
+        let low = (table as *mut u32).offset(2 * idx as isize);
+        volatile_store(low, 0);
+        volatile_store(low.offset(1), (entry >> 32) as u32);
+        volatile_store(low, entry as u32);
+    } else {
+        *(table as *mut u32).offset(idx as isize) = entry as u32;
+    }
 }
 
 bitflags! {
-    flags PageDirectoryEntry: u32 {
+    flags PageDirectoryEntry: u64 {
         const PDE_PRESENT      = 0x00000001,
         const PDE_WRITABLE     = 0x00000002,
         const PDE_SUPERVISOR   = 0x00000004,
@@ -36,47 +188,51 @@ bitflags! {
         const PDE_CACHEDISABLE = 0x00000010,
         const PDE_ACCESSED     = 0x00000020,
         const PDE_DIRTY        = 0x00000040, //*
-        const PDE_4MBREGION    = 0x00000080,
+        const PDE_LARGE        = 0x00000080,
         const PDE_GLOBAL       = 0x00000100, //*
-        const PDE_FRAMEMASK    = 0xfffff000,
+        const PDE_COW          = 0x00000200, //***
+        const PDE_FRAMEMASK    = 0x000ffffffffff000,
+        const PDE_NOEXEC       = 0x8000000000000000, //**
     }
-    //* Indicates these flags are only valid for 4MB regions.
+    //* Indicates these flags are only valid for large pages.
+    //** PAE only. Nothing mapped through the entry may be executed.
+    //*** Available to software. Marks a read-only large page that is copied on the first write.
 }
 
 impl PageDirectoryEntry {
-    
-    /// Sets the page directory entry to point to the 4MB region pointed to by `frame`.
+
+    /// Sets the page directory entry to point to the large page starting at `frame`.
     ///
     /// # Panics
     ///
-    /// If this page directory entry does not have its 4MB region flag set, this function panics.
-    pub fn set_4mbframe(&mut self, frame: usize) {
-        assert!(self.contains(PDE_4MBREGION));
-        assert!(is_aligned!(frame, PAGE_SIZE*PAGE_SIZE));
-        self.bits |= frame as u32;
+    /// If this page directory entry does not have its large page flag set, this function panics.
+    pub fn set_large_frame(&mut self, frame: usize) {
+        assert!(self.contains(PDE_LARGE));
+        assert!(is_aligned!(frame, pde_mapped_size()));
+        self.bits |= frame as u64;
     }
 
     /// Sets the page directory entry to point to the page table `pt`.
     ///
     /// # Panics
     ///
-    /// If the page directory entry has its 4MB region flag set, this function panics.
+    /// If the page directory entry has its large page flag set, this function panics.
     pub fn set_pagetable(&mut self, pt: RawBox<PageTable>) {
-        assert!(!self.contains(PDE_4MBREGION));
+        assert!(!self.contains(PDE_LARGE));
         let pt_addr: usize = pt.into_raw() as usize;
         assert!(is_page_aligned(pt_addr));
-        self.bits |= pt_addr as u32;
+        self.bits |= pt_addr as u64;
     }
 
-    /// Removes a page table from the page directory.
+    /// Removes a page table from the page directory entry.
     ///
     /// # Panics
     ///
-    /// This function panics if this entry does not have a mapped page table or its 4MB region flag
-    /// is set.
+    /// This function panics if this entry does not have a mapped page table or its large page
+    /// flag is set.
     pub fn remove_pagetable(&mut self) -> RawBox<PageTable> {
-        assert!(!self.contains(PDE_4MBREGION));
-        let pt_addr: usize = (self.bits & PDE_FRAMEMASK.bits) as usize;
+        assert!(!self.contains(PDE_LARGE));
+        let pt_addr = self.frame_addr();
         assert!(pt_addr != 0);
         self.clear();
 
@@ -84,39 +240,16 @@ impl PageDirectoryEntry {
         // pointer INTO the page directory.
         unsafe { RawBox::from_raw(pt_addr as *mut PageTable) }
     }
-  
-    /// Borrows a page table from the page directory entry.  
-    ///
-    /// We know this is safe because the entry owns the page table pointer.
-    ///
-    /// # Panics
-    ///
-    /// This function panics if this entry does not have a mapped page table or its 4MB region flag
-    /// is set.
-    pub fn borrow_pagetable(&self) -> &PageTable {
-        assert!(!self.contains(PDE_4MBREGION));
-        let pt_addr: usize = (self.bits & PDE_FRAMEMASK.bits) as usize;
-        assert!(pt_addr != 0);
-        unsafe { &*(pt_addr as *mut PageTable) }
-    }
-    
-    /// Mutably borrows a page table from the page table from the page directory entry.
-    ///
-    /// # Panics
-    ///
-    /// This function panics if this entry does not have a mapped page table or its 4MB region flag
-    /// is set.
-    pub fn borrow_pagetable_mut(&mut self) -> &mut PageTable {
-        assert!(!self.contains(PDE_4MBREGION));
-        let pt_addr: usize = (self.bits & PDE_FRAMEMASK.bits) as usize;
-        assert!(pt_addr != 0);
-        unsafe { &mut*(pt_addr as *mut PageTable) }
+
+    /// Returns the physical address of the page table or large page.
+    pub fn frame_addr(&self) -> usize {
+        (self.bits & PDE_FRAMEMASK.bits) as usize
     }
 
 }
 
 bitflags! {
-    flags PageTableEntry: u32 {
+    flags PageTableEntry: u64 {
         const PTE_PRESENT      = 0x00000001,
         const PTE_WRITABLE     = 0x00000002,
         const PTE_SUPERVISOR   = 0x00000004,
@@ -125,38 +258,56 @@ bitflags! {
         const PTE_ACCESSED     = 0x00000020,
         const PTE_DIRTY        = 0x00000040,
         const PTE_GLOBAL       = 0x00000100,
-        const PTE_FRAMEMASK    = 0xfffff000,
+        const PTE_COW          = 0x00000200, //*
+        const PTE_FRAMEMASK    = 0x000ffffffffff000,
+        const PTE_NOEXEC       = 0x8000000000000000, //**
     }
+    //* Available to software. Marks a read-only page that is copied on the first write.
+    //** PAE only. The page may not be executed.
 }
 
 impl PageTableEntry {
 
-    /// Sets the frame address of the entry to the given owned frame. 
+    /// Sets the frame address of the entry to the given owned frame. This takes a reference to
+    /// the frame in the frame descriptor table.
     ///
     /// # Panics
     ///
     /// This function panics if this entry already has a mapped frame.
     pub fn set_page(&mut self, frame: RawBox<Frame>) {
         assert!(!self.intersects(PTE_FRAMEMASK));
-        self.bits |= frame.into_raw() as u32;
+        let frame_addr = frame.into_raw() as usize;
+        phys::get_ref(frame_addr);
+        self.bits |= frame_addr as u64;
     }
 
-    /// Removes the page from the page table entry. TODO INVLPG?
+    /// Removes the page from the page table entry and drops its reference in the frame descriptor
+    /// table. Returns the frame along with the number of references left after dropping ours. Only
+    /// the caller that sees 0 may free the frame.
+    ///
+    /// This does not invalidate the TLB. Use `PageDirectory::unmap_page` unless you are doing so
+    /// yourself.
     ///
     /// # Panics
     ///
     /// This funcion panics if this entry does not have a mapped frame.
-    pub fn remove_page(&mut self) -> RawBox<Frame> {
-        let frame_addr = (self.bits & PTE_FRAMEMASK.bits) as usize;
+    pub fn remove_page(&mut self) -> (RawBox<Frame>, usize) {
+        let frame_addr = self.frame_addr();
         assert!(frame_addr != 0);
         self.clear();
+        let refs = phys::put_ref(frame_addr);
 
         // We know it's safe to construct this owned pointer because if we know we put a unique
         // pointer INTO the page table.
-        unsafe { Frame::from_addr(frame_addr) }
+        (unsafe { Frame::from_addr(frame_addr) }, refs)
     }
 
-    /// Borrows the frame from the page table entry. 
+    /// Returns the physical address of the mapped frame.
+    pub fn frame_addr(&self) -> usize {
+        (self.bits & PTE_FRAMEMASK.bits) as usize
+    }
+
+    /// Borrows the frame from the page table entry.
     ///
     /// This is safe because the entry owns the frame pointer.
     ///
@@ -164,7 +315,7 @@ impl PageTableEntry {
     ///
     /// This function panics if this entry does not have a mapped frame.
     pub fn borrow_frame(&self) -> &Frame {
-        let frame_addr = (self.bits & PTE_FRAMEMASK.bits) as usize;
+        let frame_addr = self.frame_addr();
         assert!(frame_addr != 0);
         unsafe { &*(frame_addr as *mut Frame) }
     }
@@ -175,36 +326,70 @@ impl PageTableEntry {
     ///
     /// This function panics if this entry does not have a mapped frame.
     pub fn borrow_frame_mut(&mut self) -> &mut Frame {
-        let frame_addr = (self.bits & PTE_FRAMEMASK.bits) as usize;
+        let frame_addr = self.frame_addr();
         assert!(frame_addr != 0);
         unsafe { &mut*(frame_addr as *mut Frame) }
     }
 
 }
 
+/// A page directory. With PAE this is the four page directories in consecutive pages, so it
+/// takes up `pd_pages()` pages. Entries are always indexed across the whole address space.
+///
+/// How much memory a page directory takes up depends on the paging mode, so the type has no size
+/// of its own. References to it only mark where the entries start and they are always accessed
+/// through raw pointers.
 pub struct PageDirectory {
-    pdes: [PageDirectoryEntry; 1024] 
+    _entries: [u64; 0],
 }
 
 impl PageDirectory {
-    
-    /// Tries to allocate a new, cleared page directory from the free frame list.
+
+    /// Tries to allocate a new, cleared page directory from the free frame list. The returned box
+    /// holds the physical address of the page directory.
+    ///
+    /// The frames come from the DMA zone and are written to through the kernel's direct map, so
+    /// this may only be used while building the kernel's page directory during boot. Use
+    /// `AddressSpace::new` afterwards.
     pub fn new() -> Option<RawBox<PageDirectory>> {
-        phys::get_frame().map(|f| {
-            let mut pd: RawBox<PageDirectory> = f.allocate();
-            unsafe { pd.clear() }; // This is safe because there is nothing mapped in.
-            pd
+        phys::alloc_frames_in(Zone::Dma, pd_order()).map(|f| {
+            let addr = &*f as *const Frame as usize;
+            for page in 0 .. pd_pages() {
+                if let Some(desc) = phys::lookup(addr + page * PAGE_SIZE) {
+                    desc.set_owner(phys::OWNER_PAGETABLE);
+                }
+            }
+            // This is safe because there is nothing mapped in.
+            unsafe { (*(phys_to_virt(addr) as *mut PageDirectory)).clear() };
+            f.allocate()
         })
     }
 
+    // Returns the address the entries start at.
+    fn table(&self) -> usize {
+        self as *const PageDirectory as usize
+    }
+
+    // Returns a copy of entry `idx`.
+    fn pde(&self, idx: usize) -> PageDirectoryEntry {
+        assert!(idx < pde_count());
+        PageDirectoryEntry::from_bits_truncate(unsafe { read_entry(self.table(), idx) })
+    }
+
+    // Overwrites entry `idx`. This does not touch reference counts or the TLB.
+    fn set_pde_at(&mut self, idx: usize, pde: PageDirectoryEntry) {
+        assert!(idx < pde_count());
+        unsafe { write_entry(self.table(), idx, pde.bits()) }
+    }
+
     /// Removes all mappings and marks all entries as not present.
     ///
     /// # Safety
     ///
     /// This is unsafe because it will leak any page tables that are currently mapped.
     pub unsafe fn clear(&mut self) {
-        for pde in self.pdes.as_mut() {
-            pde.clear()
+        for idx in 0 .. pde_count() {
+            self.set_pde_at(idx, PageDirectoryEntry::empty());
         }
     }
 
@@ -215,16 +400,142 @@ impl PageDirectory {
     /// This function panics if th address already has a page table mapped.
     pub fn map_pagetable(&mut self, addr: usize, pt: RawBox<PageTable>, flags: PageDirectoryEntry) {
         assert!(!self.has_pagetable(addr));
-        let pde = addr_to_pde(addr);
-        self.pdes[pde].clear();
-        self.pdes[pde].set_pagetable(pt);
-        self.pdes[pde].insert(flags | PDE_PRESENT);
+        assert!(!self.is_large(addr));
+        let mut pde = PageDirectoryEntry::empty();
+        pde.set_pagetable(pt);
+        pde.insert(flags | PDE_PRESENT);
+        self.set_pde(addr, pde);
+    }
+
+    /// Maps the page directory at the physical address `pd_addr` as the page tables of the window
+    /// starting at `window`. The page tables of that page directory then appear in the window.
+    /// This is how the recursive and the foreign windows are set up.
+    pub fn map_window(&mut self, window: usize, pd_addr: usize) {
+        for page in 0 .. pd_pages() {
+            let frame = (pd_addr + page * PAGE_SIZE) as u64;
+            let pde = PageDirectoryEntry::from_bits_truncate(frame) | PDE_PRESENT | PDE_WRITABLE |
+                      PDE_NOEXEC;
+            self.set_pde_at(addr_to_pde(window) + page, pde);
+        }
+    }
+
+    /// Unmaps the page directory mapped at `window` by `map_window`.
+    pub fn unmap_window(&mut self, window: usize) {
+        for page in 0 .. pd_pages() {
+            self.set_pde_at(addr_to_pde(window) + page, PageDirectoryEntry::empty());
+        }
     }
 
-    /// Returns whether a specified address has a page table or not.
+    /// Returns whether a specified address has a page table or not. Addresses in a large page do
+    /// not have a page table.
     pub fn has_pagetable(&self, addr: usize) -> bool {
-        let pde = addr_to_pde(addr);
-        self.pdes[pde].contains(PDE_PRESENT)
+        let pde = self.get_pde(addr);
+        pde.contains(PDE_PRESENT) && !pde.contains(PDE_LARGE)
+    }
+
+    /// Returns whether a specified address is part of a mapped large page.
+    pub fn is_large(&self, addr: usize) -> bool {
+        self.get_pde(addr).contains(PDE_PRESENT | PDE_LARGE)
+    }
+
+    /// Maps the large page starting at `frame` to the address `addr` with the given flags. Both
+    /// must be aligned to `pde_mapped_size()`. A reference is taken on the first frame of the large
+    /// page.
+    ///
+    /// Without PAE, large pages require `asm::enable_4mb_pages` before they are used.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if either address is not aligned or the address already has a page
+    /// table or large page mapped.
+    pub fn map_large(&mut self, addr: usize, frame: RawBox<Frame>, flags: PageDirectoryEntry) {
+        assert!(is_aligned!(addr, pde_mapped_size()));
+        assert!(!self.has_pagetable(addr) && !self.is_large(addr));
+        let frame_addr = frame.into_raw() as usize;
+        phys::get_ref(frame_addr);
+        let mut pde = flags | PDE_LARGE | PDE_PRESENT;
+        pde.set_large_frame(frame_addr);
+        self.set_pde(addr, pde);
+    }
+
+    /// Unmaps the large page at `addr`, invalidates its TLB entry and returns its first frame
+    /// along with the number of references to it that are left.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the address is not part of a mapped large page.
+    pub fn unmap_large(&mut self, addr: usize) -> (RawBox<Frame>, usize) {
+        assert!(self.is_large(addr));
+        let frame_addr = self.get_pde(addr).frame_addr();
+        self.set_pde(addr, PageDirectoryEntry::empty());
+        let refs = phys::put_ref(frame_addr);
+        if self.is_active() {
+            invlpg(addr);
+        }
+        // We know this is safe because the large page was owned by the entry.
+        (unsafe { Frame::from_addr(frame_addr) }, refs)
+    }
+
+    /// Makes sure the specified address has a page table, allocating and mapping a cleared one
+    /// with the given flags if it does not.
+    ///
+    /// The new page table is cleared through the page directory's window so this works both
+    /// before paging is enabled and on page directories accessed through an `AddressSpace`.
+    ///
+    /// # Failures
+    ///
+    /// Fails if there are no free frames for the page table.
+    pub fn ensure_pagetable(&mut self, addr: usize, flags: PageDirectoryEntry) -> KernResult<()> {
+        if self.has_pagetable(addr) {
+            return Ok(());
+        }
+        let frame = try!(phys::get_frame().ok_or(KernError::OutOfMemory));
+        if let Some(desc) = phys::lookup(&*frame as *const Frame as usize) {
+            desc.set_owner(phys::OWNER_PAGETABLE);
+        }
+        self.map_pagetable(addr, frame.allocate(), flags);
+
+        // We know this is safe because the page table is brand new.
+        let window = self.pagetable_addr(addr_to_pde(addr));
+        invlpg(window);
+        unsafe { (*(window as *mut PageTable)).clear() };
+        Ok(())
+    }
+
+    // Returns the virtual address at which the page table of entry `idx` can be accessed.
+    //
+    // If this page directory is seen through one of the recursive windows, the page table is
+    // accessed through the matching window. Otherwise the page directory is being built during
+    // boot and the page table is accessed through the kernel's direct map.
+    fn pagetable_addr(&self, idx: usize) -> usize {
+        let table = self.table();
+        if table == active_pd_addr() {
+            recmap_addr() + idx * PAGE_SIZE
+        } else if table == foreign_pd_addr() {
+            foreign_recmap_addr() + idx * PAGE_SIZE
+        } else {
+            phys_to_virt(self.pde(idx).frame_addr())
+        }
+    }
+
+    /// Borrows the page table for the given address.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the address does not have a page table.
+    pub fn borrow_pagetable(&self, addr: usize) -> &PageTable {
+        assert!(self.has_pagetable(addr));
+        unsafe { &*(self.pagetable_addr(addr_to_pde(addr)) as *const PageTable) }
+    }
+
+    /// Mutably borrows the page table for the given address.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the address does not have a page table.
+    pub fn borrow_pagetable_mut(&mut self, addr: usize) -> &mut PageTable {
+        assert!(self.has_pagetable(addr));
+        unsafe { &mut *(self.pagetable_addr(addr_to_pde(addr)) as *mut PageTable) }
     }
 
     /// Maps a frame for the specified address with the given flag.
@@ -235,68 +546,400 @@ impl PageDirectory {
     pub fn map_page(&mut self, addr: usize, frame: RawBox<Frame>, flags: PageTableEntry) {
         assert!(self.has_pagetable(addr));
         assert!(!self.has_page(addr));
-        let pde = addr_to_pde(addr);
-        self.pdes[pde].borrow_pagetable_mut().map_page(addr, frame, flags)
+        self.borrow_pagetable_mut(addr).map_page(addr, frame, flags)
     }
 
     /// Returns whether a specified address has a mapped frame or not.
     pub fn has_page(&self, addr: usize) -> bool {
-        let pde = addr_to_pde(addr);
-        self.pdes[pde].borrow_pagetable().has_page(addr)
+        self.borrow_pagetable(addr).has_page(addr)
     }
 
-    /// Converts this page directory into a page table. 
-    ///
-    /// # Safety
-    ///
-    /// This function is unsafe because the caller needs to ensure they use properly map this page
-    /// table. TODO it may be better to not expose this functionality and instead just provide a
-    /// `map_recursive` function.
-    pub unsafe fn as_pagetable(&mut self) -> RawBox<PageTable> {
-        RawBox::from_raw(self as *mut PageDirectory as *mut PageTable)
+    /// Returns a copy of the page table entry for the given address if it has a mapped frame.
+    pub fn get_pte(&self, addr: usize) -> Option<PageTableEntry> {
+        if self.has_pagetable(addr) && self.has_page(addr) {
+            Some(self.borrow_pagetable(addr).entry(addr_to_pte(addr)))
+        } else {
+            None
+        }
+    }
+
+    /// Returns a copy of the page directory entry for the given address.
+    pub fn get_pde(&self, addr: usize) -> PageDirectoryEntry {
+        self.pde(addr_to_pde(addr))
+    }
+
+    /// Overwrites the page directory entry for the given address. This does not touch reference
+    /// counts or the TLB.
+    pub fn set_pde(&mut self, addr: usize, pde: PageDirectoryEntry) {
+        self.set_pde_at(addr_to_pde(addr), pde)
+    }
+
+    /// Returns the physical address `addr` maps to along with the entries that map it, or `None`
+    /// if nothing is mapped at `addr`.
+    pub fn translate(&self, addr: usize) -> Option<Translation> {
+        Translation::new(self, addr)
+    }
+
+    /// Returns an iterator over everything this page directory maps, merging contiguous pages with
+    /// the same flags into a single `Mapping`.
+    pub fn mappings(&self) -> Mappings {
+        Mappings::new(self)
     }
 
     /// Removes flags from the page table for the given address.
-    /// 
+    ///
     /// # Panics
     ///
     /// This function panics if there is no page table for the given address or the flags to be
     /// removed would result in unsafe behavior (removing the present or frame mask flags).
     pub fn remove_pte_flags(&mut self, addr: usize, flags: PageTableEntry) {
-        self.pdes[addr_to_pde(addr)].borrow_pagetable_mut().remove_flags(addr, flags);
+        self.borrow_pagetable_mut(addr).remove_flags(addr, flags);
+    }
+
+    /// Shares all user pages of this page directory with `child` for copy-on-write.
+    ///
+    /// Page tables in the kernel's part of the address space are already shared and are left
+    /// alone. Every other page table is copied. Writable pages lose their writable flag and are
+    /// marked `PTE_COW` in both page directories, and every shared frame gains a reference. Large
+    /// pages are marked `PDE_COW` in the same way. The first write to such a page faults and
+    /// `copy_on_write` gives the writer its own copy.
+    ///
+    /// # Failures
+    ///
+    /// Fails if there are not enough free frames for the child's page tables. The child may then
+    /// be partially populated.
+    pub fn clone_cow(&mut self, child: &mut PageDirectory) -> KernResult<()> {
+        for pde_idx in 0 .. pde_count() {
+            if is_kernel_pde(pde_idx) || is_window_pde(pde_idx) {
+                continue;
+            }
+            let pde = self.pde(pde_idx);
+            if !pde.contains(PDE_PRESENT) {
+                continue;
+            }
+            if pde.contains(PDE_LARGE) {
+                // Large pages are shared as a whole. The reference on the first frame stands for
+                // the entire block.
+                let mut pde = pde;
+                if pde.contains(PDE_WRITABLE) {
+                    pde.remove(PDE_WRITABLE);
+                    pde.insert(PDE_COW);
+                    self.set_pde_at(pde_idx, pde);
+                }
+                phys::get_ref(pde.frame_addr());
+                child.set_pde_at(pde_idx, pde);
+                continue;
+            }
+            let addr = pde_idx << pd_shift();
+            try!(child.ensure_pagetable(addr, pde & !PDE_FRAMEMASK));
+            let pt = self.borrow_pagetable_mut(addr);
+            let child_pt = child.borrow_pagetable_mut(addr);
+            for idx in 0 .. entries_per_table() {
+                let mut pte = pt.entry(idx);
+                if !pte.contains(PTE_PRESENT) {
+                    continue;
+                }
+                if pte.contains(PTE_WRITABLE) {
+                    pte.remove(PTE_WRITABLE);
+                    pte.insert(PTE_COW);
+                    pt.set_entry(idx, pte);
+                }
+                phys::get_ref(pte.frame_addr());
+                child_pt.set_entry(idx, pte);
+            }
+        }
+
+        // Our writable pages just became read-only.
+        if self.is_active() {
+            flush_tlb();
+        }
+        Ok(())
+    }
+
+    /// Resolves a write to the copy-on-write page at `addr` by giving this page directory a
+    /// private, writable copy of it. If nobody else references the frame it is simply made
+    /// writable again.
+    ///
+    /// This must be called on the active page directory.
+    ///
+    /// # Failures
+    ///
+    /// Fails if there are no free frames for the copy.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the page is not a copy-on-write page.
+    pub fn copy_on_write(&mut self, addr: usize) -> KernResult<()> {
+        assert!(self.is_active());
+        if self.is_large(addr) {
+            return self.copy_on_write_large(addr);
+        }
+        let page = page_align(addr);
+        let pte = self.get_pte(page).expect("copy on write of an unmapped page");
+        assert!(pte.contains(PTE_COW));
+        let flags = (pte & !(PTE_FRAMEMASK | PTE_COW | PTE_PRESENT)) | PTE_WRITABLE;
+        let old_addr = pte.frame_addr();
+
+        if phys::ref_count(old_addr) == 1 {
+            // We are the last user of this frame so there is nothing to copy.
+            let pt = self.borrow_pagetable_mut(page);
+            let idx = addr_to_pte(page);
+            let mut entry = pt.entry(idx);
+            entry.remove(PTE_COW);
+            entry.insert(PTE_WRITABLE);
+            pt.set_entry(idx, entry);
+            invlpg(page);
+            return Ok(());
+        }
+
+        // Copy the page into a new frame through a temporary mapping.
+        let frame = try!(phys::get_frame().ok_or(KernError::OutOfMemory));
+        {
+            let window = kmap(&*frame as *const Frame as usize);
+            unsafe { ptr::copy_nonoverlapping(page as *const u8, window.as_ptr(), PAGE_SIZE) };
+        }
+
+        // Swap the frames in place so the page is never unmapped. If the other users dropped their
+        // references in the meantime the old frame is now ours to free.
+        let (old, refs) = {
+            let pt = self.borrow_pagetable_mut(page);
+            let idx = addr_to_pte(page);
+            let old = pt.entry(idx).remove_page();
+            let mut entry = PageTableEntry::empty();
+            entry.set_page(frame);
+            entry.insert(flags | PTE_PRESENT);
+            pt.set_entry(idx, entry);
+            old
+        };
+        invlpg(page);
+        if refs == 0 {
+            phys::return_frame(old);
+        } else {
+            old.into_raw();
+        }
+        Ok(())
+    }
+
+    // Resolves a write to the copy-on-write large page containing `addr` like `copy_on_write`.
+    fn copy_on_write_large(&mut self, addr: usize) -> KernResult<()> {
+        let start = addr & !(pde_mapped_size() - 1);
+        let pde = self.get_pde(start);
+        assert!(pde.contains(PDE_COW));
+        let old_addr = pde.frame_addr();
+        let mut entry = (pde & !(PDE_FRAMEMASK | PDE_COW)) | PDE_WRITABLE;
+
+        if phys::ref_count(old_addr) == 1 {
+            // We are the last user of this block so there is nothing to copy.
+            entry.set_large_frame(old_addr);
+            self.set_pde(start, entry);
+            invlpg(start);
+            return Ok(());
+        }
+
+        // Copy the large page into a new block one page at a time through a temporary mapping.
+        let order = large_page_order();
+        let frames = try!(phys::alloc_frames(order).ok_or(KernError::OutOfMemory));
+        let new_addr = frames.into_raw() as usize;
+        for i in 0 .. 1 << order {
+            let window = kmap(new_addr + i * PAGE_SIZE);
+            let src = (start + i * PAGE_SIZE) as *const u8;
+            unsafe { ptr::copy_nonoverlapping(src, window.as_ptr(), PAGE_SIZE) };
+        }
+
+        // Swap the blocks in place like `copy_on_write` does.
+        phys::get_ref(new_addr);
+        entry.set_large_frame(new_addr);
+        self.set_pde(start, entry);
+        invlpg(start);
+        // We know this is safe because the entry owned the old block.
+        let old = unsafe { Frame::from_addr(old_addr) };
+        if phys::put_ref(old_addr) == 0 {
+            phys::free_frames(old, order);
+        } else {
+            old.into_raw();
+        }
+        Ok(())
+    }
+
+    /// Returns whether the page at `addr` is a copy-on-write page, either a small page marked
+    /// `PTE_COW` or a large page marked `PDE_COW`.
+    pub fn is_cow(&self, addr: usize) -> bool {
+        if self.is_large(addr) {
+            self.get_pde(addr).contains(PDE_COW)
+        } else {
+            self.get_pte(addr).map_or(false, |pte| pte.contains(PTE_COW))
+        }
+    }
+
+    /// Returns whether this is the active page directory seen through the recursive window. Only
+    /// the TLB entries of the active page directory need to be invalidated.
+    fn is_active(&self) -> bool {
+        self.table() == active_pd_addr()
+    }
+
+    /// Unmaps the page at the given address, invalidates its TLB entry and returns its frame. If
+    /// this was the last page in a page table outside of the kernel's part of the address space,
+    /// the page table is returned to the frame allocator.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if there is no page mapped at the given address.
+    pub fn unmap_page(&mut self, addr: usize) -> RawBox<Frame> {
+        let (frame, _, _) = self.unmap_page_noflush(addr);
+        if self.is_active() {
+            invlpg(addr);
+        }
+        self.reclaim_pagetable(addr);
+        frame
+    }
+
+    /// Unmaps all pages in the range `[start, end)` and passes each address and frame to `op`.
+    /// Addresses without a mapped page are skipped. Large pages are skipped as well and must be
+    /// unmapped with `unmap_large`.
+    ///
+    /// Large ranges are invalidated with a single CR3 reload instead of one `invlpg` per page.
+    /// Global pages survive a CR3 reload so they are always invalidated individually.
+    pub fn unmap_range<F>(&mut self, start: usize, end: usize, mut op: F)
+                          where F: FnMut(usize, RawBox<Frame>) {
+        self.unmap_range_counted(start, end, |addr, frame, _| op(addr, frame))
+    }
+
+    /// Like `unmap_range`, but also passes `op` the number of references to each frame that are
+    /// left after unmapping it. Only frames that are left with none may be freed.
+    pub fn unmap_range_counted<F>(&mut self, start: usize, end: usize, mut op: F)
+                                  where F: FnMut(usize, RawBox<Frame>, usize) {
+        assert!(is_page_aligned(start));
+        assert!(is_page_aligned(end));
+        let active = self.is_active();
+        let reload = active && end - start > INVLPG_MAX_RANGE;
+        let mut addr = start;
+        while addr < end {
+            if !self.has_pagetable(addr) {
+                // Skip to the next page table (or the end of the address space).
+                addr = match (addr | (pde_mapped_size() - 1)).checked_add(1) {
+                    Some(next) => next,
+                    None => break,
+                };
+                continue;
+            }
+            if self.has_page(addr) {
+                let (frame, refs, global) = self.unmap_page_noflush(addr);
+                if active && (global || !reload) {
+                    invlpg(addr);
+                }
+                op(addr, frame, refs);
+            }
+            // Check whether the page table is empty once we are done with it rather than after
+            // every page, which would take time quadratic in the number of entries.
+            let next = addr.checked_add(PAGE_SIZE);
+            if next.map_or(true, |next| next >= end || addr_to_pte(next) == 0) {
+                self.reclaim_pagetable(addr);
+            }
+            addr = match next {
+                Some(next) => next,
+                None => break,
+            };
+        }
+        if reload {
+            flush_tlb();
+        }
+    }
+
+    // Unmaps the page at `addr` without touching the TLB. Returns the frame, the number of
+    // references left to it and whether the mapping was global.
+    fn unmap_page_noflush(&mut self, addr: usize) -> (RawBox<Frame>, usize, bool) {
+        assert!(self.has_pagetable(addr));
+        assert!(self.has_page(addr));
+        let pt = self.borrow_pagetable_mut(addr);
+        let idx = addr_to_pte(addr);
+        let mut pte = pt.entry(idx);
+        let global = pte.contains(PTE_GLOBAL);
+        let (frame, refs) = pte.remove_page();
+        pt.set_entry(idx, pte);
+        (frame, refs, global)
+    }
+
+    // Frees the page table that maps `addr` if it no longer maps anything. Kernel page tables are
+    // shared between all address spaces so they are never freed.
+    fn reclaim_pagetable(&mut self, addr: usize) {
+        let pde_idx = addr_to_pde(addr);
+        if is_kernel_pde(pde_idx) || !self.has_pagetable(addr) ||
+           !self.borrow_pagetable(addr).is_empty() {
+            return;
+        }
+        let window = self.pagetable_addr(pde_idx);
+        let mut pde = self.pde(pde_idx);
+        let pt_addr = pde.remove_pagetable().into_raw() as usize;
+        self.set_pde_at(pde_idx, pde);
+        if self.is_active() {
+            invlpg(window);
+        }
+        if let Some(desc) = phys::lookup(pt_addr) {
+            desc.set_owner(phys::OWNER_NONE);
+        }
+        // We know this is safe because the page table is no longer referenced.
+        phys::return_frame(unsafe { Frame::from_addr(pt_addr) });
     }
 
 }
 
 impl Debug for PageDirectory {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "PageDirectory@{:x}", self as *const PageDirectory as usize)
+        write!(f, "PageDirectory@{:x}", self.table())
     }
 }
 
+/// A page table. It holds 1024 4-byte entries, or 512 8-byte entries with PAE, so it always fills
+/// one page.
 pub struct PageTable {
-    ptes: [PageTableEntry; 1024]
+    entries: [u32; 1024],
 }
 
 impl PageTable {
 
-    /// Tries to allocate a new, cleared page table from the free frame list.
+    /// Tries to allocate a new, cleared page table from the free frame list. The returned box
+    /// holds the physical address of the page table.
+    ///
+    /// The frame comes from the DMA zone and is written to through the kernel's direct map, so
+    /// this may only be used while building the kernel's page directory during boot. Use
+    /// `PageDirectory::ensure_pagetable` afterwards.
     pub fn new() -> Option<RawBox<PageTable>> {
-        phys::get_frame().map(|f| {
-             let mut pt: RawBox<PageTable> = f.allocate();
-             unsafe { pt.clear() }; // This is safe because there is nothing mapped in.
-             pt
+        phys::get_frame_in(Zone::Dma).map(|f| {
+            let addr = &*f as *const Frame as usize;
+            if let Some(desc) = phys::lookup(addr) {
+                desc.set_owner(phys::OWNER_PAGETABLE);
+            }
+            // This is safe because there is nothing mapped in.
+            unsafe { (*(phys_to_virt(addr) as *mut PageTable)).clear() };
+            f.allocate()
         })
     }
 
+    // Returns the address the entries start at.
+    fn table(&self) -> usize {
+        self.entries.as_ptr() as usize
+    }
+
+    /// Returns a copy of entry `idx`.
+    pub fn entry(&self, idx: usize) -> PageTableEntry {
+        assert!(idx < entries_per_table());
+        PageTableEntry::from_bits_truncate(unsafe { read_entry(self.table(), idx) })
+    }
+
+    // Overwrites entry `idx`. This does not touch reference counts or the TLB.
+    fn set_entry(&mut self, idx: usize, pte: PageTableEntry) {
+        assert!(idx < entries_per_table());
+        unsafe { write_entry(self.table(), idx, pte.bits()) }
+    }
+
     /// Clears all entries in the page table.
     ///
     /// # Safety
     ///
     /// This is unsafe because it will leak any frames that are mapped in.
     pub unsafe fn clear(&mut self) {
-        for pte in self.ptes.as_mut() {
-            pte.clear()
+        for idx in 0 .. entries_per_table() {
+            self.set_entry(idx, PageTableEntry::empty());
         }
     }
 
@@ -309,17 +952,22 @@ impl PageTable {
     /// This function will panic if there is already a frame mapped to the given address.
     pub fn map_page(&mut self, addr: usize, frame: RawBox<Frame>, flags: PageTableEntry) {
         assert!(!self.has_page(addr));
-        let pte = addr_to_pte(addr);
-        self.ptes[pte].clear();
-        self.ptes[pte].set_page(frame);
-        self.ptes[pte].insert(flags | PTE_PRESENT);
+        let mut pte = PageTableEntry::empty();
+        pte.set_page(frame);
+        pte.insert(flags | PTE_PRESENT);
+        self.set_entry(addr_to_pte(addr), pte);
+    }
+
+    /// Returns whether no entries of the page table are present.
+    pub fn is_empty(&self) -> bool {
+        (0 .. entries_per_table()).all(|idx| !self.entry(idx).contains(PTE_PRESENT))
     }
 
     /// Returns whether an address has a mapped frame.
     pub fn has_page(&self, addr: usize) -> bool {
-        // Here we are assuming this is the RIGHT page table since we can't 
+        // Here we are assuming this is the RIGHT page table since we can't
         // check that the upper bits of the address correspond to this page table.
-        self.ptes[addr_to_pte(addr)].contains(PTE_PRESENT)
+        self.entry(addr_to_pte(addr)).contains(PTE_PRESENT)
     }
 
     /// Removes a set of flags from the page table entry for a given address.
@@ -333,7 +981,9 @@ impl PageTable {
     pub fn remove_flags(&mut self, addr: usize, flags: PageTableEntry) {
         assert!(self.has_page(addr));
         assert!(!flags.intersects(PTE_PRESENT | PTE_FRAMEMASK));
-        self.ptes[addr_to_pte(addr)].remove(flags);
+        let idx = addr_to_pte(addr);
+        let pte = self.entry(idx) & !flags;
+        self.set_entry(idx, pte);
     }
 
     /// Adds a set of flags from the page table entry for a given address.
@@ -347,21 +997,95 @@ impl PageTable {
     pub fn add_flags(&mut self, addr: usize, flags: PageTableEntry) {
         assert!(self.has_page(addr));
         assert!(!flags.intersects(PTE_PRESENT | PTE_FRAMEMASK));
-        self.ptes[addr_to_pte(addr)].insert(flags);
+        let idx = addr_to_pte(addr);
+        let pte = self.entry(idx) | flags;
+        self.set_entry(idx, pte);
     }
 
 }
 
 impl Debug for PageTable {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "PageTable@{:x}", self as *const PageTable as usize)
+        write!(f, "PageTable@{:x}", self.table())
+    }
+}
+
+// Points the page directory pointer table at `pdpt` at the PAE page directories starting at the
+// physical address `pd_addr`.
+unsafe fn fill_pdpt(pdpt: usize, pd_addr: usize) {
+    for i in 0 .. PAE_PDS {
+        let entry = (pd_addr + i * PAGE_SIZE) as u64 | PDPTE_PRESENT;
+        *(pdpt as *mut u64).offset(i as isize) = entry;
+    }
+}
+
+// Allocates a frame for a page directory pointer table and returns its physical address.
+fn alloc_pdpt(zone: Zone) -> KernResult<usize> {
+    let frame = try!(phys::get_frame_in(zone).ok_or(KernError::OutOfMemory));
+    let addr = frame.into_raw() as usize;
+    if let Some(desc) = phys::lookup(addr) {
+        desc.set_owner(phys::OWNER_PAGETABLE);
     }
+    Ok(addr)
 }
 
-/// Initializes the virtual memory module. This merely asserts that page tables and page
-/// directories were compiled to the correct size for x86 systems.
+/// Returns the value to load into CR3 to use the kernel's page directory at the physical address
+/// `pd_addr`. Like `PageDirectory::new` this may only be used while building the kernel's page
+/// directory during boot.
+pub fn boot_cr3(pd_addr: usize) -> usize {
+    if !is_pae() {
+        return pd_addr;
+    }
+    let pdpt = alloc_pdpt(Zone::Dma).expect("unable to allocate page directory pointer table");
+    // We know this is safe because we own the frame and it is in the direct map.
+    unsafe { fill_pdpt(phys_to_virt(pdpt), pd_addr) };
+    pdpt
+}
+
+/// Tries to create the value to load into CR3 to use the page directory at the physical address
+/// `pd_addr`. This is the page directory itself unless PAE is in use, in which case a page
+/// directory pointer table is allocated for it. Release it with `free_cr3`.
+///
+/// # Failures
+///
+/// Fails if PAE is in use and there are no free frames for the page directory pointer table.
+pub fn new_cr3(pd_addr: usize) -> KernResult<usize> {
+    if !is_pae() {
+        return Ok(pd_addr);
+    }
+    let pdpt = try!(alloc_pdpt(Zone::Normal));
+    let window = kmap(pdpt);
+    // We know this is safe because we own the frame.
+    unsafe { fill_pdpt(window.addr(), pd_addr) };
+    Ok(pdpt)
+}
+
+/// Releases what `new_cr3` allocated for the page directory at `pd_addr`.
+pub fn free_cr3(cr3: usize, pd_addr: usize) {
+    if cr3 == pd_addr {
+        return;
+    }
+    if let Some(desc) = phys::lookup(cr3) {
+        desc.set_owner(phys::OWNER_NONE);
+    }
+    // We know this is safe because nothing refers to the page directory pointer table anymore.
+    phys::return_frame(unsafe { Frame::from_addr(cr3) });
+}
+
+/// Initializes the virtual memory module. This records which paging mode the boot code chose and
+/// asserts that page tables and page directories were compiled to the expected size.
 pub fn init() {
+    // We know this is safe because nothing has looked at the paging mode yet.
+    unsafe { PAE = pae_enabled() };
+
     // In light of static_assert being removed, this will have to do.
     assert!(mem::size_of::<PageTable>() == PAGE_SIZE);
-    assert!(mem::size_of::<PageDirectory>() == PAGE_SIZE);
+    assert!(mem::size_of::<PageDirectory>() == 0);
+}
+
+/// Finishes initializing the virtual memory module once paging is enabled in the kernel's address
+/// space. This creates the shared page tables used by `kmap` and `vmalloc`.
+pub fn init_paged() {
+    kmap::init();
+    vmalloc::init();
 }
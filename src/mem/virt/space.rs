@@ -0,0 +1,321 @@
+//!
+//! Address spaces.
+//!
+//! Once paging is enabled page directories and page tables can no longer be accessed through their
+//! physical addresses. Instead, every page directory maps itself in its last entries so that the
+//! active page directory is visible at `active_pd_addr()` and its page tables are visible in the
+//! window starting at `recmap_addr()`.
+//!
+//! To edit an address space that is not active we temporarily point the entries below those at
+//! the foreign page directory. It then appears at `foreign_pd_addr()` and its page tables appear
+//! in the window starting at `foreign_recmap_addr()`. Only one foreign address space can be mapped
+//! at once so this is protected by a lock.
+//!
+//! With PAE, CR3 points at a page directory pointer table rather than at the page directory, so
+//! every address space keeps both.
+//!
+//! Address spaces also keep a list of the virtual memory areas they contain (see `mem::vma`). The
+//! page fault handler uses it to back pages on demand in the address space last activated with
+//! `switch_to`.
+//!
+use core::prelude::*;
+use core::fmt;
+use core::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use core::fmt::{Debug, Formatter};
+use mutex::Mutex;
+use phys;
+use phys::Frame;
+use util::{KernResult, KernError, PAGE_SIZE};
+use util::rawbox::RawBox;
+use util::asm::{get_cr3, set_cr3, flush_tlb, invlpg};
+use super::{PageDirectory, PageDirectoryEntry, PageTableEntry};
+use vma::{Vma, VmaList, Backing};
+use super::{PDE_WRITABLE, PDE_SUPERVISOR};
+use super::{is_kernel_pde, is_window_pde, pde_count, pd_pages, pd_order, pd_shift};
+use super::{pde_mapped_size, large_page_order, recmap_addr, foreign_recmap_addr};
+use super::{foreign_pd_addr, active_pd, new_cr3, free_cr3};
+
+// Protects the foreign window of the active page directory.
+static FOREIGN_LOCK: Mutex<()> = Mutex::new(());
+
+// The address of the mutex around the address space last activated with `switch_to`, or 0.
+static CURRENT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Makes the address space in `space` the active one and the one page faults are resolved
+/// against.
+///
+/// # Safety
+///
+/// `space` must not be moved or dropped until another address space has been switched to.
+pub unsafe fn switch_to(space: &Mutex<AddressSpace>) {
+    space.lock().activate();
+    CURRENT.store(space as *const Mutex<AddressSpace> as usize, Ordering::SeqCst);
+}
+
+/// Returns the address space last activated with `switch_to`, if any.
+pub fn current_space() -> Option<&'static Mutex<AddressSpace>> {
+    match CURRENT.load(Ordering::SeqCst) {
+        0 => None,
+        // We know this is safe because `switch_to` requires the mutex to stay put while current.
+        addr => Some(unsafe { &*(addr as *const Mutex<AddressSpace>) }),
+    }
+}
+
+/// An address space. This owns a page directory which is referred to by its physical address, and
+/// with PAE the page directory pointer table that CR3 points to.
+pub struct AddressSpace {
+    pd: usize,
+    cr3: usize,
+    vmas: VmaList,
+}
+
+impl AddressSpace {
+
+    /// Tries to create a new address space. The new address space shares the kernel's page tables
+    /// with the active address space and has nothing else mapped.
+    ///
+    /// # Failures
+    ///
+    /// Fails if there are no free frames for the page directory or its page directory pointer
+    /// table, or the VMA list cannot be allocated.
+    pub fn new() -> KernResult<AddressSpace> {
+        let vmas = try!(VmaList::new());
+        let frame = try!(phys::alloc_frames(pd_order()).ok_or(KernError::OutOfMemory));
+        let pd_addr = frame.into_raw() as usize;
+        let cr3 = match new_cr3(pd_addr) {
+            Ok(cr3) => cr3,
+            Err(e) => {
+                // We know this is safe because nothing refers to the page directory yet.
+                phys::free_frames(unsafe { Frame::from_addr(pd_addr) }, pd_order());
+                return Err(e);
+            }
+        };
+        for page in 0 .. pd_pages() {
+            if let Some(desc) = phys::lookup(pd_addr + page * PAGE_SIZE) {
+                desc.set_owner(phys::OWNER_PAGETABLE);
+            }
+        }
+
+        // The new page directory is full of garbage so we may only touch it through its own pages,
+        // never through the foreign page table window.
+        let space = AddressSpace { pd: pd_addr, cr3: cr3, vmas: vmas };
+        space.with_pd(|pd| {
+            // We know this is safe because nothing is mapped in yet.
+            unsafe { pd.clear() };
+
+            // Share the kernel's page tables.
+            let active = active_pd();
+            for i in 0 .. pde_count() {
+                if is_kernel_pde(i) {
+                    pd.set_pde_at(i, active.pde(i));
+                }
+            }
+
+            // Finally map the page directory into itself.
+            pd.map_window(recmap_addr(), pd_addr);
+        });
+        Ok(space)
+    }
+
+    /// Creates an address space from a page directory which already maps itself recursively and
+    /// is loaded by the CR3 value `cr3`.
+    ///
+    /// # Safety
+    ///
+    /// This is unsafe because the caller must ensure that the page directory has a recursive
+    /// mapping and is not owned by any other address space.
+    ///
+    /// # Failures
+    ///
+    /// Fails if the VMA list cannot be allocated.
+    pub unsafe fn from_raw(pd: RawBox<PageDirectory>, cr3: usize) -> KernResult<AddressSpace> {
+        Ok(AddressSpace {
+            pd: pd.into_raw() as usize,
+            cr3: cr3,
+            vmas: try!(VmaList::new()),
+        })
+    }
+
+    /// Returns the value to load into CR3 to activate this address space. This is the physical
+    /// address of the page directory, or of the page directory pointer table with PAE.
+    pub fn cr3(&self) -> usize {
+        self.cr3
+    }
+
+    /// Returns whether this is the active address space.
+    pub fn is_active(&self) -> bool {
+        get_cr3() == self.cr3
+    }
+
+    // Makes this the active address space. Use `switch_to` so page faults are resolved here.
+    fn activate(&self) {
+        if !self.is_active() {
+            set_cr3(self.cr3);
+        }
+    }
+
+    /// Calls `op` with a mutable reference to the page directory of this address space. If this is
+    /// not the active address space, it is mapped into the foreign window for the duration of the
+    /// call. Paging must be enabled.
+    pub fn with_pd<F, R>(&self, op: F) -> R where F: FnOnce(&mut PageDirectory) -> R {
+        if self.is_active() {
+            return op(active_pd());
+        }
+
+        let _guard = FOREIGN_LOCK.lock();
+        let active = active_pd();
+        assert!(!active.has_pagetable(foreign_recmap_addr()));
+        active.map_window(foreign_recmap_addr(), self.pd);
+        flush_tlb();
+
+        // We know this is safe because the foreign page directory now appears here.
+        let res = op(unsafe { &mut *(foreign_pd_addr() as *mut PageDirectory) });
+
+        active.unmap_window(foreign_recmap_addr());
+        flush_tlb();
+        res
+    }
+
+    /// Creates a copy-on-write clone of this address space. User pages are shared read-only
+    /// between both address spaces until one of them writes to a page. The VMA list is copied as
+    /// well.
+    ///
+    /// This must be the active address space.
+    ///
+    /// # Failures
+    ///
+    /// Fails if there is not enough memory for the new page directory, its page tables or its
+    /// VMA list.
+    pub fn clone_cow(&mut self) -> KernResult<AddressSpace> {
+        assert!(self.is_active());
+        let mut child = try!(AddressSpace::new());
+        child.vmas = try!(self.vmas.clone());
+        try!(child.with_pd(|child_pd| active_pd().clone_cow(child_pd)));
+        Ok(child)
+    }
+
+    /// Maps a frame at the specified address with the given flags, allocating a page table for it
+    /// if necessary.
+    ///
+    /// Page tables for the kernel's part of the address space are shared between all address
+    /// spaces so they must already exist.
+    ///
+    /// # Failures
+    ///
+    /// Fails if a page table is needed and there are no free frames for it.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the address already has a mapped frame.
+    pub fn map_page(&mut self, addr: usize, frame: RawBox<Frame>, flags: PageTableEntry)
+                    -> KernResult<()> {
+        self.with_pd(|pd| {
+            try!(pd.ensure_pagetable(addr, PDE_SUPERVISOR | PDE_WRITABLE));
+            pd.map_page(addr, frame, flags);
+            Ok(())
+        })
+    }
+
+    /// Unmaps the page at the specified address and returns its frame. Empty page tables outside
+    /// of the kernel's part of the address space are returned to the frame allocator.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if there is no page mapped at the given address.
+    pub fn unmap_page(&mut self, addr: usize) -> RawBox<Frame> {
+        self.with_pd(|pd| pd.unmap_page(addr))
+    }
+
+    /// Unmaps all pages in the range `[start, end)` and passes each address and frame to `op`.
+    pub fn unmap_range<F>(&mut self, start: usize, end: usize, op: F)
+                          where F: FnMut(usize, RawBox<Frame>) {
+        self.with_pd(|pd| pd.unmap_range(start, end, op))
+    }
+
+    /// Returns whether the specified address has a mapped frame.
+    pub fn has_page(&self, addr: usize) -> bool {
+        self.with_pd(|pd| pd.has_pagetable(addr) && pd.has_page(addr))
+    }
+
+    /// Returns a copy of the page directory entry for the specified address.
+    pub fn get_pde(&self, addr: usize) -> PageDirectoryEntry {
+        self.with_pd(|pd| pd.get_pde(addr))
+    }
+
+    /// Removes flags from the page table entry for the given address.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if there is no page mapped at the given address.
+    pub fn remove_pte_flags(&mut self, addr: usize, flags: PageTableEntry) {
+        self.with_pd(|pd| pd.remove_pte_flags(addr, flags));
+        if self.is_active() {
+            invlpg(addr);
+        }
+    }
+
+    /// Borrows the list of virtual memory areas.
+    pub fn vmas(&self) -> &VmaList {
+        &self.vmas
+    }
+
+    /// Mutably borrows the list of virtual memory areas.
+    pub fn vmas_mut(&mut self) -> &mut VmaList {
+        &mut self.vmas
+    }
+
+}
+
+impl Drop for AddressSpace {
+    /// Unmaps every page outside of the kernel's part of the address space and frees the page
+    /// tables and the page directory. Frames whose last reference goes away are returned to the
+    /// frame allocator unless they belong to a device area. Large pages are assumed to be blocks
+    /// of `large_page_order()`.
+    fn drop(&mut self) {
+        assert!(!self.is_active());
+        let vmas = &self.vmas;
+        let is_device = |addr| match vmas.lookup(addr) {
+            Some(&Vma { backing: Backing::Device { .. }, .. }) => true,
+            _ => false,
+        };
+        self.with_pd(|pd| {
+            for pde in 0 .. pde_count() {
+                if is_kernel_pde(pde) || is_window_pde(pde) {
+                    continue;
+                }
+                let start = pde << pd_shift();
+                if pd.is_large(start) {
+                    let (frames, refs) = pd.unmap_large(start);
+                    if refs == 0 && !is_device(start) {
+                        phys::free_frames(frames, large_page_order());
+                    } else {
+                        frames.into_raw();
+                    }
+                } else if pd.has_pagetable(start) {
+                    let end = start + pde_mapped_size();
+                    pd.unmap_range_counted(start, end, |addr, frame, refs| {
+                        if refs == 0 && !is_device(addr) {
+                            phys::return_frame(frame);
+                        } else {
+                            frame.into_raw();
+                        }
+                    });
+                }
+            }
+        });
+        free_cr3(self.cr3, self.pd);
+        for page in 0 .. pd_pages() {
+            if let Some(desc) = phys::lookup(self.pd + page * PAGE_SIZE) {
+                desc.set_owner(phys::OWNER_NONE);
+            }
+        }
+        // We know this is safe because nothing refers to the page directory anymore.
+        phys::free_frames(unsafe { Frame::from_addr(self.pd) }, pd_order());
+    }
+}
+
+impl Debug for AddressSpace {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "AddressSpace(cr3: 0x{:x})", self.cr3)
+    }
+}
@@ -0,0 +1,203 @@
+//!
+//! Large virtually contiguous kernel allocations.
+//!
+//! The kernel heap lives in a fixed window of the kernel's image so it cannot hold anything big.
+//! `vmalloc` instead reserves a range of the vmalloc area, a part of the kernel's address space
+//! reserved for this purpose, and backs it page by page with frames that need not be contiguous.
+//!
+//! Every allocation is followed by an unmapped guard page so running off the end of one faults
+//! instead of corrupting its neighbour. Since no allocation can end right below another one, the
+//! page below every allocation is never mapped either, which is what thread stacks rely on.
+//!
+//! The page tables for the vmalloc area are created at boot and shared by every address space, so
+//! an allocation is usable no matter which address space is active. Free ranges are tracked in a
+//! bitmap rather than on the heap. Device mappings made by `ioremap` live in the same area.
+//!
+use core::prelude::*;
+use core::ops::{Deref, DerefMut};
+use core::{mem, slice};
+use mutex::Mutex;
+use phys;
+use util::{KernResult, KernError, PAGE_SIZE};
+use util::asm::invlpg;
+use super::{PDE_SUPERVISOR, PDE_WRITABLE, PTE_WRITABLE, PTE_GLOBAL, PTE_NOEXEC};
+use super::pde_mapped_size;
+use super::active_pd;
+logger_init!(Trace);
+
+/// The start of the vmalloc area.
+pub const VMALLOC_START: usize = 0xF0000000;
+
+/// The end of the vmalloc area.
+pub const VMALLOC_END: usize = 0xF4000000;
+
+// The number of pages in the vmalloc area.
+const VMALLOC_PAGES: usize = (VMALLOC_END - VMALLOC_START) / PAGE_SIZE;
+
+// The number of pages tracked by each word of the bitmap.
+const BITS_PER_WORD: usize = 32;
+
+// A bitmap of the pages in the vmalloc area that are reserved, guard pages included.
+static RESERVED: Mutex<[u32; VMALLOC_PAGES / BITS_PER_WORD]> =
+    Mutex::new([0; VMALLOC_PAGES / BITS_PER_WORD]);
+
+fn is_reserved(bitmap: &[u32], page: usize) -> bool {
+    bitmap[page / BITS_PER_WORD] & (1 << (page % BITS_PER_WORD)) != 0
+}
+
+fn set_reserved(bitmap: &mut [u32], start: usize, count: usize, reserved: bool) {
+    for page in start .. start + count {
+        if reserved {
+            bitmap[page / BITS_PER_WORD] |= 1 << (page % BITS_PER_WORD);
+        } else {
+            bitmap[page / BITS_PER_WORD] &= !(1 << (page % BITS_PER_WORD));
+        }
+    }
+}
+
+/// Reserves `count` consecutive pages of the vmalloc area followed by a guard page and returns the
+/// address of the first page. Nothing is mapped in the range.
+pub fn reserve(count: usize) -> Option<usize> {
+    let mut bitmap = RESERVED.lock();
+    let mut run = 0;
+    for page in 0 .. VMALLOC_PAGES {
+        if is_reserved(&*bitmap, page) {
+            run = 0;
+            continue;
+        }
+        run += 1;
+        if run == count + 1 {
+            let start = page - count;
+            set_reserved(&mut *bitmap, start, count + 1, true);
+            return Some(VMALLOC_START + start * PAGE_SIZE);
+        }
+    }
+    None
+}
+
+/// Releases `count` pages starting at `addr` along with their guard page. The range must have been
+/// returned by `reserve` and everything in it must already be unmapped.
+pub fn release(addr: usize, count: usize) {
+    let start = (addr - VMALLOC_START) / PAGE_SIZE;
+    set_reserved(&mut *RESERVED.lock(), start, count + 1, false);
+}
+
+// Unmaps `count` pages starting at `addr` and returns their frames to the frame allocator.
+fn unmap_pages(addr: usize, count: usize) {
+    let pd = active_pd();
+    for page in (addr .. addr + count * PAGE_SIZE).step_by(PAGE_SIZE) {
+        phys::return_frame(pd.unmap_page(page));
+    }
+}
+
+/// An owned, virtually contiguous allocation from the vmalloc area. Its pages are unmapped and
+/// returned to the frame allocator when it is dropped.
+pub struct VBox {
+    addr: usize,
+    pages: usize,
+}
+
+impl VBox {
+
+    /// Returns the address of the start of the allocation.
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// Returns the size of the allocation in bytes. This is always a multiple of the page size.
+    pub fn size(&self) -> usize {
+        self.pages * PAGE_SIZE
+    }
+
+    /// Returns a pointer to the start of the allocation.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.addr as *mut u8
+    }
+
+    /// Consumes the box without freeing it and returns the address of the allocation.
+    pub fn into_raw(self) -> usize {
+        let addr = self.addr;
+        mem::forget(self);
+        addr
+    }
+
+    /// Takes ownership of an allocation given up with `into_raw`.
+    ///
+    /// # Unsafety
+    ///
+    /// `addr` and `size` must be the address and size of an allocation given up with `into_raw`,
+    /// and nothing else may own it.
+    pub unsafe fn from_raw(addr: usize, size: usize) -> VBox {
+        VBox { addr: addr, pages: size / PAGE_SIZE }
+    }
+
+}
+
+impl Deref for VBox {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        // We know this is safe because the whole range is mapped for as long as we own it.
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.size()) }
+    }
+}
+
+impl DerefMut for VBox {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_ptr(), self.size()) }
+    }
+}
+
+impl Drop for VBox {
+    fn drop(&mut self) {
+        unmap_pages(self.addr, self.pages);
+        release(self.addr, self.pages);
+    }
+}
+
+/// Tries to allocate at least `size` bytes of virtually contiguous kernel memory. The contents of
+/// the allocation are undefined.
+///
+/// # Failures
+///
+/// Fails with `OutOfMemory` if there is no free range of the vmalloc area that is large enough or
+/// there are not enough free frames to back it.
+///
+/// # Panics
+///
+/// This function panics if `size` is zero.
+pub fn vmalloc(size: usize) -> KernResult<VBox> {
+    assert!(size > 0);
+    let pages = align_up!(size, PAGE_SIZE) / PAGE_SIZE;
+    let addr = try!(reserve(pages).ok_or(KernError::OutOfMemory));
+
+    let pd = active_pd();
+    for i in 0 .. pages {
+        let page = addr + i * PAGE_SIZE;
+        let frame = match phys::get_frame() {
+            Some(frame) => frame,
+            None => {
+                trace!("out of frames after {} of {} pages", i, pages);
+                unmap_pages(addr, i);
+                release(addr, pages);
+                return Err(KernError::OutOfMemory);
+            }
+        };
+        pd.map_page(page, frame, PTE_WRITABLE | PTE_GLOBAL | PTE_NOEXEC);
+        invlpg(page);
+    }
+    Ok(VBox { addr: addr, pages: pages })
+}
+
+/// Frees an allocation returned by `vmalloc`. This is the same as dropping it.
+pub fn vfree(vbox: VBox) {
+    drop(vbox);
+}
+
+/// Creates the page tables for the vmalloc area in the kernel's address space. This must be done
+/// before any other address spaces are created.
+pub fn init() {
+    for addr in (VMALLOC_START .. VMALLOC_END).step_by(pde_mapped_size()) {
+        active_pd().ensure_pagetable(addr, PDE_SUPERVISOR | PDE_WRITABLE)
+                   .expect("unable to allocate vmalloc page table");
+    }
+}
@@ -0,0 +1,204 @@
+//!
+//! Page table introspection.
+//!
+//! These types describe what a page directory maps without changing it. `PageDirectory::translate`
+//! resolves a single address and `PageDirectory::mappings` walks everything that is present,
+//! merging neighbouring pages into runs so a dump of an address space stays short.
+//!
+use core::prelude::*;
+use core::fmt;
+use core::fmt::{Debug, Formatter};
+use util::{page_align, PAGE_SIZE};
+use super::{PageDirectory, PageDirectoryEntry, PageTableEntry};
+use super::{PDE_PRESENT, PDE_WRITABLE, PDE_SUPERVISOR, PDE_LARGE, PDE_FRAMEMASK, PDE_NOEXEC};
+use super::{PTE_PRESENT, PTE_WRITABLE, PTE_SUPERVISOR, PTE_WRITETHROUGH, PTE_CACHEDISABLE};
+use super::{PTE_GLOBAL, PTE_COW, PTE_NOEXEC, PTE_FRAMEMASK};
+use super::{pde_mapped_size, addr_to_pde, is_window_pde};
+
+// The flags that decide whether two mapped pages behave the same. The accessed and dirty flags are
+// left out since they change behind our back.
+fn behaviour(flags: PageTableEntry) -> PageTableEntry {
+    flags & (PTE_PRESENT | PTE_WRITABLE | PTE_SUPERVISOR | PTE_WRITETHROUGH | PTE_CACHEDISABLE |
+             PTE_GLOBAL | PTE_COW | PTE_NOEXEC)
+}
+
+/// Where a virtual address maps to and the entries involved in mapping it.
+#[derive(Clone, Copy)]
+pub struct Translation {
+    /// The physical address the virtual address maps to.
+    pub phys: usize,
+    /// The flags of the page directory entry without the frame address.
+    pub pde: PageDirectoryEntry,
+    /// The flags of the page table entry without the frame address. This is empty for addresses
+    /// in a large page.
+    pub pte: PageTableEntry,
+}
+
+impl Translation {
+
+    /// Translates `addr` using the entries of a page directory. Returns `None` if nothing is
+    /// mapped at `addr`.
+    pub fn new(pd: &PageDirectory, addr: usize) -> Option<Translation> {
+        let pde = pd.get_pde(addr);
+        if !pde.contains(PDE_PRESENT) {
+            None
+        } else if pde.contains(PDE_LARGE) {
+            Some(Translation {
+                phys: pde.frame_addr() + addr % pde_mapped_size(),
+                pde: pde & !PDE_FRAMEMASK,
+                pte: PageTableEntry::empty(),
+            })
+        } else {
+            pd.get_pte(addr).map(|pte| Translation {
+                phys: pte.frame_addr() + addr % PAGE_SIZE,
+                pde: pde & !PDE_FRAMEMASK,
+                pte: pte & !PTE_FRAMEMASK,
+            })
+        }
+    }
+
+    /// Returns the physical address of the frame containing the translated address.
+    pub fn frame(&self) -> usize {
+        page_align(self.phys)
+    }
+
+    /// Returns whether the address is part of a large page.
+    pub fn is_large(&self) -> bool {
+        self.pde.contains(PDE_LARGE)
+    }
+
+    /// Returns the flags the processor applies to the address. A page is only writable or
+    /// accessible from user mode if both of its entries allow it, and it is not executable if
+    /// either entry forbids it. Caching and the global flag are taken from the entry that maps the
+    /// frame.
+    pub fn flags(&self) -> PageTableEntry {
+        if self.is_large() {
+            // The low flags of both entries line up, apart from the large page flag itself.
+            let bits = (self.pde & !PDE_LARGE).bits();
+            return PageTableEntry::from_bits_truncate(bits);
+        }
+        let mut flags = self.pte;
+        if !self.pde.contains(PDE_WRITABLE) {
+            flags.remove(PTE_WRITABLE);
+        }
+        if !self.pde.contains(PDE_SUPERVISOR) {
+            flags.remove(PTE_SUPERVISOR);
+        }
+        if self.pde.contains(PDE_NOEXEC) {
+            flags.insert(PTE_NOEXEC);
+        }
+        flags
+    }
+
+}
+
+/// A run of virtually and physically contiguous pages that behave the same.
+#[derive(Clone, Copy)]
+pub struct Mapping {
+    /// The first virtual address of the run.
+    pub start: usize,
+    /// The virtual address just past the end of the run.
+    pub end: usize,
+    /// The physical address `start` maps to.
+    pub phys: usize,
+    /// The effective flags of every page in the run (see `Translation::flags`).
+    pub flags: PageTableEntry,
+}
+
+impl Mapping {
+
+    /// Returns the size of the run in bytes.
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+
+    // Returns whether `next` continues this run.
+    fn is_continued_by(&self, next: &Mapping) -> bool {
+        self.end == next.start && self.phys + self.size() == next.phys &&
+            behaviour(self.flags) == behaviour(next.flags)
+    }
+
+}
+
+impl Debug for Mapping {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:08x}-{:08x} -> {:08x} {}{}{}{}{}{}",
+               self.start, self.end, self.phys,
+               if self.flags.contains(PTE_WRITABLE) { 'w' } else { '-' },
+               if self.flags.contains(PTE_NOEXEC) { '-' } else { 'x' },
+               if self.flags.contains(PTE_SUPERVISOR) { 'u' } else { 'k' },
+               if self.flags.contains(PTE_CACHEDISABLE) { 'c' }
+               else if self.flags.contains(PTE_WRITETHROUGH) { 't' }
+               else { '-' },
+               if self.flags.contains(PTE_GLOBAL) { 'g' } else { '-' },
+               if self.flags.contains(PTE_COW) { " cow" } else { "" })
+    }
+}
+
+/// An iterator over the present mappings of a page directory in address order. The recursive
+/// windows are skipped since they only show the page tables themselves.
+pub struct Mappings<'a> {
+    pd: &'a PageDirectory,
+    addr: usize,
+    done: bool,
+}
+
+impl<'a> Mappings<'a> {
+
+    /// Creates an iterator over the mappings of `pd`.
+    pub fn new(pd: &'a PageDirectory) -> Mappings<'a> {
+        Mappings { pd: pd, addr: 0, done: false }
+    }
+
+    // Returns the mapping of the page or large page at the current address, if any, and moves past
+    // it. Regions without a page table are skipped all at once.
+    fn step(&mut self) -> Option<Mapping> {
+        let addr = self.addr;
+        let window = is_window_pde(addr_to_pde(addr));
+        let size = if !window && self.pd.has_pagetable(addr) {
+            PAGE_SIZE
+        } else {
+            pde_mapped_size()
+        };
+        self.addr = addr.wrapping_add(size);
+        self.done = self.addr == 0;
+        if window {
+            return None;
+        }
+        self.pd.translate(addr).map(|t| Mapping {
+            start: addr,
+            end: addr + size,
+            phys: t.phys,
+            flags: t.flags(),
+        })
+    }
+
+}
+
+impl<'a> Iterator for Mappings<'a> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        let mut run: Option<Mapping> = None;
+        while !self.done {
+            let addr = self.addr;
+            match self.step() {
+                Some(next) => match run {
+                    Some(prev) if prev.is_continued_by(&next) => {
+                        run = Some(Mapping { end: next.end, ..prev });
+                    }
+                    Some(_) => {
+                        // The run ends here. Come back to this page next time.
+                        self.addr = addr;
+                        self.done = false;
+                        return run;
+                    }
+                    None => run = Some(next),
+                },
+                None if run.is_some() => return run,
+                None => { }
+            }
+        }
+        run
+    }
+}
@@ -0,0 +1,315 @@
+//!
+//! Virtual memory areas.
+//!
+//! A virtual memory area (VMA) describes a page aligned range of an address space: what it may be
+//! used for, what backs it and how it behaves. Every address space keeps a `VmaList` sorted by
+//! address. The page fault handler consults it to decide how to resolve a fault and mapping
+//! operations update it as ranges are mapped and unmapped.
+//!
+use core::prelude::*;
+use core::fmt;
+use core::fmt::{Debug, Formatter};
+use collections::vec::Vec;
+use collections::vec::Iter;
+use util::{KernResult, KernError, is_page_aligned};
+use virt::{PageTableEntry, PTE_WRITABLE, PTE_SUPERVISOR, PTE_NOEXEC};
+
+// The initial capacity of a VMA list.
+const VMA_LIST_INIT_CAP: usize = 4;
+
+bitflags! {
+    flags Protection: u32 {
+        const PROT_READ  = 0x00000001,
+        const PROT_WRITE = 0x00000002,
+        const PROT_EXEC  = 0x00000004,
+        const PROT_USER  = 0x00000008,
+    }
+}
+
+bitflags! {
+    flags VmaFlags: u32 {
+        /// Writes are visible to every address space sharing the area rather than copied.
+        const VMA_SHARED    = 0x00000001,
+        /// The area is a stack that grows towards lower addresses.
+        const VMA_GROWSDOWN = 0x00000002,
+        /// Pages in the area may never be reclaimed.
+        const VMA_LOCKED    = 0x00000004,
+    }
+}
+
+/// What backs the pages of a virtual memory area.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Zeroed frames allocated on first access.
+    Anonymous,
+    /// The contents of a file starting at `offset`. The file is identified by an opaque id handed
+    /// out by the file system.
+    File { id: usize, offset: usize },
+    /// Physical memory starting at `phys`, usually belonging to a device.
+    Device { phys: usize },
+}
+
+impl Backing {
+
+    /// Returns the backing of the part of an area starting `delta` bytes into it.
+    fn advance(self, delta: usize) -> Backing {
+        match self {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::File { id, offset } => Backing::File { id: id, offset: offset + delta },
+            Backing::Device { phys } => Backing::Device { phys: phys + delta },
+        }
+    }
+
+}
+
+impl Debug for Backing {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Backing::Anonymous => write!(f, "anonymous"),
+            Backing::File { id, offset } => write!(f, "file {} @ 0x{:x}", id, offset),
+            Backing::Device { phys } => write!(f, "device @ 0x{:x}", phys),
+        }
+    }
+}
+
+/// A virtual memory area.
+#[derive(Clone, Copy)]
+pub struct Vma {
+    /// The first address of the area.
+    pub start: usize,
+    /// The address just past the end of the area.
+    pub end: usize,
+    /// What the area may be used for.
+    pub prot: Protection,
+    /// What backs the area.
+    pub backing: Backing,
+    /// How the area behaves.
+    pub flags: VmaFlags,
+}
+
+impl Vma {
+
+    /// Creates a new virtual memory area.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the range is empty or not page aligned.
+    pub fn new(start: usize, end: usize, prot: Protection, backing: Backing, flags: VmaFlags)
+               -> Vma {
+        assert!(is_page_aligned(start));
+        assert!(is_page_aligned(end));
+        assert!(start < end);
+        Vma { start: start, end: end, prot: prot, backing: backing, flags: flags }
+    }
+
+    /// Returns whether the area contains `addr`.
+    pub fn contains(&self, addr: usize) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// Returns the size of the area in bytes.
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Returns whether the area may be written to.
+    pub fn is_writable(&self) -> bool {
+        self.prot.contains(PROT_WRITE)
+    }
+
+    /// Returns whether code in the area may be executed.
+    pub fn is_executable(&self) -> bool {
+        self.prot.contains(PROT_EXEC)
+    }
+
+    /// Returns the page table entry flags pages in this area are mapped with. Areas without
+    /// `PROT_EXEC` are mapped no-execute.
+    pub fn pte_flags(&self) -> PageTableEntry {
+        let mut flags = PageTableEntry::empty();
+        if self.prot.contains(PROT_WRITE) {
+            flags.insert(PTE_WRITABLE);
+        }
+        if self.prot.contains(PROT_USER) {
+            flags.insert(PTE_SUPERVISOR);
+        }
+        if !self.prot.contains(PROT_EXEC) {
+            flags.insert(PTE_NOEXEC);
+        }
+        flags
+    }
+
+    /// Returns whether `next` starts where this area ends and behaves identically so the two can
+    /// be merged into one area.
+    fn can_merge(&self, next: &Vma) -> bool {
+        self.end == next.start && self.prot == next.prot && self.flags == next.flags &&
+            self.backing.advance(self.size()) == next.backing
+    }
+
+}
+
+impl Debug for Vma {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:08x}-{:08x} {}{}{}{} {:?}", self.start, self.end,
+               if self.prot.contains(PROT_READ) { 'r' } else { '-' },
+               if self.prot.contains(PROT_WRITE) { 'w' } else { '-' },
+               if self.prot.contains(PROT_EXEC) { 'x' } else { '-' },
+               if self.flags.contains(VMA_SHARED) { 's' } else { 'p' },
+               self.backing)
+    }
+}
+
+/// A list of non-overlapping virtual memory areas sorted by address.
+pub struct VmaList {
+    vmas: Vec<Vma>,
+}
+
+impl VmaList {
+
+    /// Tries to create an empty list.
+    pub fn new() -> KernResult<VmaList> {
+        Ok(VmaList { vmas: try!(Vec::new(VMA_LIST_INIT_CAP)) })
+    }
+
+    /// Tries to copy the list.
+    pub fn clone(&self) -> KernResult<VmaList> {
+        Ok(VmaList { vmas: try!(self.vmas.clone()) })
+    }
+
+    /// Returns the number of areas in the list.
+    pub fn len(&self) -> usize {
+        self.vmas.len()
+    }
+
+    /// Returns an iterator over the areas in address order.
+    pub fn iter(&self) -> Iter<Vma> {
+        self.vmas.into_iter()
+    }
+
+    // Returns the index of the area containing `addr` or the index at which an area starting at
+    // `addr` would be inserted.
+    fn search(&self, addr: usize) -> Result<usize, usize> {
+        let vmas = self.vmas.as_slice();
+        let mut lo = 0;
+        let mut hi = vmas.len();
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if addr < vmas[mid].start {
+                hi = mid;
+            } else if addr >= vmas[mid].end {
+                lo = mid + 1;
+            } else {
+                return Ok(mid);
+            }
+        }
+        Err(lo)
+    }
+
+    /// Returns the area containing `addr`, if any.
+    pub fn lookup(&self, addr: usize) -> Option<&Vma> {
+        self.search(addr).ok().map(|idx| &self.vmas[idx])
+    }
+
+    /// Returns whether any area overlaps `[start, end)`.
+    pub fn overlaps(&self, start: usize, end: usize) -> bool {
+        let idx = match self.search(start) {
+            Ok(_) => return true,
+            Err(idx) => idx,
+        };
+        idx < self.vmas.len() && self.vmas[idx].start < end
+    }
+
+    /// Inserts an area and merges it with its neighbours if they are compatible.
+    ///
+    /// # Failures
+    ///
+    /// Fails with `ObjectExists` if the area overlaps an existing area or with `OutOfMemory` if the
+    /// list cannot grow.
+    pub fn insert(&mut self, vma: Vma) -> KernResult<()> {
+        if self.overlaps(vma.start, vma.end) {
+            return Err(KernError::ObjectExists);
+        }
+        let idx = self.search(vma.start).err().unwrap();
+        try!(self.vmas.insert(idx, vma));
+        self.merge_at(idx);
+        if idx > 0 {
+            self.merge_at(idx - 1);
+        }
+        Ok(())
+    }
+
+    /// Splits the area containing `addr` into two areas at `addr`. Nothing happens if `addr` is
+    /// already the start of an area or is not inside any area.
+    ///
+    /// # Failures
+    ///
+    /// Fails if the list cannot grow.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `addr` is not page aligned.
+    pub fn split(&mut self, addr: usize) -> KernResult<()> {
+        assert!(is_page_aligned(addr));
+        let idx = match self.search(addr) {
+            Ok(idx) if self.vmas[idx].start != addr => idx,
+            _ => return Ok(()),
+        };
+        let mut upper = self.vmas[idx];
+        upper.backing = upper.backing.advance(addr - upper.start);
+        upper.start = addr;
+        try!(self.vmas.insert(idx + 1, upper));
+        self.vmas[idx].end = addr;
+        Ok(())
+    }
+
+    // Merges the area at `idx` with the area after it if they are compatible.
+    fn merge_at(&mut self, idx: usize) {
+        if idx + 1 < self.vmas.len() && self.vmas[idx].can_merge(&self.vmas[idx + 1]) {
+            let next = self.vmas.remove(idx + 1);
+            self.vmas[idx].end = next.end;
+        }
+    }
+
+    /// Merges all adjacent compatible areas.
+    pub fn merge(&mut self) {
+        let mut idx = 0;
+        while idx + 1 < self.vmas.len() {
+            if self.vmas[idx].can_merge(&self.vmas[idx + 1]) {
+                self.merge_at(idx);
+            } else {
+                idx += 1;
+            }
+        }
+    }
+
+    /// Removes `[start, end)` from the list, splitting areas that straddle either end, and passes
+    /// each removed area to `op`.
+    ///
+    /// # Failures
+    ///
+    /// Fails if an area needs to be split and the list cannot grow. Nothing is removed in that
+    /// case, although an area may already have been split in two.
+    pub fn remove<F>(&mut self, start: usize, end: usize, mut op: F) -> KernResult<()>
+                     where F: FnMut(Vma) {
+        assert!(start < end);
+        try!(self.split(start));
+        try!(self.split(end));
+        let mut idx = match self.search(start) {
+            Ok(idx) => idx,
+            Err(idx) => idx,
+        };
+        while idx < self.vmas.len() && self.vmas[idx].start < end {
+            op(self.vmas.remove(idx));
+        }
+        Ok(())
+    }
+
+}
+
+impl Debug for VmaList {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for vma in self.iter() {
+            try!(write!(f, "{:?}\n", vma));
+        }
+        Ok(())
+    }
+}
@@ -84,6 +84,20 @@ impl <T> Mutex<T> {
         }
     }
 
+    /// Returns an RAII style lock on the contents of the mutex if nobody owns or waits for it.
+    /// This never yields, so it can be used where blocking would deadlock.
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        // Only take a ticket if it is the one being served.
+        let ticket = self.curr_ticket.load(Ordering::SeqCst);
+        if self.next_ticket.compare_and_swap(ticket, ticket + 1, Ordering::SeqCst) != ticket {
+            return None;
+        }
+        Some(MutexGuard {
+            lock: &self,
+            data: &self.data
+        })
+    }
+
     fn unlock(&self) {
         // Notify next thread that it's their turn.
         self.curr_ticket.fetch_add(1, Ordering::SeqCst);
@@ -6,12 +6,16 @@ use collections::dlist::DList;
 use mutex::{Mutex, MutexGuard};
 
 use alloc::boxed::Box; // This should definitely not be here.
+use alloc::slab::Cache;
 
 #[allow(improper_ctypes)]
 extern {
     fn sched_yield(tid: Option<usize>);
 }
 
+// Every call to `wait` allocates a waiter and `signal` frees it again.
+static NODE_CACHE: Cache<CondVarNode> = Cache::new("condvar_node");
+
 struct CondVarNode {
     signaled: &'static AtomicBool,
     link: DoubleLink<CondVarNode>,
@@ -55,7 +59,7 @@ impl CondVar {
         let old_guard = guard.unlock();
         let signal = AtomicBool::new(false);
         let node = CondVarNode::new(unsafe { mem::transmute(&signal) });
-        let bnode = Box::new(node).unwrap();
+        let bnode = Box::new_in(node, &NODE_CACHE).unwrap();
         self.list.lock().push_tail(bnode);
 
         while !signal.load(Ordering::Relaxed) {
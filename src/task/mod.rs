@@ -1,6 +1,6 @@
 #![crate_name="task"]
 #![crate_type="rlib"]
-#![feature(no_std,core,core_prelude)]
+#![feature(no_std,core,core_prelude,const_fn)]
 #![no_std]
 //!
 //! This module contains definitions of task and thread structures.
@@ -9,23 +9,54 @@
 #[macro_use] extern crate core;
 #[macro_use] extern crate util;
 extern crate io;
+extern crate interrupt;
 extern crate alloc;
 extern crate collections;
 extern crate mem;
+extern crate mutex;
 
 /// Thread related structures.
 pub mod thread;
 
-use mem::virt::PageDirectory;
-use util::rawbox::RawBox;
+use mem::virt::{AddressSpace, switch_to};
+use mutex::Mutex;
+use util::KernResult;
+
+/// Initializes the task module.
+pub fn init() {
+    thread::init();
+}
 
-#[repr(C, packed)]
 pub struct Task {
  
-    cr3: RawBox<PageDirectory>,
+    space: Mutex<AddressSpace>,
 
 }
 
 impl Task {
-    
+
+    /// Tries to create a new task with its own address space.
+    pub fn new() -> KernResult<Task> {
+        Ok(Task { space: Mutex::new(try!(AddressSpace::new())) })
+    }
+
+    /// Returns the value to load into CR3 when switching to this task.
+    pub fn cr3(&self) -> usize {
+        self.space.lock().cr3()
+    }
+
+    /// Returns the task's address space.
+    pub fn space(&self) -> &Mutex<AddressSpace> {
+        &self.space
+    }
+
+    /// Makes the task's address space the active one.
+    ///
+    /// # Safety
+    ///
+    /// The task must not be moved or dropped until another address space has been switched to.
+    pub unsafe fn activate(&self) {
+        switch_to(&self.space);
+    }
+
 }
@@ -11,29 +11,43 @@
 //!
 //! LLVM should really support custom targets!
 //!
+//! Stacks are allocated separately from the Thread structure with `vmalloc` so the page below
+//! every stack is left unmapped. Overflowing a stack past the redzone hits that guard page. The
+//! resulting page fault cannot be delivered on the overflowing stack so it turns into a double
+//! fault, which is reported as a stack overflow of the current thread.
+//!
 use alloc::boxed::Box;
+use alloc::slab::Cache;
 use core::prelude::*;
 use core::atomic::{AtomicIsize, ATOMIC_ISIZE_INIT, Ordering};
-use core::mem;
+use core::{mem, slice};
 use collections::link::{DoubleLink, HasDoubleLink};
-use util::{KernResult, asm};
+use interrupt::{set_double_fault_handler, TaskState};
+use mem::virt::{vmalloc, VBox};
+use util::{KernResult, asm, page_align, PAGE_SIZE};
 logger_init!(Trace);
 
+/// The stack size of threads created with `Thread::new` in bytes.
+pub const DEFAULT_STACK_SIZE: usize = 8192;
+
 /// There is some "wiggle room" in stack checking which allows the stack to go slightly beyond
 /// whatever stack_bottom is set to. This is a problem if stack_bottom is contained in that area
 /// because it may get overwritten! We thus allocate a small redzone between stack_bottom and the
 /// actual end of the stack.
 const REDZONE_SIZE: usize = 16;
-const STACK_SIZE: usize = 2048;
-const STACK_TOP:  usize = STACK_SIZE - 1;
-const ARG_OFFSET: usize = STACK_SIZE - 2;
-const RET_OFFSET: usize = STACK_SIZE - 4;
-const EBP_OFFSET: usize = STACK_SIZE - 5;
-const EBX_OFFSET: usize = STACK_SIZE - 6;
-const EDI_OFFSET: usize = STACK_SIZE - 7;
-const ESI_OFFSET: usize = STACK_SIZE - 8;
+
+// The layout of the initial stack frame of a thread, in words from the top of its stack.
+const TOP_OFFSET: usize = 1;
+const ARG_OFFSET: usize = 2;
+const RET_OFFSET: usize = 4;
+const EBP_OFFSET: usize = 5;
+const EBX_OFFSET: usize = 6;
+const EDI_OFFSET: usize = 7;
+const ESI_OFFSET: usize = 8;
 static NEXT_TID: AtomicIsize = ATOMIC_ISIZE_INIT;
 
+static THREAD_CACHE: Cache<Thread> = Cache::new("thread");
+
 /// The entry point for all new threads. Currently this doesn't do much.
 extern fn thread_entry(thread: &Thread) -> ! {
     trace!("starting thread {}", thread.tid);
@@ -54,27 +68,55 @@ pub struct Thread {
     stack_bottom: usize, // This MUST be at offset 0x10
     sched_node: DoubleLink<Thread>,
     threadfn: fn() -> !,
-    stack: [usize; STACK_SIZE]
+    stack: VBox,
 }
 
 impl Thread {
 
+    /// Tries to create a thread running `f` with a stack of `DEFAULT_STACK_SIZE` bytes.
     pub fn new(f: fn() -> !) -> KernResult<Box<Thread>> {
-        Box::emplace(|thread: &mut Thread| {
-            thread.tid = NEXT_TID.fetch_add(1, Ordering::Relaxed) as i32;
-            thread.pid = 0;
-            thread.sched_node = Default::default();
-            thread.threadfn = f;
-            thread.stack_cur = &thread.stack[ESI_OFFSET] as *const usize as usize;
-            thread.stack_top = &thread.stack[STACK_TOP] as *const usize as usize;
-            thread.stack_bottom = &thread.stack[REDZONE_SIZE] as *const usize as usize;
-            thread.stack[ARG_OFFSET] = thread as *const Thread as usize;
-            thread.stack[RET_OFFSET] = unsafe { mem::transmute(thread_entry) };
-            thread.stack[EBP_OFFSET] = thread.stack_top;
-            thread.stack[EBX_OFFSET] = 0;
-            thread.stack[EDI_OFFSET] = 0;
-            thread.stack[ESI_OFFSET] = 0;
-        })
+        Thread::with_stack_size(f, DEFAULT_STACK_SIZE)
+    }
+
+    /// Tries to create a thread running `f` with a stack of at least `stack_size` bytes. The stack
+    /// is rounded up to a whole number of pages.
+    ///
+    /// # Failures
+    ///
+    /// Fails if the stack or the thread structure cannot be allocated.
+    pub fn with_stack_size(f: fn() -> !, stack_size: usize) -> KernResult<Box<Thread>> {
+        let stack = try!(vmalloc(stack_size));
+        let words = stack.size() / mem::size_of::<usize>();
+        let mut thread = try!(Box::new_in(Thread {
+            tid: NEXT_TID.fetch_add(1, Ordering::Relaxed) as i32,
+            pid: 0,
+            stack_cur: 0,
+            stack_top: 0,
+            stack_bottom: 0,
+            sched_node: Default::default(),
+            threadfn: f,
+            stack: stack,
+        }, &THREAD_CACHE));
+
+        // Set up the stack so the first context switch to the thread enters `thread_entry`. We
+        // know this is safe because the stack is mapped for as long as the thread owns it.
+        let stack_ptr = thread.stack.as_ptr() as *mut usize;
+        let stack = unsafe { slice::from_raw_parts_mut(stack_ptr, words) };
+        thread.stack_cur = &stack[words - ESI_OFFSET] as *const usize as usize;
+        thread.stack_top = &stack[words - TOP_OFFSET] as *const usize as usize;
+        thread.stack_bottom = &stack[REDZONE_SIZE] as *const usize as usize;
+        stack[words - ARG_OFFSET] = &*thread as *const Thread as usize;
+        stack[words - RET_OFFSET] = unsafe { mem::transmute(thread_entry) };
+        stack[words - EBP_OFFSET] = thread.stack_top;
+        stack[words - EBX_OFFSET] = 0;
+        stack[words - EDI_OFFSET] = 0;
+        stack[words - ESI_OFFSET] = 0;
+        Ok(thread)
+    }
+
+    /// Returns the size of the thread's stack in bytes.
+    pub fn stack_size(&self) -> usize {
+        self.stack.size()
     }
 
     pub fn run(&self) -> ! {
@@ -91,3 +133,33 @@ impl HasDoubleLink<Thread> for Thread {
         &mut self.sched_node
     }
 }
+
+// Returns the thread control block the thread local descriptor points at. This is the current
+// thread once the scheduler has started and the thread local area of the initial stack before.
+// Either way the stack bottom is at offset 0x10.
+fn current_tcb() -> *const Thread {
+    // We know this is safe because the descriptor is initialized before the kernel starts.
+    let desc = linker_sym!(_tl_desc) as *const u32;
+    let (lo, hi) = unsafe { (*desc, *desc.offset(1)) };
+    ((lo >> 16) | ((hi & 0xFF) << 16) | (hi & 0xFF000000)) as usize as *const Thread
+}
+
+// Reports a double fault. A thread that overflows its stack touches the guard page below it and
+// the page fault turns into a double fault because it cannot be pushed on the stack, so this is
+// where stack overflows end up.
+fn double_fault(state: &TaskState) {
+    let fault_addr = asm::get_cr2();
+    let tcb = current_tcb();
+    let (tid, stack_bottom) = unsafe { ((*tcb).tid, (*tcb).stack_bottom) };
+    let guard = page_align(stack_bottom) - PAGE_SIZE;
+    if guard <= fault_addr && fault_addr < guard + PAGE_SIZE {
+        panic!("stack overflow in thread {} at 0x{:x}\n{:?}", tid, fault_addr, state);
+    }
+    panic!("double fault\n{:?}", state);
+}
+
+/// Initializes the thread module. This installs the double fault handler which reports stack
+/// overflows.
+pub fn init() {
+    set_double_fault_handler(double_fault);
+}
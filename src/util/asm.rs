@@ -1,6 +1,8 @@
 // Would use bitflags! but that would just complicate things.
 const CR0_PG: u32 = 1 << 31;
+const CR0_WP: u32 = 1 << 16;
 const CR4_PSE: u32 = 1 << 4;
+const CR4_PAE: u32 = 1 << 5;
 const CR4_PGE: u32 = 1 << 7;
 
 const IF_FLAG: u32 = 1 << 9;
@@ -38,14 +40,39 @@ pub fn set_cr3(cr3: usize) {
     unsafe { asm!("mov $0, %cr3" :: "r"(cr3)) }
 }
 
-/// Enables paging.
+/// Returns the value of the CR3 register.
+pub fn get_cr3() -> usize {
+    let mut cr3: usize;
+    unsafe { asm!("mov %cr3, $0" : "=r"(cr3)) }
+    cr3
+}
+
+/// Returns the value of the CR2 register. This holds the address of the last page fault.
+pub fn get_cr2() -> usize {
+    let mut cr2: usize;
+    unsafe { asm!("mov %cr2, $0" : "=r"(cr2)) }
+    cr2
+}
+
+/// Invalidates the TLB entry for the page containing `addr`.
+pub fn invlpg(addr: usize) {
+    unsafe { asm!("invlpg ($0)" :: "r"(addr) : "memory") }
+}
+
+/// Flushes all non-global entries from the TLB by reloading CR3.
+pub fn flush_tlb() {
+    set_cr3(get_cr3())
+}
+
+/// Enables paging. Supervisor mode writes to read-only pages fault as well so read-only and
+/// copy-on-write pages are enforced in the kernel too.
 pub fn enable_paging() {
     unsafe { 
         asm!("mov %cr0, %eax\n\t
               or $0, %eax\n\t
               mov %eax, %cr0\n\t" 
              :
-             : "r"(CR0_PG)
+             : "r"(CR0_PG | CR0_WP)
              : "eax") 
     }
 }
@@ -62,6 +89,18 @@ pub fn enable_4mb_pages() {
     }
 }
 
+/// Returns the value of the CR4 register.
+pub fn get_cr4() -> u32 {
+    let mut cr4: u32;
+    unsafe { asm!("mov %cr4, $0" : "=r"(cr4)) }
+    cr4
+}
+
+/// Returns whether PAE paging is enabled. The boot code decides this before paging is enabled.
+pub fn pae_enabled() -> bool {
+    get_cr4() & CR4_PAE != 0
+}
+
 /// Enable page table global bit.
 pub fn enable_global_pages() {
     unsafe {
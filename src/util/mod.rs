@@ -3,7 +3,7 @@
 //!
 #![crate_name="util"]
 #![crate_type="rlib"]
-#![feature(no_std,core,asm,unique,core_prelude,const_fn)]
+#![feature(no_std,core,asm,unique,core_prelude,core_str_ext,const_fn)]
 #![no_std]
 
 #[macro_use] extern crate core;
@@ -46,6 +46,14 @@ pub const USER_DATA_SEGMENT: u16 = 0x0020;
 pub const PAGE_SIZE: usize = 0x1000;
 pub const PAGE_SHIFT: usize = 12;
 
+/// The virtual address the kernel is linked at. The first `DIRECT_MAP_SIZE` bytes of physical
+/// memory appear here so that physical address `p` is at virtual address `KERNEL_BASE + p`. This
+/// must match `KERNEL_BASE` in `linker.ld` and `multiboot.S`.
+pub const KERNEL_BASE: usize = 0xC0000000;
+
+/// The amount of physical memory mapped at `KERNEL_BASE`.
+pub const DIRECT_MAP_SIZE: usize = 0x01000000;
+
 #[macro_export]
 macro_rules! getbyte {
     ($val:expr, $byte:expr) => { ($val >> (8 * $byte)) as u8 };
@@ -63,6 +71,32 @@ pub fn page_align(addr: usize) -> usize {
     align!(addr, PAGE_SIZE)
 }
 
+/// Returns the virtual address at which the physical address `phys` is mapped in the kernel's part
+/// of the address space. While booting all of the first `DIRECT_MAP_SIZE` bytes of physical memory
+/// are mapped. Once the kernel's own page directory is loaded only the kernel image and video
+/// memory stay mapped.
+///
+/// # Panics
+///
+/// This function panics if `phys` is not part of the direct map.
+#[inline]
+pub fn phys_to_virt(phys: usize) -> usize {
+    assert!(phys < DIRECT_MAP_SIZE);
+    phys + KERNEL_BASE
+}
+
+/// Returns the physical address of the virtual address `virt` in the kernel's direct map. This is
+/// the inverse of `phys_to_virt`.
+///
+/// # Panics
+///
+/// This function panics if `virt` is not part of the direct map.
+#[inline]
+pub fn virt_to_phys(virt: usize) -> usize {
+    assert!(KERNEL_BASE <= virt && virt < KERNEL_BASE + DIRECT_MAP_SIZE);
+    virt - KERNEL_BASE
+}
+
 /// Returns the number of low order 0 bits in an alignment mask. This is currently used in the LMM
 /// allocator.
 #[inline]
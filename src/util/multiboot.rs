@@ -1,5 +1,7 @@
 #![allow(dead_code,raw_pointer_derive)]
+use core::prelude::*;
 use core::ops::Fn;
+use core::{slice, str};
 
 pub const MULTIBOOT_INFO_MEMORY: u32 = 0x1;
 pub const MULTIBOOT_INFO_BOOTDEV: u32 = 0x2;
@@ -93,7 +95,7 @@ impl MultibootHeader {
     pub fn walk_mmap<F>(&self, op: F) where F: Fn(usize, usize) {
         assert!(self.flags & MULTIBOOT_INFO_MEM_MAP != 0);
 
-        let mmap = self.mmap_addr as *const u8;
+        let mmap = ::phys_to_virt(self.mmap_addr as usize) as *const u8;
         let mut offset: isize = 0;
         while offset < self.mmap_length as isize {
            
@@ -118,4 +120,44 @@ impl MultibootHeader {
         }
     }
 
+    /// Returns the command line the kernel was booted with, if the bootloader passed one.
+    ///
+    /// The command line is read through the direct map since this is needed before the heap
+    /// exists. A command line that doesn't lie entirely within the first `DIRECT_MAP_SIZE` bytes of
+    /// physical memory is ignored.
+    pub fn cmdline(&self) -> Option<&str> {
+        if self.flags & MULTIBOOT_INFO_CMDLINE == 0 || self.cmdline.is_null() {
+            return None;
+        }
+
+        // We know this is safe because the bootloader put a NUL terminated string there and every
+        // byte is checked to be in the direct map before it is read.
+        let start = self.cmdline as usize;
+        let mut len = 0;
+        loop {
+            if start + len >= ::DIRECT_MAP_SIZE {
+                return None;
+            }
+            if unsafe { *(::phys_to_virt(start + len) as *const u8) } == 0 {
+                break;
+            }
+            len += 1;
+        }
+        let bytes = unsafe { slice::from_raw_parts(::phys_to_virt(start) as *const u8, len) };
+        str::from_utf8(bytes).ok()
+    }
+
+    /// Returns the value of the option `name` if it is given as `name=value` on the command line.
+    pub fn cmdline_option(&self, name: &str) -> Option<&str> {
+        self.cmdline().and_then(|line| {
+            line.split(' ').filter_map(|opt| {
+                let mut parts = opt.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(key), Some(value)) if key == name => Some(value),
+                    _ => None,
+                }
+            }).next()
+        })
+    }
+
 }